@@ -0,0 +1,101 @@
+//! Renders one image across several local processes, which each trace a range of the
+//! samples and write a partial buffer to disk, then merges the buffers.
+//!
+//! Run with `cargo run --release --example distributed`. The same split works across
+//! machines, as long as every worker uses the same scene and seed.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::process::Command;
+
+use rpt::*;
+
+const WORKERS: u32 = 4;
+const SAMPLES: u32 = 64;
+
+fn renderer(scene: &Scene) -> Renderer<'_> {
+    let camera = Camera::look_at(
+        glm::vec3(0.0, 1.5, 6.0),
+        glm::vec3(0.0, 0.0, 0.0),
+        glm::vec3(0.0, 1.0, 0.0),
+        std::f64::consts::FRAC_PI_4,
+    );
+    Renderer::new(scene, camera)
+        .width(400)
+        .height(300)
+        .max_bounces(4)
+        .num_samples(SAMPLES)
+        .seed(2021)
+}
+
+fn scene() -> Scene {
+    let mut scene = Scene::new();
+    scene.add(Object::new(sphere()).material(Material::diffuse(hex_color(0xcc4444))));
+    scene.add(
+        Object::new(sphere().translate(&glm::vec3(2.0, 0.0, -1.0)))
+            .material(Material::metallic(hex_color(0xdddddd), 0.2)),
+    );
+    scene.add(
+        Object::new(plane(glm::vec3(0.0, 1.0, 0.0), -1.0))
+            .material(Material::diffuse(hex_color(0xaaaaaa))),
+    );
+    scene.add(Light::Ambient(glm::vec3(0.05, 0.05, 0.05)));
+    scene.add(Light::Point(
+        glm::vec3(60.0, 60.0, 60.0),
+        glm::vec3(0.0, 5.0, 5.0),
+    ));
+    scene
+}
+
+fn main() -> color_eyre::Result<()> {
+    color_eyre::install()?;
+
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, start, end, path] = args.as_slice() {
+        // Worker process: render a range of the samples to a partial buffer
+        assert_eq!(command, "worker");
+        let scene = scene();
+        let buffer = renderer(&scene)
+            .sample_range(start.parse()?..end.parse()?)
+            .render_buffer();
+        let mut writer = BufWriter::new(File::create(path)?);
+        buffer.write(&mut writer)?;
+        writer.flush()?;
+        return Ok(());
+    }
+
+    let dir = tempfile::tempdir()?;
+    let exe = std::env::current_exe()?;
+    let mut workers = Vec::new();
+    let mut paths = Vec::new();
+    for i in 0..WORKERS {
+        let (start, end) = (i * SAMPLES / WORKERS, (i + 1) * SAMPLES / WORKERS);
+        let path = dir.path().join(format!("part{}.buf", i));
+        workers.push(
+            Command::new(&exe)
+                .arg("worker")
+                .arg(start.to_string())
+                .arg(end.to_string())
+                .arg(&path)
+                .spawn()?,
+        );
+        paths.push(path);
+    }
+    for mut worker in workers {
+        assert!(worker.wait()?.success(), "Worker process failed");
+    }
+
+    let mut buffer: Option<Buffer> = None;
+    for path in paths {
+        let part = Buffer::read(BufReader::new(File::open(path)?))?;
+        match &mut buffer {
+            Some(buffer) => buffer.merge(&part),
+            None => buffer = Some(part),
+        }
+    }
+    let buffer = buffer.expect("No workers");
+    println!("Merged {} samples per pixel", buffer.sample_count(0, 0));
+    buffer.image().save("output.png")?;
+
+    Ok(())
+}
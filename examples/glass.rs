@@ -1,4 +1,6 @@
 //! Demo of metal and glass balls, using a custom HDRI to light the scene
+//!
+//! This renders spectrally, so the glass ball disperses light into rainbows.
 
 use image::{
     codecs::hdr::{HdrDecoder, HdrMetadata},
@@ -37,7 +39,7 @@ fn main() -> color_eyre::Result<()> {
     );
     scene.add(
         Object::new(sphere().translate(&glm::vec3(-1.1, 0.0, 0.0)))
-            .material(Material::clear(1.5, 0.0001)),
+            .material(Material::dispersive(Dispersion::BK7, 0.0001)),
     );
 
     Renderer::new(&scene, Camera::default())
@@ -45,6 +47,7 @@ fn main() -> color_eyre::Result<()> {
         .height(900)
         .max_bounces(5)
         .num_samples(200)
+        .integrator(SpectralPathTracer)
         .render()
         .save("output.png")?;
 
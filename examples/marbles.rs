@@ -71,20 +71,32 @@ fn main() -> color_eyre::Result<()> {
             scene.add(Light::Ambient(glm::vec3(0.01, 0.01, 0.01)));
         }
 
+        // Marbles move from this frame to the next, blurred over half of the frame time
+        let mut next_state = cur_state.clone();
+        system.rk4_integrate(&mut next_state, 1. / 16., 1. / 10000.);
+
         let glass = Material::clear(1.5, 0.0001);
         scene.add(Object::new(surface_shape.clone()).material(glass));
         let colors = [0x264653, 0x2A9D8F, 0xE9C46A, 0xF4A261, 0xE76F51];
         let surf = monomial_surface(2., 4.);
-        for i in 0..N {
-            let mut pos = cur_state.pos[i];
+        let marble = |mut pos: glm::DVec3, time: f64| {
             let closest = surf.closest_point_precise(&pos);
             let vec = pos - closest;
             if glm::length(&vec) < R * 1.05 {
                 pos = closest + glm::normalize(&vec) * R * 1.05;
             }
             pos.y = pos.y.max(R - 0.06);
+            let transform = glm::translate(&glm::identity(), &pos)
+                * glm::scale(&glm::identity(), &glm::vec3(R, R, R));
+            Keyframe::new(time, transform)
+        };
+        for i in 0..N {
+            let keyframes = vec![
+                marble(cur_state.pos[i], 0.0),
+                marble(next_state.pos[i], 1.0),
+            ];
             scene.add(
-                Object::new(sphere().scale(&glm::vec3(R, R, R)).translate(&pos))
+                Object::new(animated(sphere(), keyframes))
                     .material(Material::specular(hex_color(colors[i % colors.len()]), 0.1)),
             );
         }
@@ -104,7 +116,8 @@ fn main() -> color_eyre::Result<()> {
             glm::vec3(0.0, 1.0, 0.0),
             std::f64::consts::FRAC_PI_4,
         )
-        .focus(glm::vec3(0.0, 1.0, 0.0), 0.02);
+        .focus(glm::vec3(0.0, 1.0, 0.0), 0.02)
+        .shutter(0.0, 0.5);
 
         if TEST {
             Renderer::new(&scene, camera)
@@ -123,12 +136,12 @@ fn main() -> color_eyre::Result<()> {
                 .render()
                 .save(format!("video/image_{}.png", frame))?;
         }
-        system.rk4_integrate(&mut cur_state, 1. / 16., 1. / 10000.);
+        cur_state = next_state;
         println!("Frame {} finished", frame);
     }
     Command::new("ffmpeg")
-        .args(&["-y", "-i", "video/image_%d.png", "-vcodec", "libx264"])
-        .args(&["-s", "800x600", "-pix_fmt", "yuv420p", "video.mp4"])
+        .args(["-y", "-i", "video/image_%d.png", "-vcodec", "libx264"])
+        .args(["-s", "800x600", "-pix_fmt", "yuv420p", "video.mp4"])
         .spawn()?
         .wait()?;
 
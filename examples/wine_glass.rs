@@ -67,7 +67,7 @@ fn main() -> color_eyre::Result<()> {
     Renderer::new(&scene, camera)
         .width(1920)
         .height(1080)
-        .max_bounces(6)
+        .russian_roulette(6)
         .num_samples(1000)
         .iterative_render(10, |iteration, buffer| {
             let millis = time.elapsed().as_millis();
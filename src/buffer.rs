@@ -1,13 +1,51 @@
-use image::{ImageBuffer, RgbImage};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
 
-use crate::color::{color_bytes, Color};
+use image::{ImageBuffer, Rgb, RgbImage};
 
-/// A buffer that stores sample results from path tracing
+use crate::color::{Color, ColorSpace, Primaries, TransferFunction};
+use crate::io::Channel;
+use crate::renderer::PixelStats;
+use crate::tile::Tile;
+use crate::tone_map::{DisplayTransform, ToneMap};
+
+pub use denoise::Denoiser;
+
+mod denoise;
+
+/// An image with floating-point RGB pixels, such as a high-dynamic-range render
+pub type Rgb32FImage = ImageBuffer<Rgb<f32>, Vec<f32>>;
+
+/// Magic bytes at the start of a serialized buffer, followed by a format version
+const MAGIC: &[u8; 8] = b"rptbuf\0\x01";
+
+/// Magic bytes at the start of a serialized checkpoint, followed by a format version
+const CHECKPOINT_MAGIC: &[u8; 8] = b"rptckp\0\x01";
+
+/// Size of each serialized pixel in bytes: two counts, 10 color channels with the
+/// splat weight, and 7 feature channels
+const PIXEL_BYTES: usize = 2 * 4 + (3 * 3 + 1) * 8 + 7 * 8;
+
+/// A buffer that accumulates sample results from path tracing
+///
+/// Each pixel keeps a running mean and count of its samples, along with their variance,
+/// so the memory used does not grow with the number of samples. With a reconstruction
+/// filter, every sample is also splatted onto the nearby pixels, weighted by the filter
+/// at its position on the film. Pixels can also collect features of the surfaces that
+/// they see, which guide denoising.
+///
+/// Buffers can be serialized with `write` and `read`, so that partial renders from
+/// several processes can be combined with `merge`.
+#[derive(Clone)]
 pub struct Buffer {
     width: u32,
     height: u32,
-    samples: Vec<Vec<Color>>,
+    pixels: Vec<Pixel>,
     filter: Filter,
+    tone_map: ToneMap,
+    working_space: Primaries,
+    output_space: ColorSpace,
 }
 
 impl Buffer {
@@ -16,16 +54,66 @@ impl Buffer {
         Self {
             width,
             height,
-            samples: vec![vec![]; (width * height) as usize],
+            pixels: vec![Pixel::default(); (width * height) as usize],
             filter,
+            tone_map: ToneMap::default(),
+            working_space: Primaries::default(),
+            output_space: ColorSpace::default(),
         }
     }
 
+    /// Set the tone mapping operator used when converting the buffer to an image
+    pub fn set_tone_map(&mut self, tone_map: ToneMap) {
+        self.tone_map = tone_map;
+    }
+
+    /// Set the transfer function used when converting the buffer to an image
+    pub fn set_transfer_function(&mut self, transfer_function: TransferFunction) {
+        self.output_space.transfer_function = transfer_function;
+    }
+
+    /// Set the primaries of the colors added to the buffer
+    pub fn set_working_space(&mut self, working_space: Primaries) {
+        self.working_space = working_space;
+    }
+
+    /// Set the color space that images are converted to, including its transfer
+    /// function, which is ignored for high-dynamic-range images
+    pub fn set_output_space(&mut self, output_space: ColorSpace) {
+        self.output_space = output_space;
+    }
+
+    /// Returns the width and height of the buffer
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
     /// Add a sample to the buffer, at a given pixel location
     pub fn add_sample(&mut self, x: u32, y: u32, sample: Color) {
         assert!(x < self.width && y < self.height, "Invalid pixel location");
-        let index = (y * self.width + x) as usize;
-        self.samples[index].push(sample);
+        let frame = self.frame();
+        self.add_pixel_sample(x, y, f64::from(x) + 0.5, f64::from(y) + 0.5, sample, &frame);
+    }
+
+    /// Add a sample at a continuous position on the film, where pixel (x, y) covers
+    /// the square from (x, y) to (x + 1, y + 1)
+    pub fn add_film_sample(&mut self, x: f64, y: f64, sample: Color) {
+        let frame = self.frame();
+        self.add_film_sample_within(x, y, sample, &frame);
+    }
+
+    /// Add a sample at a continuous position on the film, like `add_film_sample`, but
+    /// only splat it onto the pixels in a window, such as the crop window of a render
+    pub(crate) fn add_film_sample_within(&mut self, x: f64, y: f64, sample: Color, window: &Tile) {
+        assert!(
+            (0.0..f64::from(self.width)).contains(&x) && (0.0..f64::from(self.height)).contains(&y),
+            "Invalid film position"
+        );
+        let (i, j) = (
+            (x as u32).min(self.width - 1),
+            (y as u32).min(self.height - 1),
+        );
+        self.add_pixel_sample(i, j, x, y, sample, window);
     }
 
     /// Add a uniform matrix of samples to the buffer
@@ -34,42 +122,400 @@ impl Buffer {
             samples.len() == (self.width * self.height) as usize,
             "Invalid sample dimension"
         );
-        for (index, sample) in samples.iter().enumerate() {
-            self.samples[index].push(*sample);
+        let frame = self.frame();
+        for (index, &sample) in samples.iter().enumerate() {
+            let (x, y) = (index as u32 % self.width, index as u32 / self.width);
+            self.add_pixel_sample(x, y, f64::from(x) + 0.5, f64::from(y) + 0.5, sample, &frame);
+        }
+    }
+
+    /// Add the features of a camera sample to a pixel, which are averaged over the
+    /// samples of the pixel
+    pub fn add_features(&mut self, x: u32, y: u32, features: &Features) {
+        assert!(x < self.width && y < self.height, "Invalid pixel location");
+        let pixel = &mut self.pixels[(y * self.width + x) as usize];
+        pixel.features.albedo += features.albedo;
+        pixel.features.normal += features.normal;
+        pixel.features.depth += features.depth;
+        pixel.feature_count += 1;
+    }
+
+    /// Returns the average features of the samples in a pixel, or `None` if no
+    /// features were added to it
+    pub fn features(&self, x: u32, y: u32) -> Option<Features> {
+        assert!(x < self.width && y < self.height, "Invalid pixel location");
+        let pixel = &self.pixels[(y * self.width + x) as usize];
+        (pixel.feature_count > 0).then(|| pixel.features())
+    }
+
+    /// Returns the tile covering the whole buffer
+    fn frame(&self) -> Tile {
+        Tile {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    /// Add a sample to the statistics of pixel (x, y), and splat it at film position
+    /// (fx, fy) onto the pixels in a window if the filter is a reconstruction filter
+    fn add_pixel_sample(&mut self, x: u32, y: u32, fx: f64, fy: f64, sample: Color, window: &Tile) {
+        self.pixels[(y * self.width + x) as usize].add(&Pixel::from_sample(sample));
+        let radius = match self.filter.splat_radius() {
+            Some(radius) => radius,
+            None => return,
+        };
+        // Pixels whose centers are within the radius of the sample in each direction
+        let range = |f: f64, start: u32, len: u32| {
+            let (start, end) = (f64::from(start), f64::from(start + len));
+            let lo = (f - radius - 0.5).ceil().max(start) as u32;
+            let hi = ((f + radius - 0.5).floor() + 1.0).clamp(start, end) as u32;
+            lo..hi
+        };
+        let xs = range(fx, window.x, window.width);
+        for j in range(fy, window.y, window.height) {
+            let wy = self.filter.evaluate(f64::from(j) + 0.5 - fy);
+            for i in xs.clone() {
+                let w = self.filter.evaluate(f64::from(i) + 0.5 - fx) * wy;
+                let pixel = &mut self.pixels[(j * self.width + i) as usize];
+                pixel.splat += sample * w;
+                pixel.splat_weight += w;
+            }
+        }
+    }
+
+    /// Add all of the samples from another buffer of the same size, such as a partial
+    /// render of the same image from another process
+    ///
+    /// Both buffers must have the same filter and display settings.
+    pub fn merge(&mut self, other: &Buffer) {
+        assert!(
+            self.width == other.width && self.height == other.height,
+            "Cannot merge buffers of different sizes"
+        );
+        assert!(
+            self.filter == other.filter,
+            "Cannot merge buffers with different filters"
+        );
+        assert!(
+            self.tone_map == other.tone_map
+                && self.working_space == other.working_space
+                && self.output_space == other.output_space,
+            "Cannot merge buffers with different tone maps or color spaces"
+        );
+        for (pixel, other_pixel) in self.pixels.iter_mut().zip(&other.pixels) {
+            pixel.add(other_pixel);
         }
     }
 
-    /// Converts the current buffer to an image
+    /// Serialize the buffer in a compact binary format
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&self.width.to_le_bytes())?;
+        writer.write_all(&self.height.to_le_bytes())?;
+        let (tag, params): (u32, &[f64]) = match self.filter {
+            Filter::Box(radius) => (0, &[f64::from(radius)]),
+            Filter::Tent { radius } => (1, &[radius]),
+            Filter::Gaussian { radius, alpha } => (2, &[radius, alpha]),
+            Filter::Mitchell { radius, b, c } => (3, &[radius, b, c]),
+            Filter::Lanczos { radius } => (4, &[radius]),
+            Filter::Denoise(ref denoiser) => (
+                5,
+                &[
+                    f64::from(denoiser.iterations),
+                    denoiser.sigma_luminance,
+                    denoiser.sigma_normal,
+                    denoiser.sigma_depth,
+                    denoiser.sigma_albedo,
+                ],
+            ),
+        };
+        write_tagged(&mut writer, tag, params)?;
+        let (tag, params): (u32, &[f64]) = match self.tone_map {
+            ToneMap::Clamp => (0, &[]),
+            ToneMap::Reinhard => (1, &[]),
+            ToneMap::ReinhardExtended { white } => (2, &[white]),
+            ToneMap::Aces => (3, &[]),
+            ToneMap::Agx => (4, &[]),
+            ToneMap::Hable => (5, &[]),
+        };
+        write_tagged(&mut writer, tag, params)?;
+        let (tag, params): (u32, &[f64]) = match self.output_space.transfer_function {
+            TransferFunction::Linear => (0, &[]),
+            TransferFunction::Gamma(gamma) => (1, &[gamma]),
+            TransferFunction::Srgb => (2, &[]),
+        };
+        write_tagged(&mut writer, tag, params)?;
+        for primaries in [self.working_space, self.output_space.primaries] {
+            let tag: u32 = match primaries {
+                Primaries::Rec709 => 0,
+                Primaries::P3 => 1,
+                Primaries::Ap1 => 2,
+                Primaries::Rec2020 => 3,
+            };
+            writer.write_all(&tag.to_le_bytes())?;
+        }
+        for pixel in &self.pixels {
+            writer.write_all(&pixel.count.to_le_bytes())?;
+            let Pixel {
+                mean, m2, splat, ..
+            } = pixel;
+            for c in mean.iter().chain(m2).chain(splat) {
+                writer.write_all(&c.to_le_bytes())?;
+            }
+            writer.write_all(&pixel.splat_weight.to_le_bytes())?;
+            writer.write_all(&pixel.feature_count.to_le_bytes())?;
+            let Features {
+                albedo,
+                normal,
+                depth,
+            } = &pixel.features;
+            for c in albedo.iter().chain(normal).chain([depth]) {
+                writer.write_all(&c.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deserialize a buffer that was written by `write`
+    ///
+    /// This reads to the end of the input, which must hold nothing after the buffer.
+    pub fn read(mut reader: impl Read) -> io::Result<Self> {
+        let invalid_data = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data(
+                "Not a serialized buffer, or unsupported version",
+            ));
+        }
+        let read_u32 = |reader: &mut dyn Read| -> io::Result<u32> {
+            let mut buf = [0; 4];
+            reader.read_exact(&mut buf)?;
+            Ok(u32::from_le_bytes(buf))
+        };
+        let width = read_u32(&mut reader)?;
+        let height = read_u32(&mut reader)?;
+        let read_f64 = |reader: &mut dyn Read| -> io::Result<f64> {
+            let mut buf = [0; 8];
+            reader.read_exact(&mut buf)?;
+            Ok(f64::from_le_bytes(buf))
+        };
+        let filter = match read_u32(&mut reader)? {
+            0 => Filter::Box(read_f64(&mut reader)? as u32),
+            1 => Filter::Tent {
+                radius: read_f64(&mut reader)?,
+            },
+            2 => Filter::Gaussian {
+                radius: read_f64(&mut reader)?,
+                alpha: read_f64(&mut reader)?,
+            },
+            3 => Filter::Mitchell {
+                radius: read_f64(&mut reader)?,
+                b: read_f64(&mut reader)?,
+                c: read_f64(&mut reader)?,
+            },
+            4 => Filter::Lanczos {
+                radius: read_f64(&mut reader)?,
+            },
+            5 => Filter::Denoise(Denoiser {
+                iterations: read_f64(&mut reader)? as u32,
+                sigma_luminance: read_f64(&mut reader)?,
+                sigma_normal: read_f64(&mut reader)?,
+                sigma_depth: read_f64(&mut reader)?,
+                sigma_albedo: read_f64(&mut reader)?,
+            }),
+            _ => return Err(invalid_data("Unknown filter in serialized buffer")),
+        };
+        let tone_map = match read_u32(&mut reader)? {
+            0 => ToneMap::Clamp,
+            1 => ToneMap::Reinhard,
+            2 => ToneMap::ReinhardExtended {
+                white: read_f64(&mut reader)?,
+            },
+            3 => ToneMap::Aces,
+            4 => ToneMap::Agx,
+            5 => ToneMap::Hable,
+            _ => return Err(invalid_data("Unknown tone map in serialized buffer")),
+        };
+        let transfer_function = match read_u32(&mut reader)? {
+            0 => TransferFunction::Linear,
+            1 => TransferFunction::Gamma(read_f64(&mut reader)?),
+            2 => TransferFunction::Srgb,
+            _ => {
+                return Err(invalid_data(
+                    "Unknown transfer function in serialized buffer",
+                ))
+            }
+        };
+        let mut primaries = [Primaries::default(); 2];
+        for p in &mut primaries {
+            *p = match read_u32(&mut reader)? {
+                0 => Primaries::Rec709,
+                1 => Primaries::P3,
+                2 => Primaries::Ap1,
+                3 => Primaries::Rec2020,
+                _ => return Err(invalid_data("Unknown primaries in serialized buffer")),
+            };
+        }
+        // Check the size of the pixel data before allocating, so that a corrupt header
+        // cannot request a huge buffer
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        let expected = (width as usize)
+            .checked_mul(height as usize)
+            .and_then(|n| n.checked_mul(PIXEL_BYTES));
+        if expected != Some(data.len()) {
+            return Err(invalid_data(
+                "Serialized buffer does not match its dimensions",
+            ));
+        }
+        let mut reader = data.as_slice();
+        let mut buffer = Self::new(width, height, filter);
+        buffer.tone_map = tone_map;
+        buffer.working_space = primaries[0];
+        buffer.output_space = ColorSpace::new(primaries[1], transfer_function);
+        for pixel in &mut buffer.pixels {
+            pixel.count = read_u32(&mut reader)?;
+            let Pixel {
+                mean, m2, splat, ..
+            } = pixel;
+            for c in mean.iter_mut().chain(m2.iter_mut()).chain(splat.iter_mut()) {
+                *c = read_f64(&mut reader)?;
+            }
+            pixel.splat_weight = read_f64(&mut reader)?;
+            pixel.feature_count = read_u32(&mut reader)?;
+            let Features {
+                albedo,
+                normal,
+                depth,
+            } = &mut pixel.features;
+            for c in albedo.iter_mut().chain(normal.iter_mut()).chain([depth]) {
+                *c = read_f64(&mut reader)?;
+            }
+        }
+        Ok(buffer)
+    }
+
+    /// Returns the total number of paths sampled in a pixel
+    pub fn sample_count(&self, x: u32, y: u32) -> u32 {
+        assert!(x < self.width && y < self.height, "Invalid pixel location");
+        self.pixels[(y * self.width + x) as usize].count
+    }
+
+    /// Returns the sample variance of each color channel in a pixel, or zero if the
+    /// pixel has fewer than two samples
+    pub fn sample_variance(&self, x: u32, y: u32) -> Color {
+        assert!(x < self.width && y < self.height, "Invalid pixel location");
+        self.pixels[(y * self.width + x) as usize].variance()
+    }
+
+    /// Converts the number of paths sampled in each pixel to a heatmap image, which
+    /// ranges from black (no samples) to white (the most samples of any pixel)
+    pub fn heatmap(&self) -> RgbImage {
+        let counts: Vec<_> = (0..self.height)
+            .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.sample_count(x, y))
+            .collect();
+        let max_count = counts.iter().copied().max().unwrap_or(0).max(1);
+        let buf = counts
+            .iter()
+            .flat_map(|&count| heatmap_color(f64::from(count) / f64::from(max_count)))
+            .collect();
+        ImageBuffer::from_raw(self.width, self.height, buf)
+            .expect("Image buffer has incorrect size")
+    }
+
+    /// Converts the current buffer to an image, with its tone mapping operator and
+    /// transfer function
     pub fn image(&self) -> RgbImage {
+        let display = DisplayTransform::new(self.working_space, self.tone_map, self.output_space);
         let mut buf = Vec::new();
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let color = self.get_filtered_color(x, y);
-                let [r, g, b] = color_bytes(&color);
-                buf.push(r);
-                buf.push(g);
-                buf.push(b);
-            }
+        for color in self.filtered_colors() {
+            let [r, g, b] = display.bytes(&color);
+            buf.push(r);
+            buf.push(g);
+            buf.push(b);
         }
         ImageBuffer::from_raw(self.width, self.height, buf)
             .expect("Image buffer has incorrect size")
     }
 
+    /// Converts the current buffer to a high-dynamic-range image, with linear colors
+    /// that are not clamped, in the primaries of the output space
+    pub fn hdr_image(&self) -> Rgb32FImage {
+        let matrix = self
+            .working_space
+            .conversion_matrix(self.output_space.primaries);
+        let buf = self
+            .filtered_colors()
+            .iter()
+            .flat_map(|color| {
+                (matrix * color)
+                    .iter()
+                    .map(|&c| c as f32)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        ImageBuffer::from_raw(self.width, self.height, buf)
+            .expect("Image buffer has incorrect size")
+    }
+
+    /// Returns the feature buffers as extra channels for an image, named by OpenEXR
+    /// conventions: `albedo.R`, `albedo.G`, `albedo.B`, `normal.X`, `normal.Y`,
+    /// `normal.Z` and `Z` for depth
+    ///
+    /// Pixels without features have the values that they would have if their camera
+    /// rays escaped the scene.
+    pub fn feature_channels(&self) -> Vec<Channel> {
+        let features: Vec<_> = self.pixels.iter().map(Pixel::features).collect();
+        let channel = |name: &str, value: &dyn Fn(&Features) -> f64| Channel {
+            name: name.to_owned(),
+            values: features.iter().map(|f| value(f) as f32).collect(),
+        };
+        vec![
+            channel("albedo.R", &|f| f.albedo.x),
+            channel("albedo.G", &|f| f.albedo.y),
+            channel("albedo.B", &|f| f.albedo.z),
+            channel("normal.X", &|f| f.normal.x),
+            channel("normal.Y", &|f| f.normal.y),
+            channel("normal.Z", &|f| f.normal.z),
+            channel("Z", &|f| f.depth),
+        ]
+    }
+
     /// Return the average color variance of samples in each pixel
+    ///
+    /// Pixels with fewer than two samples have no variance, so they are skipped.
     pub fn variance(&self) -> f64 {
         let mut variance = 0.0;
         let mut count = 0.0;
-        for pix_samples in &self.samples {
-            let mean: Color = pix_samples.iter().sum::<Color>() / (pix_samples.len() as f64);
-            let mut sum_of_squares = 0.0;
-            for sample in pix_samples {
-                sum_of_squares += (sample - mean).magnitude_squared();
-            }
-            // Sample variance: n - 1 degrees of freedom
-            variance += sum_of_squares / (pix_samples.len() as f64 - 1.0);
+        for pixel in self.pixels.iter().filter(|pixel| pixel.count > 1) {
+            variance += pixel.variance().sum();
             count += 1.0;
         }
-        variance / count
+        if count > 0.0 {
+            variance / count
+        } else {
+            0.0
+        }
+    }
+
+    /// Returns the filtered colors of every pixel, in row-major order
+    fn filtered_colors(&self) -> Vec<Color> {
+        match self.filter {
+            Filter::Denoise(ref denoiser) => denoiser.apply(
+                self.width,
+                self.height,
+                &self.pixels,
+                &self.working_space.luminance_weights(),
+            ),
+            _ => (0..self.height)
+                .flat_map(|y| (0..self.width).map(move |x| (x, y)))
+                .map(|(x, y)| self.get_filtered_color(x, y))
+                .collect(),
+        }
     }
 
     fn get_filtered_color(&self, x: u32, y: u32) -> Color {
@@ -80,24 +526,297 @@ impl Buffer {
                 for i in x.saturating_sub(radius)..=(x + radius) {
                     for j in y.saturating_sub(radius)..=(y + radius) {
                         if i < self.width && j < self.height {
-                            let index = (j * self.width + i) as usize;
-                            color += self.samples[index].iter().sum::<Color>();
-                            count += self.samples[index].len();
+                            let pixel = &self.pixels[(j * self.width + i) as usize];
+                            color += pixel.mean * f64::from(pixel.count);
+                            count += pixel.count;
                         }
                     }
                 }
-                assert!(count != 0, "Pixel found with no samples");
-                color / (count as f64)
+                // Pixels can be missing samples if the render was cancelled
+                if count == 0 {
+                    return color;
+                }
+                color / f64::from(count)
+            }
+            Filter::Denoise(_) => self.pixels[(y * self.width + x) as usize].mean,
+            _ => {
+                let pixel = &self.pixels[(y * self.width + x) as usize];
+                if pixel.splat_weight > 0.0 {
+                    pixel.splat / pixel.splat_weight
+                } else {
+                    // Filters with negative lobes can cancel out at the edges of renders
+                    pixel.mean
+                }
             }
         }
     }
 }
 
-/// A noise reduction filter applied to the rendered image
-#[derive(Copy, Clone)]
+/// Running statistics of the samples in a pixel
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Pixel {
+    /// Total number of paths
+    count: u32,
+
+    /// Mean of the samples, as tracked by Welford's algorithm
+    mean: Color,
+
+    /// Sum of squared differences of the samples from the mean, in each channel
+    m2: Color,
+
+    /// Sum of samples splatted onto the pixel, weighted by the reconstruction filter
+    splat: Color,
+
+    /// Sum of the reconstruction filter weights of splatted samples
+    splat_weight: f64,
+
+    /// Sum of the features of camera samples
+    features: Features,
+
+    /// Number of camera samples with features
+    feature_count: u32,
+}
+
+impl Pixel {
+    /// Statistics of a single sample
+    fn from_sample(sample: Color) -> Self {
+        Self {
+            count: 1,
+            mean: sample,
+            m2: glm::vec3(0.0, 0.0, 0.0),
+            splat: glm::vec3(0.0, 0.0, 0.0),
+            splat_weight: 0.0,
+            features: Features::default(),
+            feature_count: 0,
+        }
+    }
+
+    /// Add the statistics of other samples, using the parallel form of Welford's
+    /// algorithm (Chan et al.)
+    fn add(&mut self, other: &Pixel) {
+        self.splat += other.splat;
+        self.splat_weight += other.splat_weight;
+        self.features.albedo += other.features.albedo;
+        self.features.normal += other.features.normal;
+        self.features.depth += other.features.depth;
+        self.feature_count += other.feature_count;
+        if other.count == 0 {
+            return;
+        }
+        let (n_a, n_b) = (f64::from(self.count), f64::from(other.count));
+        let n = n_a + n_b;
+        let delta = other.mean - self.mean;
+        self.mean += delta * (n_b / n);
+        self.m2 += other.m2 + delta.component_mul(&delta) * (n_a * n_b / n);
+        self.count += other.count;
+    }
+
+    /// Average features of the camera samples, or features that do not affect
+    /// denoising if there are none
+    fn features(&self) -> Features {
+        if self.feature_count == 0 {
+            return Features {
+                albedo: glm::vec3(1.0, 1.0, 1.0),
+                ..Features::default()
+            };
+        }
+        let n = f64::from(self.feature_count);
+        let normal = self.features.normal / n;
+        Features {
+            albedo: self.features.albedo / n,
+            // Normals of different surfaces within a pixel partially cancel out
+            normal: if normal.magnitude() > 0.5 {
+                normal.normalize()
+            } else {
+                normal
+            },
+            depth: self.features.depth / n,
+        }
+    }
+
+    /// Sample variance of each channel, with n - 1 degrees of freedom
+    fn variance(&self) -> Color {
+        if self.count > 1 {
+            self.m2 / f64::from(self.count - 1)
+        } else {
+            glm::vec3(0.0, 0.0, 0.0)
+        }
+    }
+}
+
+/// Properties of the first surface seen by a camera sample, which are collected in
+/// feature buffers to guide denoising
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Features {
+    /// Albedo of the surface, or white if the camera ray escapes the scene
+    pub albedo: Color,
+
+    /// Shading normal of the surface facing the camera, or zero if the camera ray
+    /// escapes the scene
+    pub normal: glm::DVec3,
+
+    /// Distance from the camera to the surface, or zero if the camera ray escapes
+    pub depth: f64,
+}
+
+/// A snapshot of a render in progress, which can be saved to disk and resumed
+#[derive(Clone)]
+pub struct Checkpoint {
+    /// Number of passes over the image that have been rendered
+    pub passes: u32,
+
+    /// Seed of the render, so that a resumed render continues the same samples
+    pub seed: u64,
+
+    /// Samples that have been rendered so far
+    pub buffer: Buffer,
+
+    /// Statistics of the samples in each pixel of the full frame, which adaptive
+    /// sampling continues from
+    pub stats: Vec<PixelStats>,
+}
+
+impl Checkpoint {
+    /// Serialize the checkpoint in a compact binary format
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(CHECKPOINT_MAGIC)?;
+        writer.write_all(&self.passes.to_le_bytes())?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&(self.stats.len() as u32).to_le_bytes())?;
+        for stats in &self.stats {
+            writer.write_all(&stats.count.to_le_bytes())?;
+            writer.write_all(&stats.mean.to_le_bytes())?;
+            writer.write_all(&stats.m2.to_le_bytes())?;
+        }
+        self.buffer.write(writer)
+    }
+
+    /// Deserialize a checkpoint that was written by `write`
+    pub fn read(mut reader: impl Read) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a serialized checkpoint, or unsupported version",
+            ));
+        }
+        let mut passes = [0; 4];
+        reader.read_exact(&mut passes)?;
+        let mut seed = [0; 8];
+        reader.read_exact(&mut seed)?;
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        let mut stats = Vec::new();
+        for _ in 0..u32::from_le_bytes(len) {
+            let (mut count, mut mean, mut m2) = ([0; 4], [0; 8], [0; 8]);
+            reader.read_exact(&mut count)?;
+            reader.read_exact(&mut mean)?;
+            reader.read_exact(&mut m2)?;
+            stats.push(PixelStats {
+                count: u32::from_le_bytes(count),
+                mean: f64::from_le_bytes(mean),
+                m2: f64::from_le_bytes(m2),
+            });
+        }
+        Ok(Self {
+            passes: u32::from_le_bytes(passes),
+            seed: u64::from_le_bytes(seed),
+            buffer: Buffer::read(reader)?,
+            stats,
+        })
+    }
+
+    /// Save the checkpoint to a file, replacing it atomically
+    ///
+    /// The checkpoint is written to a temporary file next to the destination first, so
+    /// the destination is never left partially written.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        self.write(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temp, path)
+    }
+
+    /// Load a checkpoint from a file
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+}
+
+/// Write the tag of an enum variant, followed by its parameters
+fn write_tagged(writer: &mut impl Write, tag: u32, params: &[f64]) -> io::Result<()> {
+    writer.write_all(&tag.to_le_bytes())?;
+    for param in params {
+        writer.write_all(&param.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Color of a value from 0 to 1 in a heatmap, as sRGB bytes
+fn heatmap_color(t: f64) -> [u8; 3] {
+    // Black, blue, red, yellow and white, evenly spaced
+    const STOPS: [[f64; 3]; 5] = [
+        [0.0, 0.0, 0.0],
+        [0.0, 0.0, 1.0],
+        [1.0, 0.0, 0.0],
+        [1.0, 1.0, 0.0],
+        [1.0, 1.0, 1.0],
+    ];
+    let x = t.clamp(0.0, 1.0) * (STOPS.len() - 1) as f64;
+    let i = (x as usize).min(STOPS.len() - 2);
+    let (a, b) = (STOPS[i], STOPS[i + 1]);
+    let f = x - i as f64;
+    [0, 1, 2].map(|c| ((a[c] + (b[c] - a[c]) * f) * 255.0).round() as u8)
+}
+
+/// A filter for reconstructing pixels from samples
+///
+/// Other than `Box`, these filters are separable and splat each sample onto the pixels
+/// whose centers are within the radius of it, measured in pixels. See
+/// https://www.pbr-book.org/3ed-2018/Sampling_and_Reconstruction/Image_Reconstruction
+/// for details.
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Filter {
-    /// Box filter with a given radius
+    /// Box filter with a given radius, averaging the samples of neighboring pixels
     Box(u32),
+
+    /// Tent filter, whose weight falls off linearly to zero at the radius
+    Tent {
+        /// Radius of the filter, in pixels
+        radius: f64,
+    },
+
+    /// Gaussian filter, shifted down so that it falls off to zero at the radius
+    Gaussian {
+        /// Radius of the filter, in pixels (usually 1.5)
+        radius: f64,
+        /// Falloff rate of the Gaussian, where larger values are sharper (usually 2)
+        alpha: f64,
+    },
+
+    /// Mitchell-Netravali filter, a cubic with small negative lobes
+    Mitchell {
+        /// Radius of the filter, in pixels (usually 2)
+        radius: f64,
+        /// Blurring parameter B (usually 1/3)
+        b: f64,
+        /// Ringing parameter C (usually 1/3)
+        c: f64,
+    },
+
+    /// Lanczos filter, a sinc function windowed by a wider sinc lobe
+    Lanczos {
+        /// Radius of the filter in pixels, which is also the number of lobes (usually 3)
+        radius: f64,
+    },
+
+    /// Edge-aware denoising filter, guided by feature buffers that are collected
+    /// during rendering
+    Denoise(Denoiser),
 }
 
 impl Default for Filter {
@@ -106,3 +825,204 @@ impl Default for Filter {
         Self::Box(0)
     }
 }
+
+impl Filter {
+    /// Returns whether the filter is guided by feature buffers, which the renderer
+    /// then collects
+    pub fn uses_features(&self) -> bool {
+        matches!(self, Self::Denoise(_))
+    }
+
+    /// Radius of the filter if it splats samples, or `None` for the box filter and
+    /// the denoiser
+    fn splat_radius(&self) -> Option<f64> {
+        match *self {
+            Self::Box(_) | Self::Denoise(_) => None,
+            Self::Tent { radius }
+            | Self::Gaussian { radius, .. }
+            | Self::Mitchell { radius, .. }
+            | Self::Lanczos { radius } => Some(radius),
+        }
+    }
+
+    /// Evaluate the filter in one dimension, at an offset from the sample in pixels
+    fn evaluate(&self, x: f64) -> f64 {
+        let x = x.abs();
+        match *self {
+            Self::Box(_) | Self::Denoise(_) => 1.0,
+            Self::Tent { radius } => (1.0 - x / radius).max(0.0),
+            Self::Gaussian { radius, alpha } => {
+                ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.0)
+            }
+            Self::Mitchell { radius, b, c } => {
+                let x = 2.0 * x / radius;
+                if x > 2.0 {
+                    0.0
+                } else if x > 1.0 {
+                    ((-b - 6.0 * c) * x.powi(3)
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            }
+            Self::Lanczos { radius } => {
+                if x >= radius {
+                    0.0
+                } else {
+                    sinc(x) * sinc(x / radius)
+                }
+            }
+        }
+    }
+}
+
+/// Normalized sinc function, sin(pi x) / (pi x)
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.0
+    } else {
+        let x = std::f64::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn running_variance_matches_samples() {
+        let samples: Vec<Color> = (0..10)
+            .map(|i| glm::vec3(f64::from(i), f64::from(i * i), 1.0))
+            .collect();
+        let mean = samples.iter().sum::<Color>() / 10.0;
+        let variance = samples
+            .iter()
+            .map(|s| (s - mean).magnitude_squared())
+            .sum::<f64>()
+            / 9.0;
+
+        // Split the samples between two buffers, then merge them
+        let mut buffer = Buffer::new(1, 1, Filter::default());
+        let mut other = Buffer::new(1, 1, Filter::default());
+        for (i, &sample) in samples.iter().enumerate() {
+            if i < 3 {
+                buffer.add_sample(0, 0, sample);
+            } else {
+                other.add_samples(&[sample]);
+            }
+        }
+        buffer.merge(&other);
+        assert_eq!(buffer.sample_count(0, 0), 10);
+        assert!((buffer.variance() - variance).abs() < 1e-9);
+        assert!((buffer.get_filtered_color(0, 0) - mean).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn serialized_buffer_round_trips() {
+        let filters = [
+            Filter::Box(1),
+            Filter::Mitchell {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+        ];
+        for filter in filters {
+            let mut buffer = Buffer::new(3, 2, filter);
+            buffer.set_tone_map(ToneMap::ReinhardExtended { white: 4.0 });
+            buffer.set_working_space(Primaries::Ap1);
+            buffer.set_output_space(ColorSpace::new(Primaries::P3, TransferFunction::Srgb));
+            buffer.add_sample(0, 0, glm::vec3(0.1, 0.2, 0.3));
+            buffer.add_samples(&[glm::vec3(1.0, 2.0, 3.0); 6]);
+            let mut bytes = Vec::new();
+            buffer.write(&mut bytes).unwrap();
+            let read = Buffer::read(bytes.as_slice()).unwrap();
+            assert_eq!(read.filter, buffer.filter);
+            assert_eq!(read.tone_map, buffer.tone_map);
+            assert_eq!(read.working_space, buffer.working_space);
+            assert_eq!(read.output_space, buffer.output_space);
+            assert_eq!(read.pixels, buffer.pixels);
+            assert_eq!(read.image(), buffer.image());
+            assert!(Buffer::read(&bytes[..bytes.len() - 1]).is_err());
+
+            // A corrupt header is rejected without allocating its dimensions
+            bytes[8..16].copy_from_slice(&[0xff; 8]);
+            assert!(Buffer::read(bytes.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn reconstruction_filters_preserve_constant_color() {
+        let filters = [
+            Filter::Tent { radius: 1.0 },
+            Filter::Gaussian {
+                radius: 1.5,
+                alpha: 2.0,
+            },
+            Filter::Mitchell {
+                radius: 2.0,
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+            Filter::Lanczos { radius: 3.0 },
+        ];
+        let color = glm::vec3(0.2, 0.5, 0.7);
+        for filter in filters {
+            assert_eq!(filter.evaluate(filter.splat_radius().unwrap() + 0.1), 0.0);
+            let mut buffer = Buffer::new(6, 5, filter);
+            for i in 0..60 {
+                for j in 0..50 {
+                    let (x, y) = ((f64::from(i) + 0.3) / 10.0, (f64::from(j) + 0.6) / 10.0);
+                    buffer.add_film_sample(x, y, color);
+                }
+            }
+            for y in 0..5 {
+                for x in 0..6 {
+                    assert_eq!(buffer.sample_count(x, y), 100);
+                    assert!((buffer.get_filtered_color(x, y) - color).magnitude() < 1e-9);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn denoiser_preserves_albedo_edges() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut buffer = Buffer::new(16, 16, Filter::Denoise(Denoiser::default()));
+        let albedo = |x: u32| {
+            if x < 8 {
+                glm::vec3(0.2, 0.2, 0.2)
+            } else {
+                glm::vec3(0.8, 0.8, 0.8)
+            }
+        };
+        for y in 0..16 {
+            for x in 0..16 {
+                for _ in 0..2 {
+                    let noise = rng.gen_range(0.5..1.5);
+                    buffer.add_sample(x, y, albedo(x) * noise);
+                    let features = Features {
+                        albedo: albedo(x),
+                        normal: glm::vec3(0.0, 0.0, 1.0),
+                        depth: 1.0,
+                    };
+                    buffer.add_features(x, y, &features);
+                }
+            }
+        }
+        let colors = buffer.filtered_colors();
+        let error = |x: u32, y: u32| (colors[(y * 16 + x) as usize] - albedo(x)).magnitude();
+        for y in 0..16 {
+            assert!(error(7, y) < 0.1 && error(8, y) < 0.2);
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use rayon::prelude::*;
+
+use super::Pixel;
+use crate::color::Color;
+
+/// Smallest albedo that colors are divided by when separating out texture detail
+const MIN_ALBEDO: f64 = 1e-2;
+
+/// Number of samples per pixel below which the variance of a pixel is estimated from
+/// its neighbors, rather than from its own samples
+const MIN_TEMPORAL_SAMPLES: u32 = 4;
+
+/// Weights of the 5-tap B3 spline kernel used at each level of the wavelet transform
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+/// Parameters of an edge-avoiding à-trous wavelet denoiser, guided by the variance of
+/// the samples and by albedo, normal and depth feature buffers
+///
+/// Colors are divided by albedo before filtering and multiplied back afterwards, so
+/// texture detail is preserved. See "Edge-Avoiding À-Trous Wavelet Transform for fast
+/// Global Illumination Filtering" (Dammertz et al. 2010) and "Spatiotemporal
+/// Variance-Guided Filtering" (Schied et al. 2017) for details.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Denoiser {
+    /// Number of filtering passes, each of which doubles the width of the filter
+    pub iterations: u32,
+
+    /// Tolerance for differences in luminance, in standard deviations of the noise
+    pub sigma_luminance: f64,
+
+    /// Exponent applied to the cosine between normals, where larger values preserve
+    /// edges between surfaces more sharply
+    pub sigma_normal: f64,
+
+    /// Tolerance for differences in depth, relative to the depth
+    pub sigma_depth: f64,
+
+    /// Tolerance for differences in albedo
+    pub sigma_albedo: f64,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_luminance: 4.0,
+            sigma_normal: 128.0,
+            sigma_depth: 0.1,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+/// A pixel in the denoiser, with its features
+#[derive(Copy, Clone)]
+struct Texel {
+    /// Color divided by albedo
+    color: Color,
+
+    /// Estimated variance of the luminance of `color`
+    variance: f64,
+
+    albedo: Color,
+    normal: glm::DVec3,
+    depth: f64,
+
+    /// Whether the pixel has any samples
+    valid: bool,
+}
+
+impl Denoiser {
+    /// Denoise the pixels of a buffer, returning their colors in row-major order
+    ///
+    /// Differences in color are measured by luminance, with the weights of the
+    /// primaries of the buffer's working space.
+    pub(super) fn apply(
+        &self,
+        width: u32,
+        height: u32,
+        pixels: &[Pixel],
+        luminance_weights: &Color,
+    ) -> Vec<Color> {
+        let (width, height) = (width as usize, height as usize);
+        let mut texels: Vec<_> = pixels
+            .iter()
+            .map(|pixel| texel(pixel, luminance_weights))
+            .collect();
+
+        // Estimate the variance of pixels with too few samples from their neighbors
+        let spatial: Vec<_> = (0..texels.len())
+            .into_par_iter()
+            .map(|p| {
+                let (x, y) = (p % width, p / width);
+                let mut moments = [0.0; 3];
+                for j in y.saturating_sub(1)..(y + 2).min(height) {
+                    for i in x.saturating_sub(1)..(x + 2).min(width) {
+                        let q = &texels[j * width + i];
+                        if q.valid {
+                            let l = luminance_weights.dot(&q.color);
+                            moments[0] += 1.0;
+                            moments[1] += l;
+                            moments[2] += l * l;
+                        }
+                    }
+                }
+                let mean = moments[1] / moments[0].max(1.0);
+                (moments[2] / moments[0].max(1.0) - mean * mean).max(0.0)
+            })
+            .collect();
+        for ((texel, pixel), variance) in texels.iter_mut().zip(pixels).zip(spatial) {
+            if pixel.count < MIN_TEMPORAL_SAMPLES {
+                texel.variance = variance;
+            }
+        }
+
+        for iteration in 0..self.iterations {
+            let step = 1_usize << iteration;
+            texels = (0..texels.len())
+                .into_par_iter()
+                .map(|p| self.filter(&texels, width, height, p, step, luminance_weights))
+                .collect();
+        }
+        texels
+            .iter()
+            .map(|texel| texel.color.component_mul(&texel.albedo))
+            .collect()
+    }
+
+    /// Filter one pixel at a level of the wavelet transform
+    fn filter(
+        &self,
+        texels: &[Texel],
+        width: usize,
+        height: usize,
+        p: usize,
+        step: usize,
+        luminance_weights: &Color,
+    ) -> Texel {
+        let center = texels[p];
+        if !center.valid {
+            return center;
+        }
+        let (x, y) = ((p % width) as isize, (p / width) as isize);
+        let l_p = luminance_weights.dot(&center.color);
+        let sigma_l = self.sigma_luminance * center.variance.sqrt() + 1e-10;
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        let mut variance = 0.0;
+        let mut total = 0.0;
+        for (dy, ky) in KERNEL.iter().enumerate() {
+            let j = y + (dy as isize - 2) * step as isize;
+            if j < 0 || j >= height as isize {
+                continue;
+            }
+            for (dx, kx) in KERNEL.iter().enumerate() {
+                let i = x + (dx as isize - 2) * step as isize;
+                if i < 0 || i >= width as isize {
+                    continue;
+                }
+                let q = &texels[j as usize * width + i as usize];
+                if !q.valid {
+                    continue;
+                }
+                let w_l = (-(l_p - luminance_weights.dot(&q.color)).abs() / sigma_l).exp();
+                let w_n = if center.normal == q.normal {
+                    1.0
+                } else {
+                    center
+                        .normal
+                        .dot(&q.normal)
+                        .max(0.0)
+                        .powf(self.sigma_normal)
+                };
+                let w_z = (-(center.depth - q.depth).abs()
+                    / (self.sigma_depth * center.depth.max(q.depth) + 1e-10))
+                    .exp();
+                let w_a = (-(center.albedo - q.albedo).magnitude_squared()
+                    / (self.sigma_albedo * self.sigma_albedo))
+                    .exp();
+                let w = kx * ky * w_l * w_n * w_z * w_a;
+                color += q.color * w;
+                variance += w * w * q.variance;
+                total += w;
+            }
+        }
+        // The center pixel always has a positive weight
+        Texel {
+            color: color / total,
+            variance: variance / (total * total),
+            ..center
+        }
+    }
+}
+
+/// Convert a pixel to the form used by the denoiser
+fn texel(pixel: &Pixel, luminance_weights: &Color) -> Texel {
+    if pixel.count == 0 {
+        return Texel {
+            color: glm::vec3(0.0, 0.0, 0.0),
+            variance: 0.0,
+            albedo: glm::vec3(0.0, 0.0, 0.0),
+            normal: glm::vec3(0.0, 0.0, 0.0),
+            depth: 0.0,
+            valid: false,
+        };
+    }
+    let features = pixel.features();
+    let albedo = features.albedo.map(|c| c.max(MIN_ALBEDO));
+    let n = f64::from(pixel.count);
+    // Variance of the mean in each channel, scaled by the albedo
+    let variance = pixel
+        .variance()
+        .component_div(&albedo.component_mul(&albedo))
+        / n;
+    // Luminance weights are squared to weight the variance of each channel, ignoring
+    // correlation between channels
+    let weights = luminance_weights.component_mul(luminance_weights);
+    Texel {
+        color: pixel.mean.component_div(&albedo),
+        variance: weights.dot(&variance),
+        albedo,
+        normal: features.normal,
+        depth: features.depth,
+        valid: true,
+    }
+}
@@ -1,6 +1,4 @@
-use rand::{rngs::StdRng, Rng};
-use rand_distr::UnitDisc;
-
+use crate::sampler::{sample_disk, Sampler};
 use crate::shape::Ray;
 
 /// A simple thin-lens perspective camera
@@ -23,6 +21,15 @@ pub struct Camera {
 
     /// Focal distance, if aperture radius is nonzero
     pub focal_distance: f64,
+
+    /// Photographic settings, which set the exposure of the renderer if present
+    pub physical: Option<PhysicalCamera>,
+
+    /// Time that the shutter opens, when rays start being traced
+    pub shutter_open: f64,
+
+    /// Time that the shutter closes, for motion blur if later than `shutter_open`
+    pub shutter_close: f64,
 }
 
 impl Default for Camera {
@@ -34,6 +41,9 @@ impl Default for Camera {
             fov: std::f64::consts::FRAC_PI_6,
             aperture: 0.0,
             focal_distance: 0.0,
+            physical: None,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         }
     }
 }
@@ -50,33 +60,200 @@ impl Camera {
             fov,
             aperture: 0.0,
             focal_distance: 0.0,
+            physical: None,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
         }
     }
 
     /// Focus the camera on a position, with simulated depth-of-field
     pub fn focus(mut self, focal_point: glm::DVec3, aperture: f64) -> Self {
-        self.focal_distance = (focal_point - self.eye).dot(&self.direction);
         self.aperture = aperture;
+        self.focus_at(focal_point)
+    }
+
+    /// Focus the camera on a position, keeping its aperture radius
+    pub fn focus_at(mut self, focal_point: glm::DVec3) -> Self {
+        self.focal_distance = (focal_point - self.eye).dot(&self.direction);
+        self
+    }
+
+    /// Use photographic settings, which replace the field of view and aperture radius
+    /// and set the exposure
+    ///
+    /// Depth-of-field also needs a focal distance, which can be set with `focus_at`.
+    pub fn physical(mut self, physical: PhysicalCamera) -> Self {
+        self.fov = physical.fov();
+        self.aperture = physical.aperture_radius();
+        self.physical = Some(physical);
         self
     }
 
+    /// Keep the shutter open over an interval of time, which blurs animated shapes
+    pub fn shutter(mut self, open: f64, close: f64) -> Self {
+        self.shutter_open = open;
+        self.shutter_close = close;
+        self
+    }
+
+    /// Sample a moment in time while the shutter is open
+    pub fn sample_time(&self, sampler: &mut dyn Sampler) -> f64 {
+        if self.shutter_close > self.shutter_open {
+            let t = sampler.next_1d();
+            self.shutter_open * (1.0 - t) + self.shutter_close * t
+        } else {
+            self.shutter_open
+        }
+    }
+
     /// Cast a ray, where (x, y) are normalized to the standard [-1, 1] box
-    pub fn cast_ray(&self, x: f64, y: f64, rng: &mut StdRng) -> Ray {
+    pub fn cast_ray(&self, x: f64, y: f64, sampler: &mut dyn Sampler) -> Ray {
         // cot(f / 2) = depth / radius
         let d = (self.fov / 2.0).tan().recip();
         let right = glm::cross(&self.direction, &self.up).normalize();
         let mut origin = self.eye;
         let mut new_dir = d * self.direction + x * right + y * self.up;
-        if self.aperture > 0.0 {
+        if self.aperture > 0.0 && self.focal_distance > 0.0 {
             // Depth of field
             let focal_point = origin + new_dir.normalize() * self.focal_distance;
-            let [x, y]: [f64; 2] = rng.sample(UnitDisc);
+            let [x, y] = sample_disk(sampler.next_2d());
             origin += (x * right + y * self.up) * self.aperture;
             new_dir = focal_point - origin;
         }
         Ray {
             origin,
             dir: new_dir.normalize(),
+            time: self.sample_time(sampler),
         }
     }
 }
+
+/// Photographic settings of a camera, with a lens and a sensor
+///
+/// The f-number sets both the aperture radius, for depth-of-field, and the exposure,
+/// along with the shutter time and ISO sensitivity. Exposure follows the photographic
+/// convention that a scene luminance of 1 cd/m² (taking radiance values as luminance)
+/// saturates the sensor at an EV100 of about -0.26, so physically scaled lights render
+/// at the same brightness as they would through a real camera.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PhysicalCamera {
+    /// Focal length of the lens, in millimeters
+    pub focal_length: f64,
+
+    /// Width of the sensor in the longer direction, in millimeters
+    pub sensor_width: f64,
+
+    /// Ratio of the focal length to the diameter of the aperture
+    pub f_number: f64,
+
+    /// Time that the shutter is open, in seconds
+    pub shutter_time: f64,
+
+    /// ISO sensitivity of the sensor
+    pub iso: f64,
+
+    /// Number of scene units in a meter, for converting the aperture radius
+    pub units_per_meter: f64,
+}
+
+impl Default for PhysicalCamera {
+    fn default() -> Self {
+        // A 50 mm lens on a full-frame sensor, at f/8, 1/125 s and ISO 100
+        Self {
+            focal_length: 50.0,
+            sensor_width: 36.0,
+            f_number: 8.0,
+            shutter_time: 1.0 / 125.0,
+            iso: 100.0,
+            units_per_meter: 1.0,
+        }
+    }
+}
+
+impl PhysicalCamera {
+    /// Field of view in the longer direction, in radians
+    pub fn fov(&self) -> f64 {
+        2.0 * (self.sensor_width / (2.0 * self.focal_length)).atan()
+    }
+
+    /// Radius of the aperture, in scene units
+    pub fn aperture_radius(&self) -> f64 {
+        self.focal_length / (2.0 * self.f_number) / 1000.0 * self.units_per_meter
+    }
+
+    /// Exposure value at ISO 100, which is higher for darker exposures
+    pub fn ev100(&self) -> f64 {
+        (self.f_number * self.f_number / self.shutter_time * 100.0 / self.iso).log2()
+    }
+
+    /// Factor that radiance is multiplied by to expose the image, using the saturation
+    /// based sensitivity of the sensor
+    pub fn exposure(&self) -> f64 {
+        // Sensors saturate at a luminance of 78 N² / (S q t), with the lens and vignetting
+        // factor q = 0.65, which gives 1.2 times 2^EV100
+        1.0 / (1.2 * 2.0_f64.powf(self.ev100()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::renderer::Renderer;
+    use crate::scene::Scene;
+
+    #[test]
+    fn physical_camera_works() {
+        let physical = PhysicalCamera {
+            f_number: 2.0,
+            ..Default::default()
+        };
+        assert!((physical.fov().to_degrees() - 39.6).abs() < 0.1);
+        assert!((physical.aperture_radius() - 0.0125).abs() < 1e-12);
+
+        // Sunny 16 rule: f/16 at 1/100 s and ISO 100
+        let sunny = PhysicalCamera {
+            f_number: 16.0,
+            shutter_time: 0.01,
+            ..Default::default()
+        };
+        assert!((sunny.ev100() - 14.64).abs() < 0.01);
+        let faster = PhysicalCamera {
+            iso: 200.0,
+            shutter_time: 0.005,
+            ..sunny
+        };
+        assert!((faster.exposure() - sunny.exposure()).abs() < 1e-15);
+        let brighter = PhysicalCamera {
+            iso: 400.0,
+            ..sunny
+        };
+        assert!((brighter.exposure() / sunny.exposure() - 4.0).abs() < 1e-9);
+
+        let camera = Camera::default().physical(physical);
+        assert_eq!(camera.fov, physical.fov());
+        assert_eq!(camera.aperture, physical.aperture_radius());
+    }
+
+    #[test]
+    fn exposure_matches_photographic_values() {
+        // f/8 at 1/125 s and ISO 100 is EV100 log2(64 * 125), about 12.97
+        let default = PhysicalCamera::default();
+        assert!((default.ev100() - 8000.0_f64.log2()).abs() < 1e-12);
+        assert!((default.exposure() - 1.0 / 9600.0).abs() < 1e-15);
+
+        // A luminance of 1 saturates the sensor at an EV100 of log2(1 / 1.2)
+        let saturated = PhysicalCamera {
+            f_number: 1.0,
+            shutter_time: 1.2,
+            ..Default::default()
+        };
+        assert!((saturated.ev100() + 0.263).abs() < 1e-3);
+        assert!((saturated.exposure() - 1.0).abs() < 1e-12);
+
+        // The renderer's exposure value adds to the exposure of the camera
+        let scene = Scene::new();
+        let camera = Camera::default().physical(default);
+        let renderer = Renderer::new(&scene, camera).exposure_value(1.0);
+        assert!((renderer.exposure() - 2.0 / 9600.0).abs() < 1e-15);
+    }
+}
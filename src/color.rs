@@ -6,6 +6,9 @@ const SRGB_GAMMA: f64 = 2.2;
 /// Construct a new color from an sRGB hex integer, applying gamma correction to
 /// return linear intensities
 ///
+/// This approximates the sRGB transfer function with a 2.2 gamma. For the exact
+/// function, or other color spaces, use `ColorSpace::hex_color`.
+///
 /// Example of use: `hex_color(0xFFFFFF)` for white, or `hex_color(0xAB23F0)` for purple.
 pub fn hex_color(x: u32) -> Color {
     let r = ((x >> 16) & 0xff) as f64 / 255.0;
@@ -17,12 +20,231 @@ pub fn hex_color(x: u32) -> Color {
 /// Convert a color to a clamped triple of sRGB unsigned bytes
 pub fn color_bytes(color: &Color) -> [u8; 3] {
     [
-        (color.x.max(0.0).min(1.0).powf(1.0 / SRGB_GAMMA) * 255.0) as u8,
-        (color.y.max(0.0).min(1.0).powf(1.0 / SRGB_GAMMA) * 255.0) as u8,
-        (color.z.max(0.0).min(1.0).powf(1.0 / SRGB_GAMMA) * 255.0) as u8,
+        (color.x.clamp(0.0, 1.0).powf(1.0 / SRGB_GAMMA) * 255.0) as u8,
+        (color.y.clamp(0.0, 1.0).powf(1.0 / SRGB_GAMMA) * 255.0) as u8,
+        (color.z.clamp(0.0, 1.0).powf(1.0 / SRGB_GAMMA) * 255.0) as u8,
     ]
 }
 
+/// Relative luminance of a linear color, with Rec. 709 primaries
+///
+/// For colors with other primaries, use the weights from `Primaries::luminance_weights`.
+pub fn luminance(color: &Color) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+/// A transfer function, which encodes linear values in [0, 1] for display
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TransferFunction {
+    /// No encoding, for linear values
+    Linear,
+
+    /// Pure power-law gamma, usually 2.2
+    Gamma(f64),
+
+    /// Exact piecewise sRGB transfer function, with a linear segment near black
+    Srgb,
+}
+
+impl Default for TransferFunction {
+    fn default() -> Self {
+        Self::Gamma(SRGB_GAMMA)
+    }
+}
+
+impl TransferFunction {
+    /// Encode a linear value in [0, 1]
+    pub fn encode(&self, x: f64) -> f64 {
+        match *self {
+            Self::Linear => x,
+            Self::Gamma(gamma) => x.powf(1.0 / gamma),
+            Self::Srgb => {
+                if x <= 0.003_130_8 {
+                    12.92 * x
+                } else {
+                    1.055 * x.powf(1.0 / 2.4) - 0.055
+                }
+            }
+        }
+    }
+
+    /// Decode a value in [0, 1] to a linear value, which inverts `encode`
+    pub fn decode(&self, x: f64) -> f64 {
+        match *self {
+            Self::Linear => x,
+            Self::Gamma(gamma) => x.powf(gamma),
+            Self::Srgb => {
+                if x <= 0.040_45 {
+                    x / 12.92
+                } else {
+                    ((x + 0.055) / 1.055).powf(2.4)
+                }
+            }
+        }
+    }
+}
+
+/// The red, green and blue primaries and white point of an RGB color space
+///
+/// Colors in a scene are linear combinations of the primaries of the renderer's
+/// working space, which is Rec. 709 (the primaries of sRGB) by default. They are read
+/// as working space values without any conversion, so changing the working space
+/// changes the colors of a scene.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Primaries {
+    /// ITU-R BT.709 primaries, shared by sRGB, with a D65 white point
+    #[default]
+    Rec709,
+
+    /// DCI-P3 primaries with a D65 white point, as in Display P3
+    P3,
+
+    /// ACES AP1 primaries, as in ACEScg, with the ACES white point
+    Ap1,
+
+    /// ITU-R BT.2020 primaries with a D65 white point
+    Rec2020,
+}
+
+impl Primaries {
+    /// CIE xy chromaticities of the red, green and blue primaries and the white point
+    fn chromaticities(&self) -> [[f64; 2]; 4] {
+        match self {
+            Self::Rec709 => [[0.64, 0.33], [0.30, 0.60], [0.15, 0.06], D65],
+            Self::P3 => [[0.680, 0.320], [0.265, 0.690], [0.150, 0.060], D65],
+            Self::Ap1 => [
+                [0.713, 0.293],
+                [0.165, 0.830],
+                [0.128, 0.044],
+                [0.321_68, 0.337_67],
+            ],
+            Self::Rec2020 => [[0.708, 0.292], [0.170, 0.797], [0.131, 0.046], D65],
+        }
+    }
+
+    /// Matrix from linear RGB to CIE XYZ, with white adapted to D65 by the Bradford
+    /// transform
+    pub fn to_xyz(&self) -> glm::DMat3 {
+        let [r, g, b, white] = self.chromaticities();
+        let primaries = glm::DMat3::from_columns(&[xyz(r), xyz(g), xyz(b)]);
+        // Scale each primary so that equal amounts of them add up to white
+        let scale = invert(&primaries) * xyz(white);
+        let matrix = primaries * glm::DMat3::from_diagonal(&scale);
+        if white == D65 {
+            matrix
+        } else {
+            let bradford = glm::DMat3::from_row_slice(&BRADFORD);
+            let ratio = (bradford * xyz(D65)).component_div(&(bradford * xyz(white)));
+            invert(&bradford) * glm::DMat3::from_diagonal(&ratio) * bradford * matrix
+        }
+    }
+
+    /// Weights of the red, green and blue channels in the relative luminance of a linear
+    /// color with these primaries (the Y row of `to_xyz`)
+    pub fn luminance_weights(&self) -> Color {
+        self.to_xyz().row(1).transpose()
+    }
+
+    /// Matrix that converts linear colors with these primaries to other primaries
+    pub fn conversion_matrix(&self, to: Primaries) -> glm::DMat3 {
+        if *self == to {
+            glm::DMat3::identity()
+        } else {
+            invert(&to.to_xyz()) * self.to_xyz()
+        }
+    }
+
+    /// Convert a linear color with these primaries to other primaries
+    pub fn convert(&self, color: &Color, to: Primaries) -> Color {
+        self.conversion_matrix(to) * color
+    }
+}
+
+/// An RGB color space, with primaries and a transfer function
+///
+/// The default is Rec. 709 primaries with a 2.2 gamma, like `color_bytes`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ColorSpace {
+    /// Primaries and white point of the color space
+    pub primaries: Primaries,
+
+    /// Transfer function that encodes linear values in the color space
+    pub transfer_function: TransferFunction,
+}
+
+impl ColorSpace {
+    /// The sRGB color space, with the exact piecewise transfer function
+    pub const SRGB: Self = Self::new(Primaries::Rec709, TransferFunction::Srgb);
+
+    /// Linear sRGB, or Rec. 709
+    pub const LINEAR_REC709: Self = Self::new(Primaries::Rec709, TransferFunction::Linear);
+
+    /// Display P3, which uses the sRGB transfer function
+    pub const DISPLAY_P3: Self = Self::new(Primaries::P3, TransferFunction::Srgb);
+
+    /// ACEScg, the linear working space of ACES
+    pub const ACESCG: Self = Self::new(Primaries::Ap1, TransferFunction::Linear);
+
+    /// Linear Rec. 2020
+    pub const REC2020: Self = Self::new(Primaries::Rec2020, TransferFunction::Linear);
+
+    /// Construct a new color space from primaries and a transfer function
+    pub const fn new(primaries: Primaries, transfer_function: TransferFunction) -> Self {
+        Self {
+            primaries,
+            transfer_function,
+        }
+    }
+
+    /// Convert an encoded color in this space to a linear color with the primaries of a
+    /// working space
+    pub fn to_linear(&self, color: &Color, working_space: Primaries) -> Color {
+        let decoded = color.map(|c| self.transfer_function.decode(c));
+        self.primaries.convert(&decoded, working_space)
+    }
+
+    /// Convert a linear color with the primaries of a working space to an encoded color
+    /// in this space
+    pub fn from_linear(&self, color: &Color, working_space: Primaries) -> Color {
+        let converted = working_space.convert(color, self.primaries);
+        converted.map(|c| self.transfer_function.encode(c))
+    }
+
+    /// Construct a linear color in a working space from a hex integer encoded in this
+    /// space, like `hex_color`
+    ///
+    /// Example of use: `ColorSpace::DISPLAY_P3.hex_color(0xFF0000, Primaries::Rec709)`
+    /// for the most saturated red on a P3 display, which is outside of the sRGB gamut.
+    pub fn hex_color(&self, x: u32, working_space: Primaries) -> Color {
+        let r = ((x >> 16) & 0xff) as f64 / 255.0;
+        let g = ((x >> 8) & 0xff) as f64 / 255.0;
+        let b = (x & 0xff) as f64 / 255.0;
+        self.to_linear(&glm::vec3(r, g, b), working_space)
+    }
+}
+
+/// CIE xy chromaticity of the D65 standard illuminant
+const D65: [f64; 2] = [0.3127, 0.3290];
+
+/// Bradford cone response matrix, used for chromatic adaptation, in row-major order
+const BRADFORD: [f64; 9] = [
+    0.8951, 0.2664, -0.1614, //
+    -0.7502, 1.7135, 0.0367, //
+    0.0389, -0.0685, 1.0296,
+];
+
+/// CIE XYZ coordinates of a chromaticity, with unit luminance
+fn xyz([x, y]: [f64; 2]) -> glm::DVec3 {
+    glm::vec3(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// Invert a color space matrix, which is always invertible
+fn invert(matrix: &glm::DMat3) -> glm::DMat3 {
+    matrix
+        .try_inverse()
+        .expect("Color space matrix should be invertible")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -36,4 +258,55 @@ mod tests {
         assert_eq!(color_bytes(&white), [255, 255, 255]);
         assert_eq!(color_bytes(&red), [255, 0, 0]);
     }
+
+    #[test]
+    fn transfer_functions_invert() {
+        for transfer in [TransferFunction::Gamma(2.2), TransferFunction::Srgb] {
+            for i in 0..=100 {
+                let x = f64::from(i) / 100.0;
+                assert!((transfer.decode(transfer.encode(x)) - x).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    fn color_spaces_convert() {
+        // Published matrix from linear sRGB to ACEScg, with Bradford adaptation
+        let expected = glm::mat3(
+            0.613_097, 0.339_523, 0.047_379, //
+            0.070_194, 0.916_354, 0.013_452, //
+            0.020_616, 0.109_570, 0.869_815,
+        );
+        let matrix = Primaries::Rec709.conversion_matrix(Primaries::Ap1);
+        assert!((matrix - expected).abs().max() < 1e-3);
+
+        let white = glm::vec3(1.0, 1.0, 1.0);
+        for primaries in [Primaries::P3, Primaries::Ap1, Primaries::Rec2020] {
+            let converted = Primaries::Rec709.convert(&white, primaries);
+            assert!((converted - white).abs().max() < 1e-3);
+            let back = primaries.convert(&converted, Primaries::Rec709);
+            assert!((back - white).abs().max() < 1e-9);
+        }
+
+        let red = ColorSpace::SRGB.hex_color(0xff0000, Primaries::Rec709);
+        assert!((red - glm::vec3(1.0, 0.0, 0.0)).abs().max() < 1e-9);
+        let orange = glm::vec3(1.0, 0.5, 0.1);
+        let encoded = ColorSpace::DISPLAY_P3.from_linear(&orange, Primaries::Rec709);
+        let decoded = ColorSpace::DISPLAY_P3.to_linear(&encoded, Primaries::Rec709);
+        assert!((decoded - orange).abs().max() < 1e-9);
+    }
+
+    #[test]
+    fn luminance_weights_match_primaries() {
+        let color = glm::vec3(0.3, 0.6, 0.9);
+        let weights = Primaries::Rec709.luminance_weights();
+        assert!((weights.dot(&color) - luminance(&color)).abs() < 1e-4);
+        for primaries in [Primaries::P3, Primaries::Ap1, Primaries::Rec2020] {
+            let weights = primaries.luminance_weights();
+            assert!((weights.sum() - 1.0).abs() < 1e-9);
+            // Converting a color keeps its luminance
+            let converted = Primaries::Rec709.convert(&color, primaries);
+            assert!((weights.dot(&converted) - luminance(&color)).abs() < 1e-4);
+        }
+    }
 }
@@ -1,4 +1,5 @@
-use crate::color::Color;
+use crate::color::{Color, Primaries};
+use crate::sampler::Sampler;
 
 /// High-dynamic-range equirectangular image for lighting 3D scenes
 #[derive(Clone)]
@@ -11,14 +12,44 @@ pub struct Hdri {
 
     /// Buffer of floating-point RGB pixels
     buf: Vec<Color>,
+
+    /// Distribution for importance sampling directions by luminance
+    distribution: Distribution2D,
 }
 
 impl Hdri {
-    /// Create a new HDRI image
+    /// Create a new HDRI image, whose colors have Rec. 709 primaries
     pub fn new(width: u32, height: u32, buf: Vec<Color>) -> Self {
         assert!(buf.len() == width as usize * height as usize);
         assert!(width > 0 && height > 0);
-        Self { width, height, buf }
+        let weights = Primaries::default().luminance_weights();
+        let distribution = Self::distribution(width, height, &buf, &weights);
+        Self {
+            width,
+            height,
+            buf,
+            distribution,
+        }
+    }
+
+    /// Set the primaries of the image's colors, which should be the working space of
+    /// the renderer (builder pattern)
+    ///
+    /// Colors are not converted, but directions are importance sampled by their
+    /// luminance with the weights of these primaries.
+    pub fn working_space(mut self, working_space: Primaries) -> Self {
+        let weights = working_space.luminance_weights();
+        self.distribution = Self::distribution(self.width, self.height, &self.buf, &weights);
+        self
+    }
+
+    /// Distribution of pixels proportional to their luminance, with given weights
+    fn distribution(width: u32, height: u32, buf: &[Color], weights: &Color) -> Distribution2D {
+        Distribution2D::new(width as usize, height as usize, |x, y| {
+            // Weight by sin θ to account for the distortion of the equirectangular map
+            let polar = (y as f64 + 0.5) / height as f64 * std::f64::consts::PI;
+            weights.dot(&buf[y * width as usize + x]) * polar.sin()
+        })
     }
 
     /// Sample a color from a direction in the environment
@@ -31,6 +62,26 @@ impl Hdri {
         self.bilinear_sample(x, y)
     }
 
+    /// Sample a direction in the environment proportional to luminance, returning the
+    /// direction and its PDF with respect to solid angle
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> (glm::DVec3, f64) {
+        let ([u, v], pdf) = self.distribution.sample(sampler);
+        let azimuth = u * std::f64::consts::TAU - std::f64::consts::PI;
+        let polar = v * std::f64::consts::PI;
+        let (sin_p, cos_p) = polar.sin_cos();
+        let dir = glm::vec3(sin_p * azimuth.cos(), cos_p, sin_p * azimuth.sin());
+        (dir, pdf_to_solid_angle(pdf, sin_p))
+    }
+
+    /// Returns the PDF, with respect to solid angle, of `sample` choosing a direction
+    pub fn pdf(&self, dir: &glm::DVec3) -> f64 {
+        let dir = dir.normalize();
+        let u = (dir.z.atan2(dir.x) + std::f64::consts::PI) / std::f64::consts::TAU;
+        let polar = dir.y.acos();
+        let v = polar / std::f64::consts::PI;
+        pdf_to_solid_angle(self.distribution.pdf(u, v), polar.sin())
+    }
+
     fn bilinear_sample(&self, x: f64, y: f64) -> Color {
         let x0 = (x as u32).min(self.width - 1);
         let y0 = (y as u32).min(self.height - 1);
@@ -75,4 +126,174 @@ impl Environment {
             Self::Hdri(hdri) => hdri.get_color(dir),
         }
     }
+
+    /// Sample a direction for explicit light sampling, returning (dir, color, PDF)
+    ///
+    /// Only HDRI environments support importance sampling, so this returns `None` for
+    /// solid colors, which are only reached by escaping rays.
+    pub fn sample(&self, sampler: &mut dyn Sampler) -> Option<(glm::DVec3, Color, f64)> {
+        match self {
+            Self::Color(_) => None,
+            Self::Hdri(hdri) => {
+                let (dir, pdf) = hdri.sample(sampler);
+                Some((dir, hdri.get_color(&dir), pdf))
+            }
+        }
+    }
+
+    /// Returns the PDF, with respect to solid angle, of `sample` choosing a direction
+    pub fn pdf(&self, dir: &glm::DVec3) -> f64 {
+        match self {
+            Self::Color(_) => 0.0,
+            Self::Hdri(hdri) => hdri.pdf(dir),
+        }
+    }
+}
+
+/// Convert a PDF on the unit square of an equirectangular map to solid angle
+fn pdf_to_solid_angle(pdf: f64, sin_polar: f64) -> f64 {
+    if sin_polar > 0.0 {
+        pdf / (2.0 * std::f64::consts::PI * std::f64::consts::PI * sin_polar)
+    } else {
+        0.0
+    }
+}
+
+/// Piecewise-constant 1D distribution on [0, 1), sampled by inverting its CDF
+#[derive(Clone)]
+struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    fn new(mut func: Vec<f64>) -> Self {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f64;
+        }
+        let mut integral = cdf[n];
+        if integral > 0.0 {
+            for c in &mut cdf {
+                *c /= integral;
+            }
+        } else {
+            // Fall back to a uniform distribution if the function is zero everywhere
+            func = vec![1.0; n];
+            for (i, c) in cdf.iter_mut().enumerate() {
+                *c = i as f64 / n as f64;
+            }
+            integral = 1.0;
+        }
+        Self {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    /// Sample a value in [0, 1), returning (value, PDF, bucket index)
+    fn sample(&self, u: f64) -> (f64, f64, usize) {
+        let n = self.func.len();
+        // Find the last bucket whose CDF is at most u
+        let index = (self.cdf.partition_point(|&c| c <= u) - 1).min(n - 1);
+        let width = self.cdf[index + 1] - self.cdf[index];
+        let du = if width > 0.0 {
+            (u - self.cdf[index]) / width
+        } else {
+            0.0
+        };
+        let pdf = self.func[index] / self.integral;
+        ((index as f64 + du) / n as f64, pdf, index)
+    }
+
+    fn pdf(&self, index: usize) -> f64 {
+        self.func[index] / self.integral
+    }
+}
+
+/// Piecewise-constant 2D distribution on the unit square, sampled row by row
+#[derive(Clone)]
+struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    fn new(width: usize, height: usize, func: impl Fn(usize, usize) -> f64) -> Self {
+        let rows: Vec<Vec<f64>> = (0..height)
+            .map(|y| (0..width).map(|x| func(x, y)).collect())
+            .collect();
+        let marginal = Distribution1D::new(
+            rows.iter()
+                .map(|row| row.iter().sum::<f64>() / width as f64)
+                .collect(),
+        );
+        let conditional = rows.into_iter().map(Distribution1D::new).collect();
+        Self {
+            conditional,
+            marginal,
+        }
+    }
+
+    /// Sample a point in the unit square, returning ([u, v], PDF)
+    fn sample(&self, sampler: &mut dyn Sampler) -> ([f64; 2], f64) {
+        let [x, y] = sampler.next_2d();
+        let (v, pdf_v, row) = self.marginal.sample(x);
+        let (u, pdf_u, _) = self.conditional[row].sample(y);
+        ([u, v], pdf_u * pdf_v)
+    }
+
+    fn pdf(&self, u: f64, v: f64) -> f64 {
+        let height = self.conditional.len();
+        let width = self.conditional[0].func.len();
+        let x = ((u * width as f64) as usize).min(width - 1);
+        let y = ((v * height as f64) as usize).min(height - 1);
+        self.marginal.pdf(y) * self.conditional[y].pdf(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn hdri_sample_pdf_matches() {
+        let (width, height) = (16, 8);
+        let buf = (0..width * height)
+            .map(|i| glm::vec3(1.0, 1.0, 1.0) * ((i % 5) as f64 + 0.1))
+            .collect();
+        let hdri = Hdri::new(width, height, buf).working_space(Primaries::Ap1);
+        let mut sampler = IndependentSampler::from(StdRng::seed_from_u64(0));
+        for _ in 0..100 {
+            let (dir, pdf) = hdri.sample(&mut sampler);
+            assert!((dir.magnitude() - 1.0).abs() < 1e-9);
+            assert!((hdri.pdf(&dir) - pdf).abs() < 1e-6 * pdf);
+        }
+    }
+
+    #[test]
+    fn hdri_samples_working_space_luminance() {
+        // A green half and a blue half, which have equal luminance in ACEScg
+        let (width, height) = (16, 8);
+        let weights = Primaries::Ap1.luminance_weights();
+        let buf = (0..width * height)
+            .map(|i| {
+                if i % width < width / 2 {
+                    glm::vec3(0.0, 1.0 / weights.y, 0.0)
+                } else {
+                    glm::vec3(0.0, 0.0, 1.0 / weights.z)
+                }
+            })
+            .collect();
+        let hdri = Hdri::new(width, height, buf).working_space(Primaries::Ap1);
+        let green = glm::vec3(0.0, 0.0, -1.0);
+        let blue = glm::vec3(0.0, 0.0, 1.0);
+        assert!((hdri.pdf(&green) - hdri.pdf(&blue)).abs() < 1e-9 * hdri.pdf(&blue));
+    }
 }
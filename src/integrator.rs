@@ -0,0 +1,115 @@
+//! Light transport algorithms, which estimate the light arriving along camera rays
+
+use crate::color::Color;
+use crate::material::Material;
+use crate::renderer::Renderer;
+use crate::sampler::Sampler;
+use crate::scene::SceneTree;
+use crate::shape::Ray;
+pub use ambient_occlusion::AmbientOcclusion;
+pub use aov::Aov;
+pub use bidirectional::BidirectionalPathTracer;
+pub use direct::DirectLighting;
+pub use path_tracer::PathTracer;
+pub use photon::PhotonMapper;
+pub use spectral::SpectralPathTracer;
+
+mod ambient_occlusion;
+mod aov;
+mod bidirectional;
+mod direct;
+mod path_tracer;
+mod photon;
+mod spectral;
+
+const EPSILON: f64 = 1e-12;
+
+/// A light transport algorithm, which the renderer uses to find the color of samples
+///
+/// The renderer takes care of the camera, pixel sampling, filtering and parallelism,
+/// and shares its settings (such as `max_bounces`) with the integrator.
+pub trait Integrator: Send + Sync {
+    /// Prepare for a pass over the image, which traces one sample in every pixel
+    ///
+    /// This is called before each pass, with passes counted from zero, so integrators
+    /// can update any state shared between samples (such as a photon map).
+    fn preprocess(&self, _renderer: &Renderer<'_>, _tree: &SceneTree<'_>, _pass: u32) {}
+
+    /// Returns a Monte Carlo estimate of the light arriving at the camera along a ray
+    fn radiance(
+        &self,
+        renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        ray: Ray,
+        sampler: &mut dyn Sampler,
+    ) -> Color;
+
+    /// Returns whether the integrator outputs raw values rather than light, such as the
+    /// surface properties of debug render modes
+    ///
+    /// Raw values skip the exposure of a physical camera, firefly clamping, tone mapping,
+    /// color space conversion and the transfer function, so they are written to images
+    /// as they are (scaled only by the renderer's exposure value).
+    fn raw_output(&self) -> bool {
+        false
+    }
+}
+
+/// Power heuristic (with β = 2) for weighting a sample in multiple importance sampling
+///
+/// An infinite PDF denotes a delta distribution, which no other technique can sample.
+pub fn power_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    if pdf.is_infinite() {
+        return 1.0;
+    }
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b > 0.0 {
+        a / (a + b)
+    } else {
+        0.0
+    }
+}
+
+/// Estimate direct lighting at a point from light sampling and one BSDF sample,
+/// combined with multiple importance sampling
+///
+/// Unlike `Renderer::sample_lights` alone, this also finds emissive surfaces and
+/// environments that cannot be sampled explicitly.
+#[allow(clippy::too_many_arguments)]
+fn direct_lighting(
+    renderer: &Renderer<'_>,
+    tree: &SceneTree<'_>,
+    material: &Material,
+    pos: &glm::DVec3,
+    n: &glm::DVec3,
+    wo: &glm::DVec3,
+    time: f64,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let mut color = renderer.sample_lights(tree, material, pos, n, wo, time, true, sampler);
+    if let Some((wi, pdf)) = material.sample_f(n, wo, sampler) {
+        let ray = Ray {
+            origin: *pos,
+            dir: wi,
+            time,
+        };
+        let hit = tree.closest_hit(&ray, EPSILON);
+        let t_max = hit.as_ref().map_or(f64::INFINITY, |(h, _)| h.time);
+        let mut radiance = glm::vec3(0.0, 0.0, 0.0);
+        for light in &renderer.scene.lights {
+            if let Some((emitted, light_pdf)) = light.emission(pos, &wi, time, t_max) {
+                radiance += emitted * power_heuristic(pdf, light_pdf);
+            }
+        }
+        radiance += match hit {
+            None => {
+                let environment = &renderer.scene.environment;
+                environment.get_color(&wi) * power_heuristic(pdf, environment.pdf(&wi))
+            }
+            Some((_, object)) => object.material.emittance * object.material.color,
+        };
+        let f = material.bsdf(n, wo, &wi);
+        color += f.component_mul(&radiance) * wi.dot(n).abs() / pdf;
+    }
+    color
+}
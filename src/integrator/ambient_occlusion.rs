@@ -0,0 +1,102 @@
+use super::{Integrator, EPSILON};
+use crate::color::Color;
+use crate::material::cosine_sample;
+use crate::renderer::Renderer;
+use crate::sampler::Sampler;
+use crate::scene::SceneTree;
+use crate::shape::Ray;
+
+/// Ambient occlusion, which shades surfaces by how much of the hemisphere above them is
+/// unobstructed, for fast clay-style previews of geometry
+///
+/// Each sample casts one cosine-weighted ray from the point seen by the camera, which
+/// is occluded if it hits any object within the maximum distance. Materials and lights
+/// are ignored, and camera rays that miss every object are white.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AmbientOcclusion {
+    max_distance: f64,
+}
+
+impl AmbientOcclusion {
+    /// Construct an ambient occlusion integrator, where only objects closer than
+    /// `max_distance` occlude a surface
+    pub fn new(max_distance: f64) -> Self {
+        Self { max_distance }
+    }
+}
+
+impl Default for AmbientOcclusion {
+    fn default() -> Self {
+        Self::new(f64::INFINITY)
+    }
+}
+
+impl Integrator for AmbientOcclusion {
+    fn radiance(
+        &self,
+        _renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        ray: Ray,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let h = match tree.closest_hit(&ray, EPSILON) {
+            Some((h, _)) => h,
+            None => return glm::vec3(1.0, 1.0, 1.0),
+        };
+        // Occlusion is measured on the side of the surface facing the camera
+        let n = if h.normal.dot(&ray.dir) > 0.0 {
+            -h.normal
+        } else {
+            h.normal
+        };
+        let occlusion_ray = Ray {
+            origin: ray.at(h.time),
+            dir: cosine_sample(&n, sampler),
+            time: ray.time,
+        };
+        match tree.closest_hit(&occlusion_ray, EPSILON) {
+            Some((h, _)) if h.time < self.max_distance => glm::vec3(0.0, 0.0, 0.0),
+            _ => glm::vec3(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::object::Object;
+    use crate::scene::{Scene, SceneAdd};
+    use crate::shape::{plane, sphere, Transformable};
+
+    fn render(scene: &Scene, integrator: AmbientOcclusion) -> Vec<u8> {
+        let camera = Camera::look_at(
+            glm::vec3(0.0, 0.0, 5.0),
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            std::f64::consts::FRAC_PI_4,
+        );
+        Renderer::new(scene, camera)
+            .width(8)
+            .height(8)
+            .num_samples(4)
+            .integrator(integrator)
+            .render()
+            .into_raw()
+    }
+
+    #[test]
+    fn open_and_enclosed_surfaces() {
+        let mut open = Scene::new();
+        open.add(Object::new(plane(glm::vec3(0.0, 0.0, 1.0), -1.0)));
+        assert!(render(&open, AmbientOcclusion::default())
+            .iter()
+            .all(|&c| c == 255));
+
+        let mut enclosed = Scene::new();
+        enclosed.add(Object::new(sphere().scale(&glm::vec3(10.0, 10.0, 10.0))));
+        assert!(render(&enclosed, AmbientOcclusion::default())
+            .iter()
+            .all(|&c| c == 0));
+    }
+}
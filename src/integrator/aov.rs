@@ -0,0 +1,153 @@
+use super::{Integrator, EPSILON};
+use crate::color::Color;
+use crate::renderer::Renderer;
+use crate::sampler::Sampler;
+use crate::scene::SceneTree;
+use crate::shape::Ray;
+
+/// Debug render modes, which output a property of the surface seen by each camera ray
+/// (an arbitrary output variable) in place of its light
+///
+/// Values are written to the buffer and filtered like any other sample, so edges are
+/// antialiased, but they are output raw, without tone mapping or color conversion. Rays
+/// that miss every object are black in all modes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Aov {
+    /// Shading normal from the hit record, mapped from [-1, 1] to [0, 1] in each
+    /// channel, without flipping it to face the camera
+    Normal,
+
+    /// Distance from the camera to the hit, which can be scaled into a visible range
+    /// with the renderer's exposure value
+    Depth,
+
+    /// Albedo of the material (`Material::color`)
+    Albedo,
+
+    /// A distinct color for each object, based on its index in the scene
+    ObjectId,
+
+    /// Number of times a path traced from the camera bounces before it is terminated
+    /// by `max_bounces` or Russian roulette, or escapes the scene
+    Bounces,
+}
+
+impl Integrator for Aov {
+    fn radiance(
+        &self,
+        renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        ray: Ray,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let (h, index) = match tree.closest_hit_index(&ray, EPSILON) {
+            Some(hit) => hit,
+            None => return glm::vec3(0.0, 0.0, 0.0),
+        };
+        let object = &renderer.scene.objects[index];
+        match self {
+            Self::Normal => (h.normal + glm::vec3(1.0, 1.0, 1.0)) / 2.0,
+            Self::Depth => glm::vec3(1.0, 1.0, 1.0) * h.time * glm::length(&ray.dir),
+            Self::Albedo => object.material.color,
+            Self::ObjectId => id_color(index),
+            Self::Bounces => {
+                let bounces = count_bounces(renderer, tree, ray, sampler);
+                glm::vec3(1.0, 1.0, 1.0) * f64::from(bounces)
+            }
+        }
+    }
+
+    fn raw_output(&self) -> bool {
+        true
+    }
+}
+
+/// Returns a pseudorandom, fully saturated color for an object index
+fn id_color(index: usize) -> Color {
+    // Fibonacci hashing spreads out the hues of consecutive indices
+    let hue = (index as f64 * 0.618_033_988_749_895).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let [r, g, b] = match hue as u32 {
+        0 => [1.0, x, 0.0],
+        1 => [x, 1.0, 0.0],
+        2 => [0.0, 1.0, x],
+        3 => [0.0, x, 1.0],
+        4 => [x, 0.0, 1.0],
+        _ => [1.0, 0.0, x],
+    };
+    glm::vec3(r, g, b)
+}
+
+/// Trace a path through the scene by BSDF sampling, returning its number of bounces
+fn count_bounces(
+    renderer: &Renderer<'_>,
+    tree: &SceneTree<'_>,
+    mut ray: Ray,
+    sampler: &mut dyn Sampler,
+) -> u32 {
+    let mut throughput = glm::vec3(1.0, 1.0, 1.0);
+    let mut num_bounces = 0;
+    while let Some((h, object)) = tree.closest_hit(&ray, EPSILON) {
+        if renderer.russian_roulette.is_none() && num_bounces >= renderer.max_bounces {
+            break;
+        }
+        let material = object.material;
+        let wo = -glm::normalize(&ray.dir);
+        let (wi, pdf) = match material.sample_f(&h.normal, &wo, sampler) {
+            Some(sample) => sample,
+            None => break,
+        };
+        let f = material.bsdf(&h.normal, &wo, &wi);
+        throughput.component_mul_assign(&(f * wi.dot(&h.normal).abs() / pdf));
+        if !renderer.survive(num_bounces, &mut throughput, sampler) {
+            break;
+        }
+        ray = Ray {
+            origin: ray.at(h.time),
+            dir: wi,
+            time: ray.time,
+        };
+        num_bounces += 1;
+    }
+    num_bounces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::color::TransferFunction;
+    use crate::object::Object;
+    use crate::scene::{Scene, SceneAdd};
+    use crate::shape::{plane, sphere, Transformable};
+    use crate::tone_map::ToneMap;
+
+    #[test]
+    fn aovs_are_output_raw() {
+        let mut scene = Scene::new();
+        scene.add(Object::new(sphere().translate(&glm::vec3(0.0, 0.0, 100.0))));
+        scene.add(Object::new(plane(glm::vec3(0.0, 0.0, 1.0), -1.0)));
+        let camera = Camera::look_at(
+            glm::vec3(0.0, 0.0, 5.0),
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            std::f64::consts::FRAC_PI_4,
+        );
+        let render = |aov: Aov| {
+            Renderer::new(&scene, camera)
+                .width(8)
+                .height(8)
+                .tone_map(ToneMap::Aces)
+                .transfer_function(TransferFunction::Srgb)
+                .integrator(aov)
+                .render()
+        };
+        for pixel in render(Aov::Normal).pixels() {
+            assert_eq!(pixel.0, [127, 127, 255]);
+        }
+        let id = id_color(1).map(|c| (c * 255.0) as u8);
+        for pixel in render(Aov::ObjectId).pixels() {
+            assert_eq!(pixel.0, [id.x, id.y, id.z]);
+        }
+    }
+}
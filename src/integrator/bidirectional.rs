@@ -0,0 +1,603 @@
+use super::{power_heuristic, Integrator, EPSILON};
+use crate::color::Color;
+use crate::light::Light;
+use crate::material::{local_to_world, Material};
+use crate::object::Object;
+use crate::renderer::Renderer;
+use crate::sampler::{sample_disk, sample_index, sample_sphere, Sampler};
+use crate::scene::SceneTree;
+use crate::shape::{solid_angle_to_area, HitRecord, Ray};
+
+/// Bidirectional path tracing, which is unbiased and handles small light sources and
+/// indirect lighting much better than unidirectional path tracing
+///
+/// A subpath is traced from the camera and another from a random object or point
+/// light, and every pair of their vertices is connected, with the resulting paths
+/// weighted by multiple importance sampling. Lights that cannot start a subpath
+/// (ambient and directional lights, and the environment) are sampled from the camera
+/// subpath as in unidirectional path tracing.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct BidirectionalPathTracer;
+
+impl Integrator for BidirectionalPathTracer {
+    fn radiance(
+        &self,
+        renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        ray: Ray,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let bdpt = Bdpt::new(renderer, tree, ray.time);
+        let (camera, mut color) = bdpt.camera_subpath(ray, sampler);
+        let light = bdpt.light_subpath(sampler);
+        for t in 2..=camera.len() {
+            for s in 1..=light.len() {
+                if s + t - 2 > bdpt.max_depth {
+                    break;
+                }
+                let contribution = bdpt.connect(&camera, &light, s, t, sampler);
+                color += renderer.firefly_clamp.clamp_bounce(contribution);
+            }
+        }
+        color
+    }
+}
+
+/// Shared state for tracing and connecting the subpaths of a single sample
+struct Bdpt<'r, 'a> {
+    renderer: &'r Renderer<'a>,
+    tree: &'r SceneTree<'r>,
+    /// Lights which can start a light subpath, chosen uniformly at random
+    lights: Vec<&'a Light>,
+    /// Maximum number of segments in a path, minus one
+    max_depth: usize,
+    /// Moment that both subpaths are traced at
+    time: f64,
+}
+
+/// A vertex of a camera or light subpath
+#[derive(Copy, Clone)]
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    /// Position of the vertex
+    p: glm::DVec3,
+    /// Surface normal, or zero for point-like vertices (the camera and point lights)
+    n: glm::DVec3,
+    /// Throughput of the subpath up to this vertex
+    beta: Color,
+    /// PDF (with respect to area) of sampling this vertex from the previous one
+    pdf_fwd: f64,
+    /// PDF (with respect to area) of sampling this vertex from the next one, if the
+    /// path had been traced in the opposite direction
+    pdf_rev: f64,
+}
+
+#[derive(Copy, Clone)]
+enum VertexKind<'a> {
+    /// The origin of a camera ray
+    Camera,
+    /// A point on an object or point light
+    Light(&'a Light),
+    /// A point where light scatters off a surface
+    Surface(Material),
+}
+
+impl Vertex<'_> {
+    /// Convert a PDF with respect to solid angle at this vertex into a PDF with respect
+    /// to area at the next vertex
+    fn convert_density(&self, pdf: f64, next: &Vertex<'_>) -> f64 {
+        if next.n == glm::vec3(0.0, 0.0, 0.0) {
+            let dist2 = glm::distance2(&self.p, &next.p);
+            return if dist2 > 0.0 { pdf / dist2 } else { 0.0 };
+        }
+        solid_angle_to_area(pdf, &self.p, &next.p, &next.n)
+    }
+
+    /// PDF (with respect to area) of sampling `next` from this vertex, which was itself
+    /// reached from `prev`
+    fn pdf(&self, prev: Option<&Vertex<'_>>, next: &Vertex<'_>) -> f64 {
+        let wn = (next.p - self.p).normalize();
+        let pdf = match self.kind {
+            // Camera subpaths are never connected at their first vertex
+            VertexKind::Camera => 0.0,
+            VertexKind::Light(Light::Object(_)) => self.n.dot(&wn).max(0.0) / std::f64::consts::PI,
+            VertexKind::Light(_) => 0.25 / std::f64::consts::PI,
+            VertexKind::Surface(material) => {
+                let wp =
+                    (prev.expect("surface vertex without a predecessor").p - self.p).normalize();
+                material.pdf(&self.n, &wp, &wn)
+            }
+        };
+        self.convert_density(pdf, next)
+    }
+
+    /// Evaluate the BSDF for light scattered at this vertex from `from` towards `to`
+    fn f(&self, from: &glm::DVec3, to: &glm::DVec3) -> Color {
+        match self.kind {
+            VertexKind::Surface(material) => material.bsdf(
+                &self.n,
+                &(to - self.p).normalize(),
+                &(from - self.p).normalize(),
+            ),
+            _ => glm::vec3(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Absolute cosine between the normal and a unit direction, or one if the vertex
+    /// is point-like
+    fn cos(&self, dir: &glm::DVec3) -> f64 {
+        if self.n == glm::vec3(0.0, 0.0, 0.0) {
+            1.0
+        } else {
+            self.n.dot(dir).abs()
+        }
+    }
+
+    fn is_delta_light(&self) -> bool {
+        matches!(self.kind, VertexKind::Light(Light::Point(..)))
+    }
+}
+
+impl<'r, 'a> Bdpt<'r, 'a> {
+    fn new(renderer: &'r Renderer<'a>, tree: &'r SceneTree<'r>, time: f64) -> Self {
+        let lights = renderer
+            .scene
+            .lights
+            .iter()
+            .filter(|light| matches!(light, Light::Object(_) | Light::Point(..)))
+            .collect();
+        // Path tracing with `max_bounces` finds paths of up to `max_bounces + 2` segments
+        let max_depth = match renderer.russian_roulette {
+            Some(_) => usize::MAX,
+            None => renderer.max_bounces as usize + 1,
+        };
+        Self {
+            renderer,
+            tree,
+            lights,
+            max_depth,
+            time,
+        }
+    }
+
+    /// PDF (with respect to area) of starting a light subpath at the first point of an
+    /// object light hit by a ray
+    fn light_origin_pdf(&self, object: &Object, ray: &Ray) -> f64 {
+        object.shape.pdf_area(ray) / self.lights.len() as f64
+    }
+
+    /// Returns whether the segment between two points is not blocked by any object
+    fn visible(&self, a: &glm::DVec3, b: &glm::DVec3) -> bool {
+        let dist = glm::distance(a, b);
+        let ray = Ray {
+            origin: *a,
+            dir: (b - a) / dist,
+            time: self.time,
+        };
+        match self.tree.closest_hit(&ray, EPSILON) {
+            Some((h, _)) => h.time > dist * (1.0 - 1e-9),
+            None => true,
+        }
+    }
+
+    /// Trace a subpath from the camera, also returning the light that does not need a
+    /// connection to the light subpath
+    ///
+    /// This includes emission found by crossing object lights (the strategies with no
+    /// light subpath vertices), emissive surfaces, and the lights handled by path tracing.
+    fn camera_subpath(&self, mut ray: Ray, sampler: &mut dyn Sampler) -> (Vec<Vertex<'a>>, Color) {
+        let renderer = self.renderer;
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        let mut path = vec![Vertex {
+            kind: VertexKind::Camera,
+            p: ray.origin,
+            n: glm::vec3(0.0, 0.0, 0.0),
+            beta: glm::vec3(1.0, 1.0, 1.0),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }];
+        let mut beta = glm::vec3(1.0, 1.0, 1.0);
+        // PDF of the current ray's direction, if it was sampled from a BSDF
+        let mut bsdf_pdf = None;
+        loop {
+            let hit = self.tree.closest_hit(&ray, EPSILON);
+            if let Some(bsdf_pdf) = bsdf_pdf {
+                let t_max = hit.as_ref().map_or(f64::INFINITY, |(h, _)| h.time);
+                let emitted = self.connect_emission(&path, &ray, t_max, bsdf_pdf, &beta);
+                color += renderer.firefly_clamp.clamp_bounce(emitted);
+            }
+
+            let (h, object) = match hit {
+                None => {
+                    let environment = &renderer.scene.environment;
+                    let weight = bsdf_pdf.map_or(1.0, |bsdf_pdf| {
+                        power_heuristic(bsdf_pdf, environment.pdf(&ray.dir))
+                    });
+                    let radiance = environment.get_color(&ray.dir) * weight;
+                    color += renderer
+                        .firefly_clamp
+                        .clamp_bounce(beta.component_mul(&radiance));
+                    break;
+                }
+                Some(hit) => hit,
+            };
+            if path.len() > self.max_depth {
+                break;
+            }
+
+            let world_pos = ray.at(h.time);
+            let material = object.material;
+            let wo = -glm::normalize(&ray.dir);
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface(material),
+                p: world_pos,
+                n: h.normal,
+                beta,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+            };
+            if let Some(bsdf_pdf) = bsdf_pdf {
+                vertex.pdf_fwd = path[path.len() - 1].convert_density(bsdf_pdf, &vertex);
+            }
+
+            let mut radiance = material.emittance * material.color;
+            for light in &renderer.scene.lights {
+                if let Light::Ambient(_) | Light::Directional(..) = light {
+                    radiance += renderer.sample_light(
+                        self.tree, light, &material, &world_pos, &h.normal, &wo, ray.time, true,
+                        sampler,
+                    );
+                }
+            }
+            radiance += renderer.sample_environment(
+                self.tree, &material, &world_pos, &h.normal, &wo, ray.time, true, sampler,
+            );
+            color += renderer
+                .firefly_clamp
+                .clamp_bounce(beta.component_mul(&radiance));
+            path.push(vertex);
+
+            let (wi, pdf) = match material.sample_f(&h.normal, &wo, sampler) {
+                Some(sample) => sample,
+                None => break,
+            };
+            let f = material.bsdf(&h.normal, &wo, &wi);
+            beta.component_mul_assign(&(f * wi.dot(&h.normal).abs() / pdf));
+            let len = path.len();
+            let pdf_rev =
+                path[len - 1].convert_density(material.pdf(&h.normal, &wi, &wo), &path[len - 2]);
+            path[len - 2].pdf_rev = pdf_rev;
+
+            if !self.renderer.survive(len as u32 - 2, &mut beta, sampler) {
+                break;
+            }
+            ray = Ray {
+                origin: world_pos,
+                dir: wi,
+                time: ray.time,
+            };
+            bsdf_pdf = Some(pdf);
+        }
+        (path, color)
+    }
+
+    /// Trace a subpath from a random light, including the vertex on the light itself
+    fn light_subpath(&self, sampler: &mut dyn Sampler) -> Vec<Vertex<'a>> {
+        let mut path = Vec::new();
+        if self.lights.is_empty() {
+            return path;
+        }
+        let pick = 1.0 / self.lights.len() as f64;
+        let light = self.lights[sample_index(sampler.next_1d(), self.lights.len())];
+        let (origin, n, dir, pdf_pos, pdf_dir, emitted) = match light {
+            Light::Object(object) => {
+                let (p, n, pdf_pos) = object.shape.sample_area(self.time, sampler);
+                // Object lights emit diffusely, so the direction is cosine-weighted
+                let [x, y] = sample_disk(sampler.next_2d());
+                let z = (1.0_f64 - x * x - y * y).sqrt();
+                let dir = local_to_world(&n) * glm::vec3(x, y, z);
+                let emitted = object.material.color * object.material.emittance;
+                (p, n, dir, pdf_pos, z / std::f64::consts::PI, emitted)
+            }
+            Light::Point(color, location) => {
+                let [x, y, z] = sample_sphere(sampler.next_2d());
+                let dir = glm::vec3(x, y, z);
+                let n = glm::vec3(0.0, 0.0, 0.0);
+                (*location, n, dir, 1.0, 0.25 / std::f64::consts::PI, *color)
+            }
+            _ => unreachable!("light cannot start a subpath"),
+        };
+        if !(pdf_pos > 0.0 && pdf_dir > 0.0) {
+            return path;
+        }
+        let vertex = Vertex {
+            kind: VertexKind::Light(light),
+            p: origin,
+            n,
+            beta: emitted / (pdf_pos * pick),
+            pdf_fwd: pdf_pos * pick,
+            pdf_rev: 0.0,
+        };
+        let mut beta = emitted * vertex.cos(&dir) / (pdf_pos * pick * pdf_dir);
+        path.push(vertex);
+
+        let mut ray = Ray {
+            origin,
+            dir,
+            time: self.time,
+        };
+        let mut pdf_fwd = pdf_dir;
+        while path.len() < self.max_depth {
+            let (h, object) = match self.tree.closest_hit(&ray, EPSILON) {
+                Some(hit) => hit,
+                None => break,
+            };
+            let world_pos = ray.at(h.time);
+            let material = object.material;
+            let mut vertex = Vertex {
+                kind: VertexKind::Surface(material),
+                p: world_pos,
+                n: h.normal,
+                beta,
+                pdf_fwd: 0.0,
+                pdf_rev: 0.0,
+            };
+            vertex.pdf_fwd = path[path.len() - 1].convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+            if path.len() >= self.max_depth {
+                break;
+            }
+
+            let wo = -glm::normalize(&ray.dir);
+            let (wi, pdf) = match material.sample_f(&h.normal, &wo, sampler) {
+                Some(sample) => sample,
+                None => break,
+            };
+            // Light arrives from `wo` and leaves along `wi`, the reverse of camera subpaths
+            let f = material.bsdf(&h.normal, &wi, &wo);
+            beta.component_mul_assign(&(f * wi.dot(&h.normal).abs() / pdf));
+            let len = path.len();
+            let pdf_rev =
+                path[len - 1].convert_density(material.pdf(&h.normal, &wi, &wo), &path[len - 2]);
+            path[len - 2].pdf_rev = pdf_rev;
+
+            if !self.renderer.survive(len as u32 - 2, &mut beta, sampler) {
+                break;
+            }
+            ray = Ray {
+                origin: world_pos,
+                dir: wi,
+                time: ray.time,
+            };
+            pdf_fwd = pdf;
+        }
+        path
+    }
+
+    /// Emission from object lights crossed by a BSDF-sampled ray leaving the last vertex
+    /// of a camera subpath, which uses no vertices of the light subpath
+    fn connect_emission(
+        &self,
+        path: &[Vertex<'a>],
+        ray: &Ray,
+        t_max: f64,
+        bsdf_pdf: f64,
+        beta: &Color,
+    ) -> Color {
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        if path.len() - 1 > self.max_depth {
+            return color;
+        }
+        for &light in &self.lights {
+            if let Light::Object(object) = light {
+                let mut h = HitRecord::new();
+                h.time = t_max;
+                if !object.shape.intersect(ray, EPSILON, &mut h) || h.normal.dot(&ray.dir) >= 0.0 {
+                    continue;
+                }
+                let mut vertex = Vertex {
+                    kind: VertexKind::Light(light),
+                    p: ray.at(h.time),
+                    n: h.normal,
+                    beta: *beta,
+                    pdf_fwd: 0.0,
+                    pdf_rev: self.light_origin_pdf(object, ray),
+                };
+                vertex.pdf_fwd = path[path.len() - 1].convert_density(bsdf_pdf, &vertex);
+                let mut camera = path.to_vec();
+                camera.push(vertex);
+                let emitted = object.material.color * object.material.emittance;
+                color += beta.component_mul(&emitted) * mis_weight(&mut camera, &mut []);
+            }
+        }
+        color
+    }
+
+    /// Connect the first `t` vertices of the camera subpath with the first `s` vertices
+    /// of the light subpath, for `t >= 2` and `s >= 1`
+    ///
+    /// When `s == 1`, a new point is sampled on a random light instead, as seen from the
+    /// camera vertex (next event estimation).
+    fn connect(
+        &self,
+        camera: &[Vertex<'a>],
+        light: &[Vertex<'a>],
+        s: usize,
+        t: usize,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let zero = glm::vec3(0.0, 0.0, 0.0);
+        let pt = &camera[t - 1];
+        let pt_prev = &camera[t - 2].p;
+        let (qs, contribution) = if s == 1 {
+            let pick = 1.0 / self.lights.len() as f64;
+            let chosen = self.lights[sample_index(sampler.next_1d(), self.lights.len())];
+            let qs = match chosen {
+                Light::Object(object) => {
+                    let (v, n, pdf) = object.shape.sample(&pt.p, self.time, sampler);
+                    let disp = v - pt.p;
+                    if !(pdf > 0.0 && pdf.is_finite()) || disp.dot(&n) >= 0.0 {
+                        return zero;
+                    }
+                    let ray = Ray {
+                        origin: pt.p,
+                        dir: disp.normalize(),
+                        time: self.time,
+                    };
+                    let emitted = object.material.color * object.material.emittance;
+                    Vertex {
+                        kind: VertexKind::Light(chosen),
+                        p: v,
+                        n,
+                        beta: emitted / (pdf * pick),
+                        pdf_fwd: self.light_origin_pdf(object, &ray),
+                        pdf_rev: 0.0,
+                    }
+                }
+                Light::Point(color, location) => Vertex {
+                    kind: VertexKind::Light(chosen),
+                    p: *location,
+                    n: zero,
+                    beta: color / (glm::distance2(location, &pt.p) * pick),
+                    pdf_fwd: pick,
+                    pdf_rev: 0.0,
+                },
+                _ => unreachable!("light cannot start a subpath"),
+            };
+            let wi = (qs.p - pt.p).normalize();
+            if pt.cos(&wi) == 0.0 {
+                return zero;
+            }
+            let contribution = pt
+                .beta
+                .component_mul(&pt.f(&qs.p, pt_prev))
+                .component_mul(&qs.beta)
+                * pt.cos(&wi);
+            (qs, contribution)
+        } else {
+            let qs = light[s - 1];
+            let disp = qs.p - pt.p;
+            let dist2 = disp.magnitude_squared();
+            let wi = disp / dist2.sqrt();
+            let g = pt.cos(&wi) * qs.cos(&wi) / dist2;
+            if g == 0.0 {
+                // The BSDFs are not well-defined for grazing directions
+                return zero;
+            }
+            let contribution = qs
+                .beta
+                .component_mul(&qs.f(&light[s - 2].p, &pt.p))
+                .component_mul(&pt.f(&qs.p, pt_prev))
+                .component_mul(&pt.beta)
+                * g;
+            (qs, contribution)
+        };
+
+        if contribution == zero || !self.visible(&pt.p, &qs.p) {
+            return zero;
+        }
+        let mut camera = camera[..t].to_vec();
+        let mut light = if s == 1 {
+            vec![qs]
+        } else {
+            light[..s].to_vec()
+        };
+        contribution * mis_weight(&mut camera, &mut light)
+    }
+}
+
+/// Power heuristic weight of a path made by connecting a camera subpath to a light
+/// subpath, relative to all other ways of splitting it into two subpaths
+///
+/// The reverse PDFs of the vertices next to the connection are overwritten, since they
+/// depend on the vertices on the other side.
+fn mis_weight(camera: &mut [Vertex<'_>], light: &mut [Vertex<'_>]) -> f64 {
+    let (s, t) = (light.len(), camera.len());
+    if s == 0 {
+        let pt = camera[t - 1];
+        camera[t - 2].pdf_rev = pt.pdf(None, &camera[t - 2]);
+    } else {
+        let (pt, qs) = (camera[t - 1], light[s - 1]);
+        let pt_prev = camera[t - 2];
+        let qs_prev = if s >= 2 { Some(light[s - 2]) } else { None };
+        camera[t - 1].pdf_rev = qs.pdf(qs_prev.as_ref(), &pt);
+        camera[t - 2].pdf_rev = pt.pdf(Some(&qs), &pt_prev);
+        light[s - 1].pdf_rev = pt.pdf(Some(&pt_prev), &qs);
+        if let Some(qs_prev) = qs_prev {
+            light[s - 2].pdf_rev = qs.pdf(Some(&pt), &qs_prev);
+        }
+    }
+
+    // Zero PDFs come from point-like vertices, which are handled separately
+    let remap = |pdf: f64| if pdf == 0.0 { 1.0 } else { pdf };
+    let mut sum = 0.0;
+    let mut ratio = 1.0;
+    for vertex in camera[2..].iter().rev() {
+        ratio *= (remap(vertex.pdf_rev) / remap(vertex.pdf_fwd)).powi(2);
+        sum += ratio;
+    }
+    ratio = 1.0;
+    for (i, vertex) in light.iter().enumerate().rev() {
+        ratio *= (remap(vertex.pdf_rev) / remap(vertex.pdf_fwd)).powi(2);
+        // Camera subpaths cannot hit a point light by chance
+        if i > 0 || !vertex.is_delta_light() {
+            sum += ratio;
+        }
+    }
+    1.0 / (1.0 + sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::integrator::PathTracer;
+    use crate::scene::{Scene, SceneAdd};
+    use crate::shape::{plane, sphere, Transformable};
+
+    /// Returns the mean of the red channel of a render
+    fn mean(scene: &Scene, camera: Camera, integrator: impl Integrator + 'static) -> f64 {
+        let image = Renderer::new(scene, camera)
+            .width(16)
+            .height(16)
+            .num_samples(256)
+            .max_bounces(2)
+            .integrator(integrator)
+            .seed(1)
+            .render_buffer()
+            .hdr_image();
+        let sum: f64 = image.pixels().map(|p| f64::from(p[0])).sum();
+        sum / f64::from(image.width() * image.height())
+    }
+
+    #[test]
+    fn matches_path_tracer_mean() {
+        let mut scene = Scene::new();
+        scene.add(Object::new(sphere()).material(Material::diffuse(glm::vec3(0.5, 0.5, 0.5))));
+        scene.add(
+            Object::new(plane(glm::vec3(0.0, 1.0, 0.0), -1.0))
+                .material(Material::diffuse(glm::vec3(0.8, 0.8, 0.8))),
+        );
+        scene.add(Light::Object(
+            Object::new(
+                sphere()
+                    .scale(&glm::vec3(0.3, 0.3, 0.3))
+                    .translate(&glm::vec3(1.0, 2.0, 1.0)),
+            )
+            .material(Material::light(glm::vec3(1.0, 1.0, 1.0), 20.0)),
+        ));
+        scene.add(Light::Point(
+            glm::vec3(5.0, 5.0, 5.0),
+            glm::vec3(-2.0, 2.0, 2.0),
+        ));
+        let camera = Camera::look_at(
+            glm::vec3(0.0, 1.0, 5.0),
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            std::f64::consts::FRAC_PI_4,
+        );
+        let path_tracer = mean(&scene, camera, PathTracer);
+        let bdpt = mean(&scene, camera, BidirectionalPathTracer);
+        assert!((path_tracer - bdpt).abs() < 0.01 * path_tracer);
+    }
+}
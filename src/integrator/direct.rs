@@ -0,0 +1,91 @@
+use super::{direct_lighting, Integrator, EPSILON};
+use crate::color::Color;
+use crate::renderer::Renderer;
+use crate::sampler::Sampler;
+use crate::scene::SceneTree;
+use crate::shape::Ray;
+
+/// Direct lighting only, which renders light arriving at the first surface hit by the
+/// camera straight from a light source, without any indirect bounces
+///
+/// Light from object lights and the environment is found by both light and BSDF
+/// sampling, combined with multiple importance sampling.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DirectLighting;
+
+impl Integrator for DirectLighting {
+    fn radiance(
+        &self,
+        renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        ray: Ray,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        match tree.closest_hit(&ray, EPSILON) {
+            None => renderer.scene.environment.get_color(&ray.dir),
+            Some((h, object)) => {
+                let world_pos = ray.at(h.time);
+                let material = &object.material;
+                let wo = -glm::normalize(&ray.dir);
+                let direct = direct_lighting(
+                    renderer, tree, material, &world_pos, &h.normal, &wo, ray.time, sampler,
+                );
+                material.emittance * material.color + direct
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::integrator::PathTracer;
+    use crate::light::Light;
+    use crate::material::Material;
+    use crate::object::Object;
+    use crate::scene::{Scene, SceneAdd};
+    use crate::shape::{plane, sphere, Transformable};
+
+    /// Returns the mean of the red channel of a render with no indirect bounces
+    fn mean(scene: &Scene, camera: Camera, integrator: impl Integrator + 'static) -> f64 {
+        let image = Renderer::new(scene, camera)
+            .width(16)
+            .height(16)
+            .num_samples(256)
+            .max_bounces(0)
+            .integrator(integrator)
+            .seed(2)
+            .render_buffer()
+            .hdr_image();
+        let sum: f64 = image.pixels().map(|p| f64::from(p[0])).sum();
+        sum / f64::from(image.width() * image.height())
+    }
+
+    #[test]
+    fn matches_path_tracer_without_bounces() {
+        let mut scene = Scene::new();
+        scene.add(Object::new(sphere()).material(Material::diffuse(glm::vec3(0.5, 0.5, 0.5))));
+        scene.add(
+            Object::new(plane(glm::vec3(0.0, 1.0, 0.0), -1.0))
+                .material(Material::diffuse(glm::vec3(0.8, 0.8, 0.8))),
+        );
+        scene.add(Light::Object(
+            Object::new(
+                sphere()
+                    .scale(&glm::vec3(0.3, 0.3, 0.3))
+                    .translate(&glm::vec3(1.0, 2.0, 1.0)),
+            )
+            .material(Material::light(glm::vec3(1.0, 1.0, 1.0), 20.0)),
+        ));
+        let camera = Camera::look_at(
+            glm::vec3(0.0, 1.0, 5.0),
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            std::f64::consts::FRAC_PI_4,
+        );
+        let path_tracer = mean(&scene, camera, PathTracer);
+        let direct = mean(&scene, camera, DirectLighting);
+        assert!((path_tracer - direct).abs() < 0.01 * path_tracer);
+    }
+}
@@ -0,0 +1,121 @@
+use super::{power_heuristic, Integrator, EPSILON};
+use crate::color::Color;
+use crate::material::Material;
+use crate::renderer::Renderer;
+use crate::sampler::Sampler;
+use crate::scene::SceneTree;
+use crate::shape::Ray;
+
+/// Unidirectional path tracing with next event estimation
+///
+/// This follows a single path through the scene, carrying its throughput (the
+/// product of BSDF weights so far) and adding the light found at each vertex.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PathTracer;
+
+impl Integrator for PathTracer {
+    fn radiance(
+        &self,
+        renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        ray: Ray,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        trace_path(
+            renderer,
+            tree,
+            ray,
+            sampler,
+            &|color| *color,
+            &mut |material, _| *material,
+        )
+    }
+}
+
+/// Trace a path with next event estimation, returning the light that it carries back
+/// along the ray
+///
+/// The colors of lights and emissive materials are converted by `emitted`, which must
+/// be linear, and `material` returns the material used at each vertex, given the
+/// material of the object hit and the throughput of the path, which it can change. The
+/// path tracer uses colors as they are, while the spectral path tracer converts them to
+/// the wavelengths of the path.
+pub(super) fn trace_path(
+    renderer: &Renderer<'_>,
+    tree: &SceneTree<'_>,
+    mut ray: Ray,
+    sampler: &mut dyn Sampler,
+    emitted: &dyn Fn(&Color) -> Color,
+    material: &mut dyn FnMut(&Material, &mut Color) -> Material,
+) -> Color {
+    let mut color = glm::vec3(0.0, 0.0, 0.0);
+    let mut throughput = glm::vec3(1.0, 1.0, 1.0);
+    // PDF of the current ray's direction, if it was sampled from a BSDF
+    let mut bsdf_pdf = None;
+    let mut num_bounces = 0;
+    loop {
+        let hit = tree.closest_hit(&ray, EPSILON);
+        let mut radiance = glm::vec3(0.0, 0.0, 0.0);
+        if let Some(bsdf_pdf) = bsdf_pdf {
+            // Object lights are invisible, but BSDF samples can still find their emission
+            let t_max = hit.as_ref().map_or(f64::INFINITY, |(h, _)| h.time);
+            for light in &renderer.scene.lights {
+                if let Some((e, light_pdf)) = light.emission(&ray.origin, &ray.dir, ray.time, t_max)
+                {
+                    radiance += emitted(&e) * power_heuristic(bsdf_pdf, light_pdf);
+                }
+            }
+        }
+
+        let (h, object) = match hit {
+            None => {
+                let environment = &renderer.scene.environment;
+                let weight = bsdf_pdf.map_or(1.0, |bsdf_pdf| {
+                    power_heuristic(bsdf_pdf, environment.pdf(&ray.dir))
+                });
+                radiance += emitted(&environment.get_color(&ray.dir)) * weight;
+                color += renderer
+                    .firefly_clamp
+                    .clamp_bounce(throughput.component_mul(&radiance));
+                break;
+            }
+            Some(hit) => hit,
+        };
+
+        let world_pos = ray.at(h.time);
+        let material = material(&object.material, &mut throughput);
+        let wo = -glm::normalize(&ray.dir);
+
+        radiance += emitted(&object.material.color) * object.material.emittance;
+        let bounce = renderer.russian_roulette.is_some() || num_bounces < renderer.max_bounces;
+        radiance += renderer.sample_lights_with(
+            tree, &material, &world_pos, &h.normal, &wo, ray.time, bounce, sampler, emitted,
+        );
+        color += renderer
+            .firefly_clamp
+            .clamp_bounce(throughput.component_mul(&radiance));
+        if !bounce {
+            break;
+        }
+
+        let (wi, pdf) = match material.sample_f(&h.normal, &wo, sampler) {
+            Some(sample) => sample,
+            None => break,
+        };
+        let f = material.bsdf(&h.normal, &wo, &wi);
+        throughput.component_mul_assign(&(f * wi.dot(&h.normal).abs() / pdf));
+
+        if !renderer.survive(num_bounces, &mut throughput, sampler) {
+            break;
+        }
+
+        ray = Ray {
+            origin: world_pos,
+            dir: wi,
+            time: ray.time,
+        };
+        bsdf_pdf = Some(pdf);
+        num_bounces += 1;
+    }
+    color
+}
@@ -0,0 +1,547 @@
+use std::sync::RwLock;
+
+use rayon::prelude::*;
+
+use super::{direct_lighting, Integrator, EPSILON};
+use crate::color::Color;
+use crate::environment::Environment;
+use crate::light::Light;
+use crate::material::{local_to_world, Material};
+use crate::object::Object;
+use crate::renderer::{Renderer, PHOTON_STREAM};
+use crate::sampler::{sample_disk, sample_index, sample_sphere, IndependentSampler, Sampler};
+use crate::scene::SceneTree;
+use crate::shape::Ray;
+
+/// Radius reduction parameter of progressive photon mapping, between 0 and 1, which
+/// trades off variance (higher values) against bias (lower values)
+const ALPHA: f64 = 2.0 / 3.0;
+
+/// Materials smoother than this are treated as specular, so camera paths are traced
+/// through them instead of estimating the photon density on their surface
+const MAX_SPECULAR_ROUGHNESS: f64 = 0.1;
+
+/// Number of photons traced by each parallel task
+const PHOTONS_PER_TASK: usize = 4096;
+
+/// Progressive photon mapping, which is biased but consistent, and renders caustics
+/// much faster than path tracing
+///
+/// Each pass over the image traces a new photon map with `photons` photons, and the
+/// radius of its density estimation shrinks from `radius` over the passes.
+pub struct PhotonMapper {
+    photons: usize,
+    radius: f64,
+    map: RwLock<Option<PhotonMap>>,
+}
+
+impl PhotonMapper {
+    /// Construct a photon mapper with a number of photons per pass and an initial
+    /// gather radius
+    pub fn new(photons: usize, radius: f64) -> Self {
+        Self {
+            photons,
+            radius,
+            map: RwLock::new(None),
+        }
+    }
+}
+
+impl Integrator for PhotonMapper {
+    fn preprocess(&self, renderer: &Renderer<'_>, tree: &SceneTree<'_>, pass: u32) {
+        let radius = pass_radius(self.radius, pass);
+        let map = PhotonMap::new(renderer, tree, pass, self.photons, radius);
+        *self.map.write().unwrap() = Some(map);
+    }
+
+    fn radiance(
+        &self,
+        renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        ray: Ray,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let map = self.map.read().unwrap();
+        let map = map.as_ref().expect("Photon map was not traced");
+        trace_ray(renderer, tree, map, ray, sampler)
+    }
+}
+
+/// A photon that has landed on a rough surface
+#[derive(Copy, Clone)]
+struct Photon {
+    /// Position of the photon
+    p: glm::DVec3,
+    /// Unit direction that the photon arrived from
+    wi: glm::DVec3,
+    /// Power carried by the photon, before dividing by the number of photons emitted
+    power: Color,
+}
+
+/// Photons emitted from the lights in one pass of progressive photon mapping
+///
+/// Photons are stored in a balanced kd-tree over their positions, which is laid out
+/// implicitly in an array: the median of each subarray is the root of its subtree,
+/// splitting it along the axis recorded for that index.
+struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<u8>,
+    /// Number of photons emitted, including those that were never stored
+    num_emitted: usize,
+    /// Radius of the density estimation
+    radius: f64,
+}
+
+/// A source of photons
+#[derive(Copy, Clone)]
+enum Emitter<'a> {
+    /// An object, point or directional light
+    Light(&'a Light),
+    /// An emissive object in the scene, which is visible unlike an object light
+    Object(&'a Object),
+    /// The environment, shining onto the scene from every direction
+    Environment(&'a Environment),
+}
+
+/// Returns the radius of the density estimation in a given pass (counting from zero)
+///
+/// The radius shrinks slowly enough that both the bias and the variance of the
+/// average over all passes vanish, as in probabilistic progressive photon mapping.
+fn pass_radius(radius: f64, pass: u32) -> f64 {
+    let mut radius2 = radius * radius;
+    for i in 1..=pass {
+        radius2 *= (f64::from(i) + ALPHA) / f64::from(i + 1);
+    }
+    radius2.sqrt()
+}
+
+/// Trace a ray with photon mapping, obtaining a Monte Carlo estimate of the luminance
+///
+/// The path follows specular bounces until it reaches a rough surface. There, direct
+/// lighting is sampled like in path tracing, and indirect lighting (including caustics)
+/// is estimated from the density of nearby photons.
+fn trace_ray(
+    renderer: &Renderer<'_>,
+    tree: &SceneTree<'_>,
+    map: &PhotonMap,
+    mut ray: Ray,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let mut color = glm::vec3(0.0, 0.0, 0.0);
+    let mut throughput = glm::vec3(1.0, 1.0, 1.0);
+    let mut num_bounces = 0;
+    loop {
+        let hit = tree.closest_hit(&ray, EPSILON);
+        let mut radiance = glm::vec3(0.0, 0.0, 0.0);
+        if num_bounces > 0 {
+            // Object lights are only visible through specular bounces, which never sample them
+            let t_max = hit.as_ref().map_or(f64::INFINITY, |(h, _)| h.time);
+            for light in &renderer.scene.lights {
+                if let Some((emitted, _)) = light.emission(&ray.origin, &ray.dir, ray.time, t_max) {
+                    radiance += emitted;
+                }
+            }
+        }
+
+        let (h, object) = match hit {
+            None => {
+                radiance += renderer.scene.environment.get_color(&ray.dir);
+                color += renderer
+                    .firefly_clamp
+                    .clamp_bounce(throughput.component_mul(&radiance));
+                break;
+            }
+            Some(hit) => hit,
+        };
+
+        let world_pos = ray.at(h.time);
+        let material = object.material;
+        let wo = -glm::normalize(&ray.dir);
+        radiance += material.emittance * material.color;
+        let bounce = renderer.russian_roulette.is_some() || num_bounces < renderer.max_bounces;
+        if !is_specular(&material) || !bounce {
+            radiance += direct_lighting(
+                renderer, tree, &material, &world_pos, &h.normal, &wo, ray.time, sampler,
+            );
+            radiance += map.estimate(&material, &world_pos, &h.normal, &wo);
+            color += renderer
+                .firefly_clamp
+                .clamp_bounce(throughput.component_mul(&radiance));
+            break;
+        }
+        color += renderer
+            .firefly_clamp
+            .clamp_bounce(throughput.component_mul(&radiance));
+
+        let (wi, pdf) = match material.sample_f(&h.normal, &wo, sampler) {
+            Some(sample) => sample,
+            None => break,
+        };
+        let f = material.bsdf(&h.normal, &wo, &wi);
+        throughput.component_mul_assign(&(f * wi.dot(&h.normal).abs() / pdf));
+        if !renderer.survive(num_bounces, &mut throughput, sampler) {
+            break;
+        }
+        ray = Ray {
+            origin: world_pos,
+            dir: wi,
+            time: ray.time,
+        };
+        num_bounces += 1;
+    }
+    color
+}
+
+/// Returns whether camera paths should be traced through a material
+fn is_specular(material: &Material) -> bool {
+    material.transparent || material.roughness < MAX_SPECULAR_ROUGHNESS
+}
+
+impl PhotonMap {
+    /// Trace photons from the lights and emissive objects in the scene, and store them
+    /// in a photon map
+    ///
+    /// Photons are only stored after scattering at least once, since direct lighting
+    /// is sampled separately. Directional lights and the environment emit photons onto
+    /// a bounding sphere of the objects with finite extent. Emissive objects with
+    /// infinite extent cannot be sampled, so they do not emit photons.
+    pub(crate) fn new(
+        renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        pass: u32,
+        num_photons: usize,
+        radius: f64,
+    ) -> Self {
+        let mut emitters: Vec<_> = renderer
+            .scene
+            .lights
+            .iter()
+            .filter(|light| !matches!(light, Light::Ambient(_)))
+            .map(Emitter::Light)
+            .collect();
+        emitters.extend(
+            renderer
+                .scene
+                .objects
+                .iter()
+                .filter(|object| object.material.emittance > 0.0 && object.shape.bounds().is_some())
+                .map(Emitter::Object),
+        );
+        let environment = &renderer.scene.environment;
+        if !matches!(environment, Environment::Color(color) if *color == glm::vec3(0.0, 0.0, 0.0)) {
+            emitters.push(Emitter::Environment(environment));
+        }
+        let bounding_sphere = tree.bounds().map(|bounds| {
+            let center = (bounds.p_min + bounds.p_max) / 2.0;
+            (center, glm::distance(&center, &bounds.p_max))
+        });
+
+        let num_tasks = num_photons.div_ceil(PHOTONS_PER_TASK);
+        let mut photons: Vec<_> = (0..num_tasks)
+            .into_par_iter()
+            .flat_map(|task| {
+                let rng = renderer.rng(&[PHOTON_STREAM, pass.into(), task as u64]);
+                let mut sampler = IndependentSampler::from(rng);
+                let count = PHOTONS_PER_TASK.min(num_photons - task * PHOTONS_PER_TASK);
+                let mut photons = Vec::new();
+                for _ in 0..count {
+                    if emitters.is_empty() {
+                        break;
+                    }
+                    let emitter = emitters[sample_index(sampler.next_1d(), emitters.len())];
+                    let time = renderer.camera.sample_time(&mut sampler);
+                    if let Some((ray, power)) = emit(emitter, bounding_sphere, time, &mut sampler) {
+                        let power = power * emitters.len() as f64;
+                        trace_photon(renderer, tree, ray, power, &mut photons, &mut sampler);
+                    }
+                }
+                photons
+            })
+            .collect();
+
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        Self {
+            photons,
+            axes,
+            num_emitted: num_photons,
+            radius,
+        }
+    }
+
+    /// Estimate the light reflected towards `wo` from the density of nearby photons
+    fn estimate(
+        &self,
+        material: &Material,
+        pos: &glm::DVec3,
+        n: &glm::DVec3,
+        wo: &glm::DVec3,
+    ) -> Color {
+        let mut color = glm::vec3(0.0, 0.0, 0.0);
+        if self.num_emitted == 0 {
+            return color;
+        }
+        let radius2 = self.radius * self.radius;
+        query(&self.photons, &self.axes, pos, radius2, &mut |photon| {
+            color += material
+                .bsdf(n, wo, &photon.wi)
+                .component_mul(&photon.power);
+        });
+        color / (std::f64::consts::PI * radius2 * self.num_emitted as f64)
+    }
+}
+
+/// Sample a photon leaving an emitter at a given time, returning its ray and power
+fn emit(
+    emitter: Emitter<'_>,
+    bounding_sphere: Option<(glm::DVec3, f64)>,
+    time: f64,
+    sampler: &mut dyn Sampler,
+) -> Option<(Ray, Color)> {
+    // Light from far away, which arrives from `dir`, is emitted from a disk facing it
+    let distant = |dir: glm::DVec3, sampler: &mut dyn Sampler| {
+        let (center, radius) = bounding_sphere?;
+        let [x, y] = sample_disk(sampler.next_2d());
+        let disk = local_to_world(&dir) * glm::vec3(x, y, 0.0);
+        let ray = Ray {
+            origin: center + radius * (dir + disk),
+            dir: -dir,
+            time,
+        };
+        Some((ray, std::f64::consts::PI * radius * radius))
+    };
+
+    match emitter {
+        Emitter::Light(Light::Object(object)) | Emitter::Object(object) => {
+            let (p, n, pdf_pos) = object.shape.sample_area(time, sampler);
+            if pdf_pos <= 0.0 {
+                return None;
+            }
+            // Object lights emit diffusely, so the direction is cosine-weighted
+            let [x, y] = sample_disk(sampler.next_2d());
+            let z = (1.0_f64 - x * x - y * y).sqrt();
+            let ray = Ray {
+                origin: p,
+                dir: local_to_world(&n) * glm::vec3(x, y, z),
+                time,
+            };
+            let emitted = object.material.color * object.material.emittance;
+            Some((ray, emitted * std::f64::consts::PI / pdf_pos))
+        }
+        Emitter::Light(Light::Point(color, location)) => {
+            let [x, y, z] = sample_sphere(sampler.next_2d());
+            let ray = Ray {
+                origin: *location,
+                dir: glm::vec3(x, y, z),
+                time,
+            };
+            Some((ray, color * 4.0 * std::f64::consts::PI))
+        }
+        Emitter::Light(Light::Directional(color, direction)) => {
+            let (ray, area) = distant(-glm::normalize(direction), sampler)?;
+            Some((ray, color * area))
+        }
+        Emitter::Light(Light::Ambient(_)) => None,
+        Emitter::Environment(environment) => {
+            let (dir, color, pdf) = environment.sample(sampler).unwrap_or_else(|| {
+                let [x, y, z] = sample_sphere(sampler.next_2d());
+                let dir = glm::vec3(x, y, z);
+                (
+                    dir,
+                    environment.get_color(&dir),
+                    0.25 / std::f64::consts::PI,
+                )
+            });
+            if pdf <= 0.0 {
+                return None;
+            }
+            let (ray, area) = distant(dir, sampler)?;
+            Some((ray, color * area / pdf))
+        }
+    }
+}
+
+/// Trace a photon through the scene, storing it at every rough surface it lands on
+/// after scattering at least once
+fn trace_photon(
+    renderer: &Renderer<'_>,
+    tree: &SceneTree<'_>,
+    mut ray: Ray,
+    power: Color,
+    photons: &mut Vec<Photon>,
+    sampler: &mut dyn Sampler,
+) {
+    let mut throughput = glm::vec3(1.0, 1.0, 1.0);
+    let mut num_bounces = 0;
+    while let Some((h, object)) = tree.closest_hit(&ray, EPSILON) {
+        let world_pos = ray.at(h.time);
+        let material = object.material;
+        let wo = -glm::normalize(&ray.dir);
+        if num_bounces > 0 && !is_specular(&material) {
+            photons.push(Photon {
+                p: world_pos,
+                wi: wo,
+                power: power.component_mul(&throughput),
+            });
+        }
+        if renderer.russian_roulette.is_none() && num_bounces >= renderer.max_bounces {
+            break;
+        }
+
+        let (wi, pdf) = match material.sample_f(&h.normal, &wo, sampler) {
+            Some(sample) => sample,
+            None => break,
+        };
+        // Light arrives from `wo` and leaves along `wi`, the reverse of camera paths
+        let f = material.bsdf(&h.normal, &wi, &wo);
+        throughput.component_mul_assign(&(f * wi.dot(&h.normal).abs() / pdf));
+        if !renderer.survive(num_bounces, &mut throughput, sampler) {
+            break;
+        }
+        ray = Ray {
+            origin: world_pos,
+            dir: wi,
+            time: ray.time,
+        };
+        num_bounces += 1;
+    }
+}
+
+/// Arrange photons into an implicit kd-tree, splitting each subarray at its median
+/// along the axis where the photons are most spread out
+fn build(photons: &mut [Photon], axes: &mut [u8]) {
+    if photons.is_empty() {
+        return;
+    }
+    let (p_min, p_max) = photons
+        .iter()
+        .fold((photons[0].p, photons[0].p), |(p_min, p_max), photon| {
+            (glm::min2(&p_min, &photon.p), glm::max2(&p_max, &photon.p))
+        });
+    let axis = (p_max - p_min).imax();
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| a.p[axis].total_cmp(&b.p[axis]));
+    axes[mid] = axis as u8;
+
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+/// Call a function on every photon within a squared distance of a point
+fn query<F>(photons: &[Photon], axes: &[u8], pos: &glm::DVec3, radius2: f64, f: &mut F)
+where
+    F: FnMut(&Photon),
+{
+    if photons.is_empty() {
+        return;
+    }
+    let mid = photons.len() / 2;
+    let photon = &photons[mid];
+    let axis = axes[mid] as usize;
+    let diff = pos[axis] - photon.p[axis];
+    let (left, right) = (..mid, mid + 1..);
+    if diff < 0.0 {
+        query(&photons[left], &axes[left], pos, radius2, f);
+    } else {
+        query(
+            &photons[right.clone()],
+            &axes[right.clone()],
+            pos,
+            radius2,
+            f,
+        );
+    }
+    if glm::distance2(pos, &photon.p) <= radius2 {
+        f(photon);
+    }
+    if diff * diff <= radius2 {
+        if diff < 0.0 {
+            query(&photons[right.clone()], &axes[right], pos, radius2, f);
+        } else {
+            query(&photons[left], &axes[left], pos, radius2, f);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::camera::Camera;
+    use crate::integrator::PathTracer;
+    use crate::scene::{Scene, SceneAdd};
+    use crate::shape::{plane, sphere, Transformable};
+
+    /// Returns the mean of the red channel of a render
+    fn mean(scene: &Scene, camera: Camera, integrator: impl Integrator + 'static) -> f64 {
+        let image = Renderer::new(scene, camera)
+            .width(16)
+            .height(16)
+            .num_samples(256)
+            .max_bounces(5)
+            .integrator(integrator)
+            .seed(3)
+            .render_buffer()
+            .hdr_image();
+        let sum: f64 = image.pixels().map(|p| f64::from(p[0])).sum();
+        sum / f64::from(image.width() * image.height())
+    }
+
+    #[test]
+    fn query_matches_linear_search() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut photons: Vec<_> = (0..1000)
+            .map(|_| Photon {
+                p: glm::vec3(rng.gen(), rng.gen(), rng.gen::<f64>() * 0.1),
+                wi: glm::vec3(0.0, 0.0, 1.0),
+                power: glm::vec3(1.0, 1.0, 1.0),
+            })
+            .collect();
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        for _ in 0..100 {
+            let pos = glm::vec3(rng.gen(), rng.gen(), rng.gen::<f64>() * 0.1);
+            let mut count = 0;
+            query(&photons, &axes, &pos, 0.01, &mut |_| count += 1);
+            let expected = photons
+                .iter()
+                .filter(|photon| glm::distance2(&pos, &photon.p) <= 0.01)
+                .count();
+            assert_eq!(count, expected);
+        }
+    }
+
+    #[test]
+    fn matches_path_tracer_mean_with_emissive_objects() {
+        let mut scene = Scene::new();
+        scene.add(Object::new(sphere()).material(Material::diffuse(glm::vec3(0.5, 0.5, 0.5))));
+        // A floor and a ceiling, so that much of the light arrives indirectly
+        for (normal, value) in [(1.0, -1.0), (-1.0, -3.0)] {
+            scene.add(
+                Object::new(plane(glm::vec3(0.0, normal, 0.0), value))
+                    .material(Material::diffuse(glm::vec3(0.8, 0.8, 0.8))),
+            );
+        }
+        // Visible emissive geometry, which is not one of the scene's lights
+        scene.add(
+            Object::new(
+                sphere()
+                    .scale(&glm::vec3(0.5, 0.5, 0.5))
+                    .translate(&glm::vec3(1.0, 2.0, 1.0)),
+            )
+            .material(Material::light(glm::vec3(1.0, 1.0, 1.0), 10.0)),
+        );
+        let camera = Camera::look_at(
+            glm::vec3(0.0, 1.0, 5.0),
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            std::f64::consts::FRAC_PI_4,
+        );
+        let path_tracer = mean(&scene, camera, PathTracer);
+        let photon_mapper = mean(&scene, camera, PhotonMapper::new(2000, 0.1));
+        assert!((path_tracer - photon_mapper).abs() < 0.03 * path_tracer);
+    }
+}
@@ -0,0 +1,99 @@
+use super::path_tracer::trace_path;
+use super::Integrator;
+use crate::color::Color;
+use crate::material::Material;
+use crate::renderer::Renderer;
+use crate::sampler::Sampler;
+use crate::scene::SceneTree;
+use crate::shape::Ray;
+use crate::spectrum::{Wavelengths, NUM_WAVELENGTHS};
+
+/// Spectral path tracing with next event estimation and hero wavelength sampling
+///
+/// This works like `PathTracer`, but each path carries radiance at a few wavelengths
+/// instead of RGB colors. Colors of materials and lights are upsampled to spectra in
+/// the renderer's working space, and transparent materials with a `dispersion` refract
+/// each wavelength differently, which splits white light into rainbows.
+///
+/// When a path meets a dispersive material, its direction only suits the hero
+/// wavelength, so the other wavelengths are dropped from the rest of the path.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SpectralPathTracer;
+
+impl Integrator for SpectralPathTracer {
+    fn radiance(
+        &self,
+        renderer: &Renderer<'_>,
+        tree: &SceneTree<'_>,
+        ray: Ray,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let primaries = renderer.working_space;
+        let wavelengths = Wavelengths::sample(sampler.next_1d());
+        let emitted = |color: &Color| wavelengths.illuminant(color, primaries);
+        let mut dispersed = false;
+        let mut material = |material: &Material, throughput: &mut Color| {
+            if material.dispersion.is_some() && !dispersed {
+                // Keep only the hero wavelength, weighted up to remain unbiased
+                *throughput = glm::vec3(throughput.x * NUM_WAVELENGTHS as f64, 0.0, 0.0);
+                dispersed = true;
+            }
+            wavelengths.material(material, primaries)
+        };
+        let radiance = trace_path(renderer, tree, ray, sampler, &emitted, &mut material);
+        wavelengths.integrate(&radiance, primaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::environment::Environment;
+    use crate::integrator::PathTracer;
+    use crate::light::Light;
+    use crate::object::Object;
+    use crate::scene::{Scene, SceneAdd};
+    use crate::shape::{plane, sphere};
+
+    /// Returns the mean color of a render
+    fn mean(scene: &Scene, integrator: impl Integrator + 'static) -> Color {
+        let camera = Camera::look_at(
+            glm::vec3(0.0, 1.0, 5.0),
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            std::f64::consts::FRAC_PI_4,
+        );
+        let image = Renderer::new(scene, camera)
+            .width(16)
+            .height(16)
+            .num_samples(256)
+            .max_bounces(2)
+            .integrator(integrator)
+            .seed(4)
+            .render_buffer()
+            .hdr_image();
+        let sum = image.pixels().fold(glm::vec3(0.0, 0.0, 0.0), |sum, p| {
+            sum + glm::vec3(f64::from(p[0]), f64::from(p[1]), f64::from(p[2]))
+        });
+        sum / f64::from(image.width() * image.height())
+    }
+
+    #[test]
+    fn white_scene_matches_rgb() {
+        let mut scene = Scene::new();
+        scene.add(Object::new(sphere()).material(Material::diffuse(glm::vec3(0.8, 0.8, 0.8))));
+        scene.add(
+            Object::new(plane(glm::vec3(0.0, 1.0, 0.0), -1.0))
+                .material(Material::diffuse(glm::vec3(0.5, 0.5, 0.5))),
+        );
+        scene.add(Light::Point(
+            glm::vec3(10.0, 10.0, 10.0),
+            glm::vec3(0.0, 3.0, 2.0),
+        ));
+        scene.environment = Environment::Color(glm::vec3(0.2, 0.2, 0.2));
+        let rgb = mean(&scene, PathTracer);
+        let spectral = mean(&scene, SpectralPathTracer);
+        assert!((rgb - spectral).abs().max() < 0.02 * rgb.max());
+    }
+}
@@ -3,6 +3,9 @@ use std::error::Error;
 use std::fs::File;
 use std::io::{self, prelude::*, BufReader, SeekFrom};
 
+use image::codecs::hdr::HdrEncoder;
+
+use crate::buffer::Rgb32FImage;
 use crate::material::Material;
 use crate::object::Object;
 use crate::shape::{Mesh, Triangle};
@@ -118,20 +121,18 @@ pub fn load_obj_with_mtl(obj_file: File, mtl_file: File) -> io::Result<Vec<Objec
                 let face = parse_obj_face(&tokens, &vertices, &normals)?;
                 current_triangles.extend(face);
             }
-            "usemtl" => {
+            "usemtl" if last_usemtl.is_none() || last_usemtl.as_ref().unwrap() != tokens[1] => {
                 // material
-                if last_usemtl.is_none() || last_usemtl.as_ref().unwrap() != tokens[1] {
-                    if !current_triangles.is_empty() {
-                        objects.push(
-                            Object::new(Mesh::new(current_triangles.drain(..).collect()))
-                                .material(current_material),
-                        );
-                    }
-                    current_material = *materials.get(tokens[1]).ok_or_else(|| {
-                        invalid_data(format!("Could not found `usemtl {}` in library", tokens[1]))
-                    })?;
-                    last_usemtl = Some(tokens[1].to_owned());
+                if !current_triangles.is_empty() {
+                    objects.push(
+                        Object::new(Mesh::new(std::mem::take(&mut current_triangles)))
+                            .material(current_material),
+                    );
                 }
+                current_material = *materials.get(tokens[1]).ok_or_else(|| {
+                    invalid_data(format!("Could not found `usemtl {}` in library", tokens[1]))
+                })?;
+                last_usemtl = Some(tokens[1].to_owned());
             }
             // Ignore other unrecognized or non-standard commands
             _ => (),
@@ -140,7 +141,7 @@ pub fn load_obj_with_mtl(obj_file: File, mtl_file: File) -> io::Result<Vec<Objec
 
     if !current_triangles.is_empty() {
         objects.push(
-            Object::new(Mesh::new(current_triangles.drain(..).collect()))
+            Object::new(Mesh::new(std::mem::take(&mut current_triangles)))
                 .material(current_material),
         );
     }
@@ -358,3 +359,185 @@ fn load_stl_binary(file: File, num_triangles: u64) -> io::Result<Mesh> {
     }
     Ok(Mesh::new(triangles))
 }
+
+/// A named channel of floating-point image data, such as an arbitrary output variable,
+/// with one value per pixel in row-major order
+#[derive(Clone, Debug, PartialEq)]
+pub struct Channel {
+    /// Name of the channel, such as `Z` or `albedo.R`
+    pub name: String,
+
+    /// Values of the pixels in the channel
+    pub values: Vec<f32>,
+}
+
+/// Write an image in the Radiance .HDR format (RGBE)
+pub fn write_hdr(writer: impl Write, image: &Rgb32FImage) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let pixels: Vec<_> = image.pixels().copied().collect();
+    HdrEncoder::new(writer)
+        .encode(&pixels, width as usize, height as usize)
+        .map_err(io::Error::other)
+}
+
+/// Write an image in the Portable Float Map (.PFM) format
+///
+/// See http://www.pauldebevec.com/Research/HDR/PFM/ for details.
+pub fn write_pfm(mut writer: impl Write, image: &Rgb32FImage) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    // A negative scale means that the data is little-endian
+    write!(writer, "PF\n{} {}\n-1.0\n", width, height)?;
+    // Rows are stored from bottom to top
+    for y in (0..height).rev() {
+        for x in 0..width {
+            for c in image.get_pixel(x, y).0.iter() {
+                writer.write_all(&c.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write an image in the OpenEXR format, with uncompressed 32-bit float channels, and
+/// optionally extra channels such as feature buffers
+///
+/// See https://www.openexr.com/documentation/openexrfilelayout.pdf for details.
+pub fn write_exr(mut writer: impl Write, image: &Rgb32FImage, extra: &[Channel]) -> io::Result<()> {
+    let (width, height) = image.dimensions();
+    let color: Vec<_> = ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(c, name)| Channel {
+            name: name.to_string(),
+            values: image.pixels().map(|pixel| pixel[c]).collect(),
+        })
+        .collect();
+    let mut channels: Vec<_> = color.iter().chain(extra).collect();
+    // Channels are stored in alphabetical order
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    for (i, channel) in channels.iter().enumerate() {
+        if channel.values.len() != (width * height) as usize {
+            return Err(invalid_data(format!(
+                "Channel {} has the wrong number of values",
+                channel.name
+            )));
+        }
+        if i > 0 && channel.name == channels[i - 1].name {
+            return Err(invalid_data(format!("Duplicate channel {}", channel.name)));
+        }
+    }
+
+    let mut header = Vec::new();
+    let mut attribute = |name: &str, kind: &str, value: &[u8]| {
+        header.extend_from_slice(name.as_bytes());
+        header.push(0);
+        header.extend_from_slice(kind.as_bytes());
+        header.push(0);
+        header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+        header.extend_from_slice(value);
+    };
+    let mut chlist = Vec::new();
+    for channel in &channels {
+        chlist.extend_from_slice(channel.name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&2_i32.to_le_bytes()); // FLOAT
+        chlist.extend_from_slice(&[0; 4]); // pLinear and reserved
+        chlist.extend_from_slice(&1_i32.to_le_bytes()); // xSampling
+        chlist.extend_from_slice(&1_i32.to_le_bytes()); // ySampling
+    }
+    chlist.push(0);
+    attribute("channels", "chlist", &chlist);
+    attribute("compression", "compression", &[0]); // NO_COMPRESSION
+    let window: Vec<_> = [0, 0, width as i32 - 1, height as i32 - 1]
+        .iter()
+        .flat_map(|v| v.to_le_bytes())
+        .collect();
+    attribute("dataWindow", "box2i", &window);
+    attribute("displayWindow", "box2i", &window);
+    attribute("lineOrder", "lineOrder", &[0]); // INCREASING_Y
+    attribute("pixelAspectRatio", "float", &1.0_f32.to_le_bytes());
+    attribute("screenWindowCenter", "v2f", &[0; 8]);
+    attribute("screenWindowWidth", "float", &1.0_f32.to_le_bytes());
+    header.push(0);
+
+    // Names longer than 31 bytes need the long names flag in the version field
+    let long_names = channels.iter().any(|channel| channel.name.len() > 31);
+    let version: u32 = if long_names { 2 | 0x400 } else { 2 };
+    writer.write_all(&[0x76, 0x2f, 0x31, 0x01])?;
+    writer.write_all(&version.to_le_bytes())?;
+    writer.write_all(&header)?;
+
+    // Offset table, followed by one uncompressed block per scanline
+    let block_size = 8 + 4 * width as u64 * channels.len() as u64;
+    let start = 8 + header.len() as u64 + 8 * u64::from(height);
+    for y in 0..u64::from(height) {
+        writer.write_all(&(start + y * block_size).to_le_bytes())?;
+    }
+    for y in 0..height {
+        writer.write_all(&(y as i32).to_le_bytes())?;
+        writer.write_all(&((block_size - 8) as i32).to_le_bytes())?;
+        for channel in &channels {
+            let row = (y * width) as usize..((y + 1) * width) as usize;
+            for value in &channel.values[row] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::hdr::HdrDecoder;
+
+    use super::*;
+
+    fn test_image() -> Rgb32FImage {
+        Rgb32FImage::from_fn(9, 4, |x, y| {
+            image::Rgb([x as f32 * 0.5, y as f32 * 2.0, 100.0 + (x * y) as f32])
+        })
+    }
+
+    #[test]
+    fn hdr_writers_work() {
+        let image = test_image();
+
+        let mut hdr = Vec::new();
+        write_hdr(&mut hdr, &image).unwrap();
+        let decoded = HdrDecoder::new(hdr.as_slice())
+            .unwrap()
+            .read_image_hdr()
+            .unwrap();
+        for (a, b) in decoded.iter().zip(image.pixels()) {
+            for c in 0..3 {
+                assert!((a[c] - b[c]).abs() <= 0.01 * b[c].max(1.0));
+            }
+        }
+
+        let mut pfm = Vec::new();
+        write_pfm(&mut pfm, &image).unwrap();
+        let header = b"PF\n9 4\n-1.0\n";
+        assert!(pfm.starts_with(header));
+        let last_row = &pfm[header.len()..header.len() + 4];
+        assert_eq!(last_row, &image.get_pixel(0, 3)[0].to_le_bytes());
+
+        let mut exr = Vec::new();
+        let depth = Channel {
+            name: "Z".to_string(),
+            values: vec![1.5; 36],
+        };
+        write_exr(&mut exr, &image, &[depth]).unwrap();
+        assert_eq!(&exr[..4], &[0x76, 0x2f, 0x31, 0x01]);
+        // The last block of the file is the last scanline, with its channels sorted
+        let block_size = 8 + 4 * 9 * 4;
+        let block = &exr[exr.len() - block_size..];
+        assert_eq!(&block[..4], &3_i32.to_le_bytes());
+        let value = |channel: usize, x: usize| {
+            let i = 8 + 4 * (9 * channel + x);
+            f32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]])
+        };
+        assert_eq!(value(0, 2), image.get_pixel(2, 3)[2]); // B
+        assert_eq!(value(2, 2), image.get_pixel(2, 3)[0]); // R
+        assert_eq!(value(3, 2), 1.5); // Z
+    }
+}
@@ -1,6 +1,6 @@
-use rand::{distributions::Uniform, rngs::StdRng, Rng};
 use std::sync::Arc;
 
+use crate::sampler::{sample_index, Sampler};
 use crate::shape::{HitRecord, Ray, Shape};
 
 const SCORE_THRESHOLD: f64 = 0.85;
@@ -67,6 +67,30 @@ impl BoundingBox {
         )
     }
 
+    /// Returns the bounding box of this box after applying a homogeneous transformation
+    pub fn transform(&self, transform: &glm::DMat4) -> BoundingBox {
+        // This is not necessarily the best bounding box, but it is correct
+        let BoundingBox { p_min, p_max } = self;
+        let v1 = (transform * glm::vec4(p_min.x, p_min.y, p_min.z, 1.0)).xyz();
+        let v2 = (transform * glm::vec4(p_min.x, p_min.y, p_max.z, 1.0)).xyz();
+        let v3 = (transform * glm::vec4(p_min.x, p_max.y, p_min.z, 1.0)).xyz();
+        let v4 = (transform * glm::vec4(p_min.x, p_max.y, p_max.z, 1.0)).xyz();
+        let v5 = (transform * glm::vec4(p_max.x, p_min.y, p_min.z, 1.0)).xyz();
+        let v6 = (transform * glm::vec4(p_max.x, p_min.y, p_max.z, 1.0)).xyz();
+        let v7 = (transform * glm::vec4(p_max.x, p_max.y, p_min.z, 1.0)).xyz();
+        let v8 = (transform * glm::vec4(p_max.x, p_max.y, p_max.z, 1.0)).xyz();
+        BoundingBox {
+            p_min: glm::min2(
+                &glm::min4(&v1, &v2, &v3, &v4),
+                &glm::min4(&v5, &v6, &v7, &v8),
+            ),
+            p_max: glm::max2(
+                &glm::max4(&v1, &v2, &v3, &v4),
+                &glm::max4(&v5, &v6, &v7, &v8),
+            ),
+        }
+    }
+
     /// Splits the bounding box with respect to a plane
     pub fn split(&self, axis: usize, value: f64) -> (BoundingBox, BoundingBox) {
         let mut p_mid_max = self.p_max;
@@ -127,26 +151,71 @@ impl<T: Bounded> Bounded for KdTree<T> {
 
 impl<T: Bounded> Shape for KdTree<T> {
     fn intersect(&self, ray: &Ray, t_min: f64, record: &mut HitRecord) -> bool {
-        let (b_min, b_max) = self.bounds.intersect(ray);
-        if f64::max(b_min, t_min) > f64::min(b_max, record.time) {
-            // No potential for intersecting, even the broader bounding box
-            return false;
+        self.intersect_object(ray, t_min, record).is_some()
+    }
+
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        let num = self.objects.len();
+        let index = sample_index(sampler.next_1d(), num);
+        let (v, n, p) = self.objects[index].sample(target, time, sampler);
+        (v, n, p / (num as f64))
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        let ray = Ray {
+            origin: *target,
+            dir: *dir,
+            time,
+        };
+        let mut h = HitRecord::new();
+        match self.intersect_object(&ray, 0.0, &mut h) {
+            Some(object) => object.pdf(target, dir, time) / (self.objects.len() as f64),
+            None => 0.0,
         }
-        self.intersect_subtree(&self.root, &self.bounds, ray, t_min, record)
     }
 
-    fn sample(&self, target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
+    fn sample_area(&self, time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
         let num = self.objects.len();
-        let index = rng.sample(Uniform::from(0..num));
-        let (v, n, p) = self.objects[index].sample(target, rng);
+        let index = sample_index(sampler.next_1d(), num);
+        let (v, n, p) = self.objects[index].sample_area(time, sampler);
         (v, n, p / (num as f64))
     }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        let mut h = HitRecord::new();
+        match self.intersect_object(ray, 0.0, &mut h) {
+            Some(object) => object.pdf_area(ray) / (self.objects.len() as f64),
+            None => 0.0,
+        }
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(self.bounds)
+    }
 }
 
 impl<T: Bounded> KdTree<T> {
+    /// Intersect the tree with a ray, like `Shape::intersect`, but also return a
+    /// reference to the closest object that was hit
+    pub fn intersect_object(&self, ray: &Ray, t_min: f64, record: &mut HitRecord) -> Option<&T> {
+        let (b_min, b_max) = self.bounds.intersect(ray);
+        if f64::max(b_min, t_min) > f64::min(b_max, record.time) {
+            // No potential for intersecting, even the broader bounding box
+            return None;
+        }
+        let index = self.intersect_subtree(&self.root, &self.bounds, ray, t_min, record)?;
+        Some(&self.objects[index])
+    }
+
     /// Intersect the current ray with a given subtree.
     ///
     /// Guarantee: we always find the closest intersection in the current kd-cell, if any.
+    /// Returns the index of the object that was hit, if the record was updated.
     #[allow(clippy::float_cmp)]
     fn intersect_subtree(
         &self,
@@ -155,16 +224,16 @@ impl<T: Bounded> KdTree<T> {
         ray: &Ray,
         t_min: f64,
         record: &mut HitRecord,
-    ) -> bool {
+    ) -> Option<usize> {
         let (b_min, b_max) = bbox.intersect(ray);
 
         let (t_split, first, second, bbox_split) = match node {
             KdNode::Leaf(indices) => {
                 // Try to intersect the ray with all objects in the node
-                let mut result = false;
+                let mut result = None;
                 for &index in indices {
                     if self.objects[index].intersect(ray, t_min, record) {
-                        result = true;
+                        result = Some(index);
                     }
                 }
                 return result;
@@ -210,14 +279,14 @@ impl<T: Bounded> KdTree<T> {
             self.intersect_subtree(second, &bbox_split.1, ray, t_min, record)
         } else {
             let h1 = self.intersect_subtree(first, &bbox_split.0, ray, t_min, record);
-            if h1 && record.time < t_split {
-                true
+            if h1.is_some() && record.time < t_split {
+                h1
             } else {
                 // We still might need to visit the second subtree, since the first
                 // subtree might have discovered an intersection that lies outside of the
                 // actual subtree bounding box itself, but is suboptimal.
                 let h2 = self.intersect_subtree(second, &bbox_split.1, ray, t_split, record);
-                h1 || h2
+                h2.or(h1)
             }
         }
     }
@@ -293,7 +362,7 @@ fn construct<T: Bounded>(objects: &[T], indices: Vec<usize>) -> Box<KdNode> {
         // First try the direction with maximum extent
         let bounds = bboxs
             .iter()
-            .fold(BoundingBox::default(), |b1, b2| b1.merge(&b2));
+            .fold(BoundingBox::default(), |b1, b2| b1.merge(b2));
         let extent = bounds.p_max - bounds.p_min;
         if extent.x > extent.y && extent.x > extent.z {
             if sx < threshold {
@@ -10,6 +10,7 @@ pub use buffer::*;
 pub use camera::*;
 pub use color::*;
 pub use environment::*;
+pub use integrator::*;
 pub use io::*;
 pub use kdtree::*;
 pub use light::*;
@@ -17,13 +18,18 @@ pub use material::*;
 pub use object::*;
 pub use ode::*;
 pub use renderer::*;
+pub use sampler::*;
 pub use scene::*;
 pub use shape::*;
+pub use spectrum::*;
+pub use tile::*;
+pub use tone_map::*;
 
 mod buffer;
 mod camera;
 mod color;
 mod environment;
+mod integrator;
 mod io;
 mod kdtree;
 mod light;
@@ -31,5 +37,9 @@ mod material;
 mod object;
 mod ode;
 mod renderer;
+mod sampler;
 mod scene;
 mod shape;
+mod spectrum;
+mod tile;
+mod tone_map;
@@ -1,7 +1,9 @@
-use rand::rngs::StdRng;
-
 use crate::color::Color;
 use crate::object::Object;
+use crate::sampler::Sampler;
+use crate::shape::{HitRecord, Ray};
+
+const EPSILON: f64 = 1e-12;
 
 /// Type representing various forms of lighting
 pub enum Light {
@@ -15,34 +17,83 @@ pub enum Light {
     Directional(Color, glm::DVec3),
 
     /// Light from an invisible, emissive object
+    ///
+    /// The object's shape must be bounded, since points are sampled uniformly over its
+    /// surface. Adding an object light with an unbounded shape (like a plane) to a
+    /// scene panics.
     Object(Object),
 }
 
 impl Light {
-    /// Illuminates a point, returning (intensity, dir_to_light, dist_to_light)
-    pub fn illuminate(&self, world_pos: &glm::DVec3, rng: &mut StdRng) -> (Color, glm::DVec3, f64) {
+    /// Illuminates a point, returning (intensity, dir_to_light, dist_to_light, pdf)
+    ///
+    /// The intensity has already been divided by the PDF, which is given with respect
+    /// to solid angle at the point. Lights with a delta distribution (such as point and
+    /// directional lights) have an infinite PDF, since they cannot be hit by chance.
+    /// Object lights are sampled where they are at `time`, the time of the shadow ray.
+    pub fn illuminate(
+        &self,
+        world_pos: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (Color, glm::DVec3, f64, f64) {
         match self {
-            Light::Ambient(color) => (*color, glm::vec3(0.0, 0.0, 0.0), 0.0),
+            Light::Ambient(color) => (*color, glm::vec3(0.0, 0.0, 0.0), 0.0, f64::INFINITY),
             Light::Point(color, location) => {
                 let disp = location - world_pos;
                 let len = glm::length(&disp);
-                (color / (len * len), disp / len, len)
-            }
-            Light::Directional(color, direction) => {
-                (*color, -glm::normalize(direction), f64::INFINITY)
+                (color / (len * len), disp / len, len, f64::INFINITY)
             }
+            Light::Directional(color, direction) => (
+                *color,
+                -glm::normalize(direction),
+                f64::INFINITY,
+                f64::INFINITY,
+            ),
             Light::Object(object) => {
-                let (v, n, p) = object.shape.sample(&world_pos, rng);
+                let (v, n, pdf) = object.shape.sample(world_pos, time, sampler);
                 let disp = v - world_pos;
                 let len = glm::length(&disp);
-                let cosine = (-disp.dot(&n)).max(0.0) / len;
-                let surface_area = cosine.max(0.0) / (len * len);
-                (
-                    object.material.color * object.material.emittance * surface_area / p,
-                    disp / len,
-                    len,
-                )
+                let intensity = if disp.dot(&n) < 0.0 && pdf > 0.0 && pdf.is_finite() {
+                    object.material.color * object.material.emittance / pdf
+                } else {
+                    // The sampled point is facing away, so it does not emit any light
+                    glm::vec3(0.0, 0.0, 0.0)
+                };
+                (intensity, disp / len, len, pdf)
+            }
+        }
+    }
+
+    /// Returns the emitted radiance and PDF of illuminating `world_pos` from the
+    /// direction `dir` at `time`, if this is an object light hit by that ray before
+    /// `t_max`
+    ///
+    /// This is used to weight emission found by BSDF sampling, since object lights are
+    /// otherwise invisible to rays traced through the scene.
+    pub fn emission(
+        &self,
+        world_pos: &glm::DVec3,
+        dir: &glm::DVec3,
+        time: f64,
+        t_max: f64,
+    ) -> Option<(Color, f64)> {
+        match self {
+            Light::Object(object) => {
+                let ray = Ray {
+                    origin: *world_pos,
+                    dir: *dir,
+                    time,
+                };
+                let mut h = HitRecord::new();
+                h.time = t_max;
+                if !object.shape.intersect(&ray, EPSILON, &mut h) || h.normal.dot(dir) >= 0.0 {
+                    return None;
+                }
+                let radiance = object.material.color * object.material.emittance;
+                Some((radiance, object.shape.pdf(world_pos, dir, time)))
             }
+            _ => None,
         }
     }
 }
@@ -1,7 +1,6 @@
-use rand::{rngs::StdRng, Rng};
-use rand_distr::{UnitCircle, UnitDisc};
-
 use crate::color::{hex_color, Color};
+use crate::sampler::{sample_circle, sample_disk, Sampler};
+use crate::spectrum::Dispersion;
 
 /// Represents a shader material with some physical properties
 #[derive(Copy, Clone)]
@@ -12,6 +11,10 @@ pub struct Material {
     /// Index of refraction
     pub index: f64,
 
+    /// Wavelength-dependent index of refraction, which replaces `index` in spectral
+    /// rendering
+    pub dispersion: Option<Dispersion>,
+
     /// Roughness parameter for Beckmann microfacet distribution
     pub roughness: f64,
 
@@ -37,6 +40,7 @@ impl Material {
         Material {
             color,
             index: 1.5,
+            dispersion: None,
             roughness: 1.0,
             metallic: 0.0,
             emittance: 0.0,
@@ -49,6 +53,7 @@ impl Material {
         Material {
             color,
             index: 1.5,
+            dispersion: None,
             roughness,
             metallic: 0.0,
             emittance: 0.0,
@@ -61,6 +66,7 @@ impl Material {
         Material {
             color: glm::vec3(1.0, 1.0, 1.0),
             index,
+            dispersion: None,
             roughness,
             metallic: 0.0,
             emittance: 0.0,
@@ -73,6 +79,7 @@ impl Material {
         Material {
             color,
             index,
+            dispersion: None,
             roughness,
             metallic: 0.0,
             emittance: 0.0,
@@ -80,11 +87,21 @@ impl Material {
         }
     }
 
+    /// Clear material with a wavelength-dependent index of refraction, which shows
+    /// dispersion in spectral rendering
+    pub fn dispersive(dispersion: Dispersion, roughness: f64) -> Material {
+        Material {
+            dispersion: Some(dispersion),
+            ..Material::clear(dispersion.d_line_index(), roughness)
+        }
+    }
+
     /// Metallic material (has extra tinted specular reflections)
     pub fn metallic(color: Color, roughness: f64) -> Material {
         Material {
             color,
             index: 1.5,
+            dispersion: None,
             roughness,
             metallic: 1.0,
             emittance: 0.0,
@@ -97,6 +114,7 @@ impl Material {
         Material {
             color,
             index: 1.0,
+            dispersion: None,
             roughness: 1.0,
             metallic: 0.0,
             emittance,
@@ -225,55 +243,35 @@ impl Material {
         &self,
         n: &glm::DVec3,
         wo: &glm::DVec3,
-        rng: &mut StdRng,
+        sampler: &mut dyn Sampler,
     ) -> Option<(glm::DVec3, f64)> {
         let m2 = self.roughness * self.roughness;
+        let f = self.specular_probability();
+        let eta_t = self.eta_t(n, wo);
 
-        // Estimate specular contribution using Fresnel term
-        let f0 = ((self.index - 1.0) / (self.index + 1.0)).powi(2);
-        let f = (1.0 - self.metallic) * f0 + self.metallic * self.color.mean();
-        let f = glm::mix_scalar(f, 1.0, 0.2);
-
-        // Ratio of refractive indices
-        let eta_t = if wo.dot(n) > 0.0 {
-            self.index
-        } else {
-            1.0 / self.index
-        };
-
-        let beckmann = |rng: &mut StdRng| {
+        let beckmann = |sampler: &mut dyn Sampler| {
+            let [u, v] = sampler.next_2d();
             // PIT for Beckmann distribution microfacet normal
             // θ = arctan √(-m^2 ln U)
-            let theta = (m2 * -rng.gen::<f64>().ln()).sqrt().atan();
+            let theta = (m2 * -u.ln()).sqrt().atan();
             let (sin_t, cos_t) = theta.sin_cos();
 
             // Generate halfway vector by sampling azimuth uniformly
-            let [x, y]: [f64; 2] = rng.sample(UnitCircle);
+            let [x, y] = sample_circle(v);
             let h = glm::vec3(x * sin_t, y * sin_t, cos_t);
             local_to_world(n) * h
         };
 
-        let beckmann_pdf = |h: &glm::DVec3| {
-            // p = 1 / (πm^2 cos^3 θ) * e^(-tan^2(θ) / m^2)
-            let cos_t = h.dot(n).abs();
-            let sin_t = (1.0 - cos_t * cos_t).sqrt();
-            (std::f64::consts::PI * m2 * cos_t.powi(3)).recip()
-                * (-(sin_t / cos_t).powi(2) / m2).exp()
-        };
-
-        let wi = if rng.gen_bool(f) {
+        let wi = if sampler.next_1d() < f {
             // Specular component
-            let h = beckmann(rng);
+            let h = beckmann(sampler);
             -glm::reflect_vec(wo, &h)
         } else if !self.transparent {
             // Diffuse component (Lambertian)
-            // Simple cosine-sampling using Malley's method
-            let [x, y]: [f64; 2] = rng.sample(UnitDisc);
-            let z = (1.0_f64 - x * x - y * y).sqrt();
-            local_to_world(n) * glm::vec3(x, y, z)
+            cosine_sample(n, sampler)
         } else {
             // Transmitted component
-            let h = beckmann(rng);
+            let h = beckmann(sampler);
             let cos_to = h.dot(wo);
             let wo_perp = wo - h * cos_to;
             let wi_perp = -wo_perp / eta_t;
@@ -287,6 +285,26 @@ impl Material {
             -cos_to.signum() * cos_ti * h + wi_perp
         };
 
+        Some((wi, self.pdf(n, wo, &wi)))
+    }
+
+    /// Probability density of `sample_f` choosing the direction `wi`, given `wo`
+    ///
+    /// This is the total probability over all of the components of the BSDF, which
+    /// are sampled independently (a one-sample multiple importance sampling model).
+    pub fn pdf(&self, n: &glm::DVec3, wo: &glm::DVec3, wi: &glm::DVec3) -> f64 {
+        let m2 = self.roughness * self.roughness;
+        let f = self.specular_probability();
+        let eta_t = self.eta_t(n, wo);
+
+        let beckmann_pdf = |h: &glm::DVec3| {
+            // p = 1 / (πm^2 cos^3 θ) * e^(-tan^2(θ) / m^2)
+            let cos_t = h.dot(n).abs();
+            let sin_t = (1.0 - cos_t * cos_t).sqrt();
+            (std::f64::consts::PI * m2 * cos_t.powi(3)).recip()
+                * (-(sin_t / cos_t).powi(2) / m2).exp()
+        };
+
         // Multiple importance sampling - add up total probability
         let mut p = 0.0;
         p += {
@@ -303,17 +321,43 @@ impl Material {
             let h = (wi * eta_t + wo).normalize();
             let p_h = beckmann_pdf(&h);
             let h_dot_wo = h.dot(wo);
-            let h_dot_wi = h.dot(&wi);
+            let h_dot_wi = h.dot(wi);
             let jacobian = h_dot_wo.abs() / (eta_t * h_dot_wi + h_dot_wo).powi(2);
             (1.0 - f) * p_h * jacobian
         } else {
             0.0
         };
-        Some((wi, p))
+        p
     }
+
+    /// Estimate the probability of sampling the specular component, using the Fresnel term
+    fn specular_probability(&self) -> f64 {
+        let f0 = ((self.index - 1.0) / (self.index + 1.0)).powi(2);
+        let f = (1.0 - self.metallic) * f0 + self.metallic * self.color.mean();
+        glm::mix_scalar(f, 1.0, 0.2)
+    }
+
+    /// Ratio of refractive indices, depending on which side of the surface `wo` is on
+    fn eta_t(&self, n: &glm::DVec3, wo: &glm::DVec3) -> f64 {
+        if wo.dot(n) > 0.0 {
+            self.index
+        } else {
+            1.0 / self.index
+        }
+    }
+}
+
+/// Sample a direction from the cosine-weighted hemisphere around a normal, with
+/// probability density cos(θ) / π
+///
+/// This is simple cosine-sampling using Malley's method.
+pub(crate) fn cosine_sample(n: &glm::DVec3, sampler: &mut dyn Sampler) -> glm::DVec3 {
+    let [x, y] = sample_disk(sampler.next_2d());
+    let z = (1.0_f64 - x * x - y * y).sqrt();
+    local_to_world(n) * glm::vec3(x, y, z)
 }
 
-fn local_to_world(n: &glm::DVec3) -> glm::DMat3 {
+pub(crate) fn local_to_world(n: &glm::DVec3) -> glm::DMat3 {
     let ns = if n.x.is_normal() {
         glm::vec3(n.y, -n.x, 0.0).normalize()
     } else {
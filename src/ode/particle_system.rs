@@ -24,8 +24,10 @@ pub trait ParticleSystem {
     }
 }
 
+#[cfg(test)]
 pub struct SimpleCircleSystem;
 
+#[cfg(test)]
 impl ParticleSystem for SimpleCircleSystem {
     fn time_derivative(&self, state: &ParticleState) -> ParticleState {
         ParticleState {
@@ -1,18 +1,38 @@
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
 use image::RgbImage;
-use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand::{rngs::StdRng, SeedableRng};
 use rayon::prelude::*;
 
-use crate::buffer::{Buffer, Filter};
+use crate::buffer::{Buffer, Checkpoint, Features, Filter};
 use crate::camera::Camera;
-use crate::color::Color;
+use crate::color::{Color, ColorSpace, Primaries, TransferFunction};
+use crate::integrator::{power_heuristic, Integrator, PathTracer};
 use crate::light::Light;
 use crate::material::Material;
-use crate::object::Object;
-use crate::scene::Scene;
-use crate::shape::{HitRecord, Ray};
+use crate::sampler::{hash, IndependentSampler, SampleIndex, Sampler};
+use crate::scene::{Scene, SceneTree};
+use crate::shape::Ray;
+use crate::tile::{Tile, TileOrder, TileProgress};
+use crate::tone_map::ToneMap;
 
 const EPSILON: f64 = 1e-12;
-const FIREFLY_CLAMP: f64 = 100.0;
+
+/// Upper bound on the survival probability in Russian roulette, so that paths with
+/// high throughput (such as those stuck inside glass) still terminate eventually
+const RUSSIAN_ROULETTE_MAX: f64 = 0.95;
+
+/// Added to the mean in the denominator of the relative error, so that pixels that are
+/// nearly black can converge
+const RELATIVE_ERROR_EPSILON: f64 = 1e-3;
+
+/// First index of the random streams used for tracing photon maps, which distinguishes
+/// them from the streams of other random choices (see `Renderer::rng`)
+pub(crate) const PHOTON_STREAM: u64 = 1;
 
 /// Builder object for rendering a scene
 pub struct Renderer<'a> {
@@ -28,17 +48,81 @@ pub struct Renderer<'a> {
     /// The height of the output image
     pub height: u32,
 
-    /// Exposure value (EV)
+    /// Exposure value (EV), which is added to the exposure of a physical camera
     pub exposure_value: f64,
 
     /// Optional noise-reduction filter
     pub filter: Filter,
 
+    /// Tone mapping operator for the output image
+    pub tone_map: ToneMap,
+
+    /// Primaries of the colors in the scene, which light is transported in, and whose
+    /// luminance weights adaptive sampling, firefly clamping and denoising use
+    pub working_space: Primaries,
+
+    /// Color space of the output image
+    pub output_space: ColorSpace,
+
+    /// Whether to collect feature buffers even if the filter does not use them, such
+    /// as to write them out as extra channels of an image
+    pub collect_features: bool,
+
     /// The maximum number of ray bounces
     pub max_bounces: u32,
 
+    /// Minimum number of bounces before paths are terminated by Russian roulette,
+    /// which replaces the `max_bounces` cutoff when enabled
+    pub russian_roulette: Option<u32>,
+
     /// Number of random paths traced per pixel
     pub num_samples: u32,
+
+    /// Adaptive sampling, which traces more paths in noisy pixels
+    pub adaptive_sampling: AdaptiveSampling,
+
+    /// Optional range of passes over the image to render, out of all of the passes
+    pub sample_range: Option<Range<u32>>,
+
+    /// Firefly reduction applied to path contributions (biased)
+    pub firefly_clamp: FireflyClamp,
+
+    /// The light transport algorithm
+    pub integrator: Box<dyn Integrator>,
+
+    /// Seed for the random number generators, which makes rendering deterministic
+    pub seed: Option<u64>,
+
+    /// The sampler that generates the random choices of camera samples
+    pub sampler: Box<dyn Sampler>,
+
+    /// Width and height of the tiles that each pass over the image is split into
+    pub tile_size: u32,
+
+    /// Order in which tiles are rendered
+    pub tile_order: TileOrder,
+
+    /// Callback for reporting progress, which is called after each tile is finished
+    #[allow(clippy::type_complexity)]
+    pub progress: Option<Box<dyn Fn(&TileProgress<'_>) + Send + Sync + 'a>>,
+
+    /// Token for stopping the render early
+    pub cancellation_token: Option<CancellationToken>,
+
+    /// Optional file that checkpoints of the render are saved to
+    pub checkpoint_path: Option<PathBuf>,
+
+    /// Number of passes over the image between checkpoints
+    pub checkpoint_interval: u32,
+
+    /// Optional checkpoint of an earlier render, which this render continues from
+    pub resume_from: Option<Checkpoint>,
+
+    /// Optional sub-rectangle of the image to render, leaving the rest unsampled
+    pub crop_window: Option<CropWindow>,
+
+    /// Whether a cropped render outputs only the crop window, or the full frame
+    pub crop_output: CropOutput,
 }
 
 impl<'a> Renderer<'a> {
@@ -51,8 +135,28 @@ impl<'a> Renderer<'a> {
             height: 600,
             exposure_value: 0.0,
             filter: Filter::default(),
+            tone_map: ToneMap::default(),
+            working_space: Primaries::default(),
+            output_space: ColorSpace::default(),
+            collect_features: false,
             max_bounces: 0,
+            russian_roulette: None,
             num_samples: 1,
+            adaptive_sampling: AdaptiveSampling::default(),
+            sample_range: None,
+            firefly_clamp: FireflyClamp::default(),
+            integrator: Box::new(PathTracer),
+            seed: None,
+            sampler: Box::new(IndependentSampler::default()),
+            tile_size: 32,
+            tile_order: TileOrder::default(),
+            progress: None,
+            cancellation_token: None,
+            checkpoint_path: None,
+            checkpoint_interval: 1,
+            resume_from: None,
+            crop_window: None,
+            crop_output: CropOutput::default(),
         }
     }
 
@@ -80,142 +184,1261 @@ impl<'a> Renderer<'a> {
         self
     }
 
+    /// Set the tone mapping operator, which compresses bright colors in the output
+    /// image after the exposure value is applied
+    pub fn tone_map(mut self, tone_map: ToneMap) -> Self {
+        self.tone_map = tone_map;
+        self
+    }
+
+    /// Set the transfer function that encodes the output image for display
+    pub fn transfer_function(mut self, transfer_function: TransferFunction) -> Self {
+        self.output_space.transfer_function = transfer_function;
+        self
+    }
+
+    /// Set the working space, whose primaries the colors of materials and
+    /// environments are given in
+    ///
+    /// Scene colors are read as values in the working space, without any conversion,
+    /// so colors authored in other spaces should be converted with
+    /// `ColorSpace::to_linear`. HDRI environments should be given the same working
+    /// space with `Hdri::working_space`, which they are importance sampled in.
+    pub fn working_space(mut self, working_space: Primaries) -> Self {
+        self.working_space = working_space;
+        self
+    }
+
+    /// Set the color space of the output image, which replaces the transfer function
+    pub fn output_space(mut self, output_space: ColorSpace) -> Self {
+        self.output_space = output_space;
+        self
+    }
+
+    /// Set whether to collect albedo, normal and depth feature buffers, which are
+    /// otherwise only collected for filters that use them
+    pub fn collect_features(mut self, collect_features: bool) -> Self {
+        self.collect_features = collect_features;
+        self
+    }
+
     /// Set the maximum number of ray bounces when ray is traced
     pub fn max_bounces(mut self, max_bounces: u32) -> Self {
         self.max_bounces = max_bounces;
         self
     }
 
+    /// Terminate paths by Russian roulette after a minimum number of bounces
+    ///
+    /// Unlike `max_bounces`, this is unbiased: a path survives each bounce with a
+    /// probability based on its throughput, and surviving paths are weighted up.
+    pub fn russian_roulette(mut self, min_depth: u32) -> Self {
+        self.russian_roulette = Some(min_depth);
+        self
+    }
+
     /// Set the number of random paths traced per pixel
     pub fn num_samples(mut self, num_samples: u32) -> Self {
         self.num_samples = num_samples;
         self
     }
 
-    /// Render the scene by path tracing
+    /// Render only a range of the passes over the image, such as to split a render
+    /// across several processes
+    ///
+    /// Each pass traces one sample per pixel, so buffers rendered from disjoint ranges
+    /// can be combined with `Buffer::merge`. The renderer should have a seed, so that
+    /// every range continues the same sequence of samples. With adaptive sampling,
+    /// convergence is estimated from the samples in each range alone.
+    pub fn sample_range(mut self, sample_range: Range<u32>) -> Self {
+        self.sample_range = Some(sample_range);
+        self
+    }
+
+    /// Set the adaptive sampling mode
+    ///
+    /// When enabled, every pixel first gets `num_samples` paths, after which only the
+    /// pixels with an error above the threshold are sampled further.
+    pub fn adaptive_sampling(mut self, adaptive_sampling: AdaptiveSampling) -> Self {
+        self.adaptive_sampling = adaptive_sampling;
+        self
+    }
+
+    /// Set the firefly reduction, which clamps bright path contributions
+    pub fn firefly_clamp(mut self, firefly_clamp: FireflyClamp) -> Self {
+        self.firefly_clamp = firefly_clamp;
+        self
+    }
+
+    /// Set the light transport algorithm
+    pub fn integrator(mut self, integrator: impl Integrator + 'static) -> Self {
+        self.integrator = Box::new(integrator);
+        self
+    }
+
+    /// Seed the random number generators, so that renders are reproducible
+    ///
+    /// Every sample of every pixel gets its own random stream derived from the seed,
+    /// so the output is identical no matter how work is scheduled across threads.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Set the sampler, such as a low-discrepancy sampler that reduces noise
+    pub fn sampler(mut self, sampler: impl Sampler + 'static) -> Self {
+        self.sampler = Box::new(sampler);
+        self
+    }
+
+    /// Set the size and order of the tiles that the image is rendered in
+    pub fn tiles(mut self, tile_size: u32, tile_order: TileOrder) -> Self {
+        self.tile_size = tile_size;
+        self.tile_order = tile_order;
+        self
+    }
+
+    /// Set a callback for reporting progress, which is called from worker threads
+    /// after each tile of each pass is finished
+    pub fn progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&TileProgress<'_>) + Send + Sync + 'a,
+    {
+        self.progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Set a token that can be used to cancel the render from another thread
+    ///
+    /// After cancellation, tiles that are in progress are finished, and the render
+    /// returns early with the passes that it has completed so far.
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    /// Save a checkpoint of the render to a file after every k samples, and when the
+    /// render finishes or is cancelled
+    ///
+    /// The file is replaced atomically, so it always holds a complete checkpoint. A
+    /// render can be continued from it later with `Renderer::resume`.
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>, interval: u32) -> Self {
+        self.checkpoint_path = Some(path.into());
+        self.checkpoint_interval = interval.max(1);
+        self
+    }
+
+    /// Continue a render from a checkpoint, accumulating samples until the renderer has
+    /// traced `num_samples` in total
+    ///
+    /// The renderer should be configured the same way as the one that saved the
+    /// checkpoint. The seed of the checkpoint takes precedence, so that the resumed
+    /// render continues the same sequence of samples, and adaptive sampling continues
+    /// from the statistics of the checkpoint.
+    ///
+    /// Returns an error if the checkpoint does not match the size of the render, so the
+    /// width, height and crop window should be set before this is called.
+    pub fn resume(mut self, checkpoint: Checkpoint) -> io::Result<Self> {
+        let num_pixels = (self.width * self.height) as usize;
+        if checkpoint.buffer.dimensions() != self.output_size()
+            || checkpoint.stats.len() != num_pixels
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Checkpoint does not match the size of the render",
+            ));
+        }
+        self.resume_from = Some(checkpoint);
+        Ok(self)
+    }
+
+    /// Render only a sub-rectangle of the image, with the same camera mapping as the
+    /// full frame
+    ///
+    /// With `CropOutput::FullFrame`, the buffers rendered from disjoint windows can be
+    /// combined with `Buffer::merge`, such as to split a render across processes.
+    pub fn crop(mut self, crop_window: CropWindow, crop_output: CropOutput) -> Self {
+        self.crop_window = Some(crop_window);
+        self.crop_output = crop_output;
+        self
+    }
+
+    /// Returns the rectangle of pixels that are rendered, which is the crop window if
+    /// there is one, or otherwise the full frame
+    pub fn region(&self) -> Tile {
+        let frame = Tile {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        match self.crop_window {
+            Some(crop_window) => crop_window.region(self.width, self.height),
+            None => frame,
+        }
+    }
+
+    /// Returns the range of passes over the image to render
+    fn passes(&self) -> Range<u32> {
+        let passes = self.adaptive_sampling.max_samples(self.num_samples);
+        match &self.sample_range {
+            Some(range) => range.start.min(passes)..range.end.min(passes),
+            None => 0..passes,
+        }
+    }
+
+    /// Returns the size of the output image, which depends on the crop output mode
+    fn output_size(&self) -> (u32, u32) {
+        match (self.crop_window, self.crop_output) {
+            (Some(_), CropOutput::Cropped) => {
+                let region = self.region();
+                (region.width, region.height)
+            }
+            _ => (self.width, self.height),
+        }
+    }
+
+    /// Returns a random number generator for a stream of random choices, identified
+    /// by a sequence of indices
+    ///
+    /// If the renderer has a seed, the generator is seeded deterministically from it
+    /// and the indices, so distinct streams are independent. Otherwise, it is seeded
+    /// from system entropy.
+    pub fn rng(&self, stream: &[u64]) -> StdRng {
+        match self.seed {
+            Some(seed) => StdRng::seed_from_u64(hash(&[&[seed], stream].concat())),
+            None => StdRng::from_entropy(),
+        }
+    }
+
+    /// Render the scene
     pub fn render(&self) -> RgbImage {
-        let mut buffer = Buffer::new(self.width, self.height, self.filter);
-        self.sample(self.num_samples, &mut buffer);
-        buffer.image()
+        self.render_buffer().image()
+    }
+
+    /// Render the scene, returning the buffer of samples instead of an image
+    ///
+    /// This can be used to inspect the render further, such as with `Buffer::heatmap`.
+    pub fn render_buffer(&self) -> Buffer {
+        self.render_passes(u32::MAX, |_, _| ())
     }
 
     /// Render the scene iteratively, calling a callback after every k samples
-    pub fn iterative_render<F>(&self, callback_interval: u32, mut callback: F)
+    ///
+    /// With adaptive sampling or cancellation, this stops early once every pixel has
+    /// converged or the render is cancelled.
+    pub fn iterative_render<F>(&self, callback_interval: u32, callback: F)
+    where
+        F: FnMut(u32, &Buffer),
+    {
+        self.render_passes(callback_interval, callback);
+    }
+
+    /// Render passes over the image, calling a callback after every k samples and
+    /// saving checkpoints along the way, and return the final buffer
+    fn render_passes<F>(&self, callback_interval: u32, mut callback: F) -> Buffer
     where
         F: FnMut(u32, &Buffer),
     {
-        let mut buffer = Buffer::new(self.width, self.height, self.filter);
-        let mut iteration = 0;
-        while iteration < self.num_samples {
-            let steps = std::cmp::min(self.num_samples - iteration, callback_interval);
-            self.sample(steps, &mut buffer);
-            iteration += steps;
-            callback(iteration, &buffer);
+        let tree = SceneTree::new(self.scene);
+        let (width, height) = self.output_size();
+        let passes = self.passes();
+        let num_pixels = (self.width * self.height) as usize;
+        let (seed, start, mut buffer, mut stats) = match &self.resume_from {
+            Some(checkpoint) => {
+                assert!(
+                    checkpoint.buffer.dimensions() == (width, height)
+                        && checkpoint.stats.len() == num_pixels,
+                    "Renderer was resized after resuming from a checkpoint"
+                );
+                let start = checkpoint.passes.max(passes.start).min(passes.end);
+                (
+                    checkpoint.seed,
+                    start,
+                    checkpoint.buffer.clone(),
+                    checkpoint.stats.clone(),
+                )
+            }
+            None => {
+                let seed = self.seed.unwrap_or_else(rand::random);
+                let buffer = Buffer::new(width, height, self.filter);
+                (
+                    seed,
+                    passes.start,
+                    buffer,
+                    vec![PixelStats::default(); num_pixels],
+                )
+            }
+        };
+        if self.integrator.raw_output() {
+            // Raw values are written out without any display transform
+            buffer.set_tone_map(ToneMap::Clamp);
+            buffer.set_working_space(self.working_space);
+            buffer.set_output_space(ColorSpace::new(
+                self.working_space,
+                TransferFunction::Linear,
+            ));
+        } else {
+            buffer.set_tone_map(self.tone_map);
+            buffer.set_working_space(self.working_space);
+            buffer.set_output_space(self.output_space);
+        }
+        let callback_interval = callback_interval.max(1);
+        let checkpoint_interval = match self.checkpoint_path {
+            Some(_) => self.checkpoint_interval.max(1),
+            None => u32::MAX,
+        };
+        let mut iteration = start;
+        while iteration < passes.end {
+            let steps = (passes.end - iteration)
+                .min(callback_interval - (iteration - start) % callback_interval)
+                .min(checkpoint_interval - iteration % checkpoint_interval);
+            let completed = self.sample(&tree, seed, iteration, steps, &mut stats, &mut buffer);
+            iteration += completed;
+            let done = completed < steps || self.cancelled();
+            let finished = done || iteration == passes.end;
+            if finished || (iteration - start) % callback_interval == 0 {
+                callback(iteration, &buffer);
+            }
+            if finished || iteration % checkpoint_interval == 0 {
+                self.save_checkpoint(seed, iteration, &buffer, &stats);
+            }
+            if done {
+                break;
+            }
+        }
+        buffer
+    }
+
+    /// Save a checkpoint of the render, if enabled, warning if it fails
+    fn save_checkpoint(&self, seed: u64, passes: u32, buffer: &Buffer, stats: &[PixelStats]) {
+        if let Some(path) = &self.checkpoint_path {
+            let checkpoint = Checkpoint {
+                passes,
+                seed,
+                buffer: buffer.clone(),
+                stats: stats.to_vec(),
+            };
+            if let Err(err) = checkpoint.save(path) {
+                eprintln!(
+                    "Warning: Failed to save checkpoint to {}: {}",
+                    path.display(),
+                    err
+                );
+            }
         }
     }
 
-    fn sample(&self, iterations: u32, buffer: &mut Buffer) {
-        let colors: Vec<_> = (0..self.height)
+    /// Trace passes over the image, each with one sample per pixel that has not yet
+    /// converged, and add them to the buffer
+    ///
+    /// Returns the number of passes that were completed, which is less than `iterations`
+    /// if every pixel converged or the render was cancelled first.
+    fn sample(
+        &self,
+        tree: &SceneTree<'_>,
+        seed: u64,
+        pass: u32,
+        iterations: u32,
+        stats: &mut [PixelStats],
+        buffer: &mut Buffer,
+    ) -> u32 {
+        let region = self.region();
+        let tiles = region.split(self.tile_size, self.tile_order);
+        let luminance_weights = self.working_space.luminance_weights();
+        for i in 0..iterations {
+            let index = pass + i;
+            if self.cancelled() {
+                return i;
+            }
+            let active: Vec<_> = stats
+                .iter()
+                .enumerate()
+                .map(|(pixel, stats)| {
+                    let pixel = pixel as u32;
+                    region.contains(pixel % self.width, pixel / self.width)
+                        && (index < self.num_samples || !self.adaptive_sampling.converged(stats))
+                })
+                .collect();
+            if !active.contains(&true) {
+                return i;
+            }
+            self.integrator.preprocess(self, tree, index);
+            let samples =
+                match self.sample_tiles(tree, seed, index, &tiles, &active, &luminance_weights) {
+                    Some(samples) => samples,
+                    // The pass was cancelled before every tile was finished
+                    None => return i,
+                };
+            // Film positions are relative to the crop window, if only it is output
+            let origin = match (self.crop_window, self.crop_output) {
+                (Some(_), CropOutput::Cropped) => (region.x, region.y),
+                _ => (0, 0),
+            };
+            // Samples are only splatted inside the crop window, even in the full frame
+            let window = Tile {
+                x: region.x - origin.0,
+                y: region.y - origin.1,
+                ..region
+            };
+            for (x, y) in region.pixels() {
+                let pixel = (y * self.width + x) as usize;
+                if let Some(sample) = &samples[pixel] {
+                    let [fx, fy] = sample.film;
+                    let (fx, fy) = (fx - f64::from(origin.0), fy - f64::from(origin.1));
+                    stats[pixel].add(luminance_weights.dot(&sample.color));
+                    buffer.add_film_sample_within(fx, fy, sample.color, &window);
+                    if let Some(features) = &sample.features {
+                        buffer.add_features(x - origin.0, y - origin.1, features);
+                    }
+                }
+            }
+        }
+        iterations
+    }
+
+    /// Trace one sample in each active pixel of every tile, returning the samples of
+    /// the whole image
+    ///
+    /// Tiles are handed out to threads in order, and are skipped after cancellation,
+    /// in which case this returns `None` unless every tile was already finished.
+    fn sample_tiles(
+        &self,
+        tree: &SceneTree<'_>,
+        seed: u64,
+        index: u32,
+        tiles: &[Tile],
+        active: &[bool],
+        luminance_weights: &Color,
+    ) -> Option<Vec<Option<CameraSample>>> {
+        let next_tile = AtomicUsize::new(0);
+        let tiles_finished = AtomicUsize::new(0);
+        let results: Vec<_> = (0..rayon::current_num_threads())
             .into_par_iter()
-            .flat_map(|y| {
-                let mut rng = StdRng::from_entropy();
-                (0..self.width)
-                    .into_iter()
-                    .map(|x| self.get_color(x, y, iterations, &mut rng))
-                    .collect::<Vec<_>>()
+            .flat_map_iter(|_| {
+                let mut sampler = self.sampler.clone_sampler();
+                let mut results = Vec::new();
+                while !self.cancelled() {
+                    let tile = match tiles.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                        Some(tile) => *tile,
+                        None => break,
+                    };
+                    let samples: Vec<_> = tile
+                        .pixels()
+                        .map(|(x, y)| {
+                            let pixel = y * self.width + x;
+                            active[pixel as usize].then(|| {
+                                sampler.start_sample(SampleIndex {
+                                    seed,
+                                    pixel: pixel.into(),
+                                    index,
+                                    count: self.num_samples,
+                                });
+                                self.get_color(tree, x, y, luminance_weights, sampler.as_mut())
+                            })
+                        })
+                        .collect();
+                    if let Some(progress) = &self.progress {
+                        let colors: Vec<_> = samples
+                            .iter()
+                            .map(|sample| sample.as_ref().map(|sample| sample.color))
+                            .collect();
+                        progress(&TileProgress {
+                            pass: index,
+                            tile,
+                            samples: &colors,
+                            tiles_finished: tiles_finished.fetch_add(1, Ordering::Relaxed) + 1,
+                            num_tiles: tiles.len(),
+                        });
+                    }
+                    results.push((tile, samples));
+                }
+                results
             })
             .collect();
-        buffer.add_samples(&colors);
+        if results.len() < tiles.len() {
+            return None;
+        }
+        let mut samples = vec![None; (self.width * self.height) as usize];
+        for (tile, tile_samples) in results {
+            for ((x, y), sample) in tile.pixels().zip(tile_samples) {
+                samples[(y * self.width + x) as usize] = sample;
+            }
+        }
+        Some(samples)
+    }
+
+    /// Returns whether the render has been cancelled
+    fn cancelled(&self) -> bool {
+        self.cancellation_token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
     }
 
-    fn get_color(&self, x: u32, y: u32, iterations: u32, rng: &mut StdRng) -> Color {
+    /// Trace a sample at a random position in a pixel
+    fn get_color(
+        &self,
+        tree: &SceneTree<'_>,
+        x: u32,
+        y: u32,
+        luminance_weights: &Color,
+        sampler: &mut dyn Sampler,
+    ) -> CameraSample {
         let dim = std::cmp::max(self.width, self.height) as f64;
         let xn = ((2 * x + 1) as f64 - self.width as f64) / dim;
         let yn = ((2 * (self.height - y) - 1) as f64 - self.height as f64) / dim;
-        let mut color = glm::vec3(0.0, 0.0, 0.0);
-        for _ in 0..iterations {
-            let dx = rng.gen_range((-1.0 / dim)..(1.0 / dim));
-            let dy = rng.gen_range((-1.0 / dim)..(1.0 / dim));
-            color += self.trace_ray(self.camera.cast_ray(xn + dx, yn + dy, rng), 0, rng);
-        }
-        color / f64::from(iterations) * 2.0_f64.powf(self.exposure_value)
-    }
-
-    /// Trace a ray, obtaining a Monte Carlo estimate of the luminance
-    fn trace_ray(&self, ray: Ray, num_bounces: u32, rng: &mut StdRng) -> Color {
-        match self.get_closest_hit(ray) {
-            None => self.scene.environment.get_color(&ray.dir),
-            Some((h, object)) => {
-                let world_pos = ray.at(h.time);
-                let material = object.material;
-                let wo = -glm::normalize(&ray.dir);
-
-                let mut color = material.emittance * material.color;
-                color += self.sample_lights(&material, &world_pos, &h.normal, &wo, rng);
-                if num_bounces < self.max_bounces {
-                    if let Some((wi, pdf)) = material.sample_f(&h.normal, &wo, rng) {
-                        let f = material.bsdf(&h.normal, &wo, &wi);
-                        let ray = Ray {
-                            origin: world_pos,
-                            dir: wi,
-                        };
-                        let indirect = 1.0 / pdf
-                            * f.component_mul(&self.trace_ray(ray, num_bounces + 1, rng))
-                            * wi.dot(&h.normal).abs();
-                        color.x += indirect.x.min(FIREFLY_CLAMP);
-                        color.y += indirect.y.min(FIREFLY_CLAMP);
-                        color.z += indirect.z.min(FIREFLY_CLAMP);
-                    }
-                }
+        let [u, v] = sampler.next_2d();
+        let dx = (2.0 * u - 1.0) / dim;
+        let dy = (1.0 - 2.0 * v) / dim;
+        let ray = self.camera.cast_ray(xn + dx, yn + dy, sampler);
+        let features =
+            (self.collect_features || self.filter.uses_features()).then(|| features(tree, &ray));
+        let mut color = self.integrator.radiance(self, tree, ray, sampler);
+        if !self.integrator.raw_output() {
+            color = self.firefly_clamp.clamp_sample(color, luminance_weights);
+        }
+        CameraSample {
+            color: color * self.exposure(),
+            film: [f64::from(x) + u, f64::from(y) + v],
+            features,
+        }
+    }
+
+    /// Factor that radiance is multiplied by, from the exposure value and the settings
+    /// of the camera
+    ///
+    /// The settings of a physical camera are ignored for integrators with raw output.
+    pub fn exposure(&self) -> f64 {
+        let physical = match self.camera.physical {
+            Some(physical) if !self.integrator.raw_output() => physical.exposure(),
+            _ => 1.0,
+        };
+        physical * 2.0_f64.powf(self.exposure_value)
+    }
 
-                color
+    /// Apply Russian roulette after a bounce, if enabled, returning false if the path
+    /// should be terminated and otherwise weighting up the throughput
+    pub fn survive(
+        &self,
+        num_bounces: u32,
+        throughput: &mut Color,
+        sampler: &mut dyn Sampler,
+    ) -> bool {
+        if let Some(min_depth) = self.russian_roulette {
+            if num_bounces >= min_depth {
+                let survival = throughput.max().min(RUSSIAN_ROULETTE_MAX);
+                if sampler.next_1d() >= survival {
+                    return false;
+                }
+                *throughput /= survival;
             }
         }
+        true
     }
 
-    /// Explicitly sample from all the lights in the scene
-    fn sample_lights(
+    /// Explicitly sample from all the lights in the scene, including the environment
+    ///
+    /// If `mis` is set, samples are weighted with the power heuristic against BSDF
+    /// sampling, which can also find object lights and the environment. Shadow rays
+    /// are traced at `time`, so they see animated shapes where the path does.
+    #[allow(clippy::too_many_arguments)]
+    pub fn sample_lights(
         &self,
+        tree: &SceneTree<'_>,
         material: &Material,
         pos: &glm::DVec3,
         n: &glm::DVec3,
         wo: &glm::DVec3,
-        rng: &mut StdRng,
+        time: f64,
+        mis: bool,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        self.sample_lights_with(tree, material, pos, n, wo, time, mis, sampler, &|color| {
+            *color
+        })
+    }
+
+    /// Explicitly sample from all the lights in the scene, like `sample_lights`, but
+    /// with the colors of lights converted by `emitted` (such as to spectral radiance)
+    ///
+    /// The conversion must be linear, since it is applied to colors after they are
+    /// divided by the PDF of sampling the light.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn sample_lights_with(
+        &self,
+        tree: &SceneTree<'_>,
+        material: &Material,
+        pos: &glm::DVec3,
+        n: &glm::DVec3,
+        wo: &glm::DVec3,
+        time: f64,
+        mis: bool,
+        sampler: &mut dyn Sampler,
+        emitted: &dyn Fn(&Color) -> Color,
     ) -> Color {
         let mut color = glm::vec3(0.0, 0.0, 0.0);
         for light in &self.scene.lights {
-            if let Light::Ambient(ambient_color) = light {
-                color += ambient_color.component_mul(&material.color);
+            color += self.sample_light_with(
+                tree, light, material, pos, n, wo, time, mis, sampler, emitted,
+            );
+        }
+        color
+            + self.sample_environment_with(tree, material, pos, n, wo, time, mis, sampler, emitted)
+    }
+
+    /// Explicitly sample a single light, with the same weighting as `sample_lights`
+    #[allow(clippy::too_many_arguments)]
+    pub fn sample_light(
+        &self,
+        tree: &SceneTree<'_>,
+        light: &Light,
+        material: &Material,
+        pos: &glm::DVec3,
+        n: &glm::DVec3,
+        wo: &glm::DVec3,
+        time: f64,
+        mis: bool,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        self.sample_light_with(
+            tree,
+            light,
+            material,
+            pos,
+            n,
+            wo,
+            time,
+            mis,
+            sampler,
+            &|color| *color,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn sample_light_with(
+        &self,
+        tree: &SceneTree<'_>,
+        light: &Light,
+        material: &Material,
+        pos: &glm::DVec3,
+        n: &glm::DVec3,
+        wo: &glm::DVec3,
+        time: f64,
+        mis: bool,
+        sampler: &mut dyn Sampler,
+        emitted: &dyn Fn(&Color) -> Color,
+    ) -> Color {
+        if let Light::Ambient(ambient_color) = light {
+            return emitted(ambient_color).component_mul(&material.color);
+        }
+        let (intensity, wi, dist_to_light, pdf) = light.illuminate(pos, time, sampler);
+        if intensity == glm::vec3(0.0, 0.0, 0.0) {
+            return intensity;
+        }
+        let intensity = emitted(&intensity);
+        let closest_hit = tree
+            .closest_hit(
+                &Ray {
+                    origin: *pos,
+                    dir: wi,
+                    time,
+                },
+                EPSILON,
+            )
+            .map(|(r, _)| r.time);
+        if closest_hit.is_none() || closest_hit.unwrap() > dist_to_light {
+            let f = material.bsdf(n, wo, &wi);
+            let weight = light_weight(material, n, wo, &wi, pdf, mis);
+            f.component_mul(&intensity) * wi.dot(n).abs() * weight
+        } else {
+            glm::vec3(0.0, 0.0, 0.0)
+        }
+    }
+
+    /// Explicitly sample the environment, with the same weighting as `sample_lights`
+    #[allow(clippy::too_many_arguments)]
+    pub fn sample_environment(
+        &self,
+        tree: &SceneTree<'_>,
+        material: &Material,
+        pos: &glm::DVec3,
+        n: &glm::DVec3,
+        wo: &glm::DVec3,
+        time: f64,
+        mis: bool,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        self.sample_environment_with(tree, material, pos, n, wo, time, mis, sampler, &|color| {
+            *color
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn sample_environment_with(
+        &self,
+        tree: &SceneTree<'_>,
+        material: &Material,
+        pos: &glm::DVec3,
+        n: &glm::DVec3,
+        wo: &glm::DVec3,
+        time: f64,
+        mis: bool,
+        sampler: &mut dyn Sampler,
+        emitted: &dyn Fn(&Color) -> Color,
+    ) -> Color {
+        if let Some((wi, radiance, pdf)) = self.scene.environment.sample(sampler) {
+            let ray = Ray {
+                origin: *pos,
+                dir: wi,
+                time,
+            };
+            if pdf > 0.0 && tree.closest_hit(&ray, EPSILON).is_none() {
+                let f = material.bsdf(n, wo, &wi);
+                let weight = light_weight(material, n, wo, &wi, pdf, mis);
+                return f.component_mul(&emitted(&radiance)) * wi.dot(n).abs() * weight / pdf;
+            }
+        }
+        glm::vec3(0.0, 0.0, 0.0)
+    }
+}
+
+/// MIS weight of a light sample against BSDF sampling, or one if `mis` is not set
+fn light_weight(
+    material: &Material,
+    n: &glm::DVec3,
+    wo: &glm::DVec3,
+    wi: &glm::DVec3,
+    pdf: f64,
+    mis: bool,
+) -> f64 {
+    if mis {
+        power_heuristic(pdf, material.pdf(n, wo, wi))
+    } else {
+        1.0
+    }
+}
+
+/// A sample traced from the camera through a pixel
+#[derive(Copy, Clone)]
+struct CameraSample {
+    /// Color of the sample, after exposure
+    color: Color,
+
+    /// Position of the sample on the film, in pixels
+    film: [f64; 2],
+
+    /// Features of the first surface seen by the sample, if they are collected
+    features: Option<Features>,
+}
+
+/// Returns the features of the first surface that a camera ray hits
+fn features(tree: &SceneTree<'_>, ray: &Ray) -> Features {
+    match tree.closest_hit(ray, EPSILON) {
+        Some((h, object)) => {
+            let normal = if h.normal.dot(&ray.dir) > 0.0 {
+                -h.normal
             } else {
-                let (intensity, wi, dist_to_light) = light.illuminate(pos, rng);
-                let closest_hit = self
-                    .get_closest_hit(Ray {
-                        origin: *pos,
-                        dir: wi,
-                    })
-                    .map(|(r, _)| r.time);
-                if closest_hit.is_none() || closest_hit.unwrap() > dist_to_light {
-                    let f = material.bsdf(n, wo, &wi);
-                    color += f.component_mul(&intensity) * wi.dot(n);
-                }
+                h.normal
+            };
+            Features {
+                albedo: object.material.color,
+                normal,
+                depth: h.time * glm::length(&ray.dir),
             }
         }
-        color
+        None => Features {
+            albedo: glm::vec3(1.0, 1.0, 1.0),
+            ..Features::default()
+        },
     }
+}
 
-    /// Loop through all objects in the scene to find the closest hit.
+/// A rectangular window of the image to render
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CropWindow {
+    /// Rectangle in pixel coordinates, given by its top-left corner and size
+    Pixels {
+        /// Column of the top-left pixel
+        x: u32,
+        /// Row of the top-left pixel
+        y: u32,
+        /// Width of the window in pixels
+        width: u32,
+        /// Height of the window in pixels
+        height: u32,
+    },
+
+    /// Rectangle in normalized coordinates from (0, 0) at the top-left of the image
+    /// to (1, 1) at the bottom-right, given by two opposite corners
+    Normalized {
+        /// Left edge of the window, in [0, 1]
+        x0: f64,
+        /// Top edge of the window, in [0, 1]
+        y0: f64,
+        /// Right edge of the window, in [0, 1]
+        x1: f64,
+        /// Bottom edge of the window, in [0, 1]
+        y1: f64,
+    },
+}
+
+impl CropWindow {
+    /// Returns the pixels covered by the window in an image, clamped to its bounds
     ///
-    /// Note that we intentionally do not use a `KdTree` to accelerate this computation.
-    /// The reason is that some objects, like planes, have infinite extent, so it would
-    /// not be appropriate to put them indiscriminately into a kd-tree.
-    fn get_closest_hit(&self, ray: Ray) -> Option<(HitRecord, &'_ Object)> {
-        let mut h = HitRecord::new();
-        let mut hit = None;
-        for object in &self.scene.objects {
-            if object.shape.intersect(&ray, EPSILON, &mut h) {
-                hit = Some(object);
+    /// Normalized windows include every pixel that they overlap.
+    pub fn region(&self, width: u32, height: u32) -> Tile {
+        let (x0, y0, x1, y1) = match *self {
+            Self::Pixels {
+                x,
+                y,
+                width,
+                height,
+            } => (x, y, x.saturating_add(width), y.saturating_add(height)),
+            Self::Normalized { x0, y0, x1, y1 } => {
+                let w = f64::from(width);
+                let h = f64::from(height);
+                (
+                    (x0.min(x1) * w).floor().max(0.0) as u32,
+                    (y0.min(y1) * h).floor().max(0.0) as u32,
+                    (x0.max(x1) * w).ceil().max(0.0) as u32,
+                    (y0.max(y1) * h).ceil().max(0.0) as u32,
+                )
+            }
+        };
+        let (x1, y1) = (x1.min(width), y1.min(height));
+        let (x0, y0) = (x0.min(x1), y0.min(y1));
+        assert!(x0 < x1 && y0 < y1, "Crop window is empty");
+        Tile {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        }
+    }
+}
+
+/// Output of a render with a crop window
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CropOutput {
+    /// Output only the pixels in the crop window
+    #[default]
+    Cropped,
+
+    /// Output the full frame, with pixels outside of the crop window left black
+    FullFrame,
+}
+
+/// A token for cancelling a render, which can be shared between threads
+///
+/// A cancelled render discards the samples of the pass over the image that it was in
+/// the middle of, so the buffer it returns holds only complete passes (every pixel
+/// has the same number of samples, unless adaptive sampling skipped some of them).
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Construct a new token, which is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the renders that use this token
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns whether the token has been cancelled
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Adaptive sampling, which keeps tracing paths in pixels whose estimated error is above
+/// a threshold, up to a maximum number of paths per pixel
+///
+/// The error is estimated from the luminance of the samples in each pixel.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum AdaptiveSampling {
+    /// Every pixel gets the same number of samples
+    #[default]
+    Off,
+
+    /// Sample until the variance of the pixel's mean is below a threshold
+    Variance {
+        /// Threshold on the variance of the mean
+        threshold: f64,
+        /// Maximum number of paths per pixel
+        max_samples: u32,
+    },
+
+    /// Sample until the standard error of the pixel's mean, relative to the mean, is
+    /// below a threshold
+    RelativeError {
+        /// Threshold on the relative error
+        threshold: f64,
+        /// Maximum number of paths per pixel
+        max_samples: u32,
+    },
+}
+
+impl AdaptiveSampling {
+    /// Returns the maximum number of paths traced in any pixel
+    fn max_samples(&self, num_samples: u32) -> u32 {
+        match *self {
+            Self::Off => num_samples,
+            Self::Variance { max_samples, .. } | Self::RelativeError { max_samples, .. } => {
+                max_samples.max(num_samples)
+            }
+        }
+    }
+
+    /// Returns whether a pixel has converged, so it needs no more samples
+    fn converged(&self, stats: &PixelStats) -> bool {
+        if stats.count < 2 {
+            return matches!(self, Self::Off);
+        }
+        let variance = stats.m2 / f64::from(stats.count - 1) / f64::from(stats.count);
+        match *self {
+            Self::Off => true,
+            Self::Variance { threshold, .. } => variance <= threshold,
+            Self::RelativeError { threshold, .. } => {
+                variance.sqrt() <= threshold * (stats.mean.abs() + RELATIVE_ERROR_EPSILON)
             }
         }
-        Some((h, hit?))
+    }
+}
+
+/// Running statistics of the luminance of samples in a pixel, with Welford's algorithm,
+/// which adaptive sampling uses to decide when the pixel has converged
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct PixelStats {
+    pub(crate) count: u32,
+    pub(crate) mean: f64,
+    pub(crate) m2: f64,
+}
+
+impl PixelStats {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / f64::from(self.count);
+        self.m2 += delta * (value - self.mean);
+    }
+}
+
+/// Firefly reduction, which clamps bright path contributions at the cost of bias
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum FireflyClamp {
+    /// No clamping, which keeps the estimator unbiased
+    #[default]
+    Off,
+
+    /// Scale down each sample (a full path) so its luminance is at most this value
+    Sample(f64),
+
+    /// Clamp each color channel of the light added at every bounce to this value
+    Bounce(f64),
+}
+
+impl FireflyClamp {
+    /// Clamp the color of a full sample, whose luminance is measured with the weights
+    /// of its primaries (from `Primaries::luminance_weights`)
+    pub fn clamp_sample(&self, color: Color, luminance_weights: &Color) -> Color {
+        match *self {
+            Self::Sample(max) => {
+                let lum = luminance_weights.dot(&color);
+                if lum > max {
+                    color * (max / lum)
+                } else {
+                    color
+                }
+            }
+            _ => color,
+        }
+    }
+
+    /// Clamp the light added at a single bounce of a path
+    pub fn clamp_bounce(&self, color: Color) -> Color {
+        match *self {
+            Self::Bounce(max) => color.map(|c| c.min(max)),
+            _ => color,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::PhotonMapper;
+    use crate::light::Light;
+    use crate::object::Object;
+    use crate::scene::SceneAdd;
+    use crate::shape::{plane, sphere};
+
+    fn test_scene() -> (Scene, Camera) {
+        let mut scene = Scene::new();
+        scene.add(Object::new(sphere()).material(Material::diffuse(glm::vec3(0.5, 0.5, 0.5))));
+        scene.add(Object::new(plane(glm::vec3(0.0, 1.0, 0.0), -1.0)));
+        scene.add(Light::Point(
+            glm::vec3(10.0, 10.0, 10.0),
+            glm::vec3(0.0, 3.0, 2.0),
+        ));
+        let camera = Camera::look_at(
+            glm::vec3(0.0, 1.0, 5.0),
+            glm::vec3(0.0, 0.0, 0.0),
+            glm::vec3(0.0, 1.0, 0.0),
+            std::f64::consts::FRAC_PI_4,
+        );
+        (scene, camera)
+    }
+
+    #[test]
+    fn russian_roulette_preserves_mean() {
+        let (scene, camera) = test_scene();
+        let mean = |renderer: Renderer<'_>| {
+            let image = renderer
+                .width(16)
+                .height(16)
+                .num_samples(256)
+                .seed(5)
+                .render_buffer()
+                .hdr_image();
+            let sum: f64 = image.pixels().map(|p| f64::from(p[0])).sum();
+            sum / f64::from(image.width() * image.height())
+        };
+        let fixed = mean(Renderer::new(&scene, camera).max_bounces(16));
+        let roulette = mean(Renderer::new(&scene, camera).russian_roulette(0));
+        assert!((fixed - roulette).abs() < 0.01 * fixed);
+    }
+
+    #[test]
+    fn firefly_clamp_caps_contributions() {
+        let weights = Primaries::Rec709.luminance_weights();
+        let bright = glm::vec3(8.0, 2.0, 4.0);
+        let sample = FireflyClamp::Sample(1.0).clamp_sample(bright, &weights);
+        assert!((weights.dot(&sample) - 1.0).abs() < 1e-9);
+        assert!((sample.x / sample.y - 4.0).abs() < 1e-9, "hue is preserved");
+        assert_eq!(FireflyClamp::Sample(1.0).clamp_bounce(bright), bright);
+
+        let dim = glm::vec3(0.5, 0.2, 0.1);
+        assert_eq!(FireflyClamp::Sample(1.0).clamp_sample(dim, &weights), dim);
+        assert_eq!(
+            FireflyClamp::Bounce(3.0).clamp_bounce(bright),
+            glm::vec3(3.0, 2.0, 3.0)
+        );
+        assert_eq!(
+            FireflyClamp::Bounce(3.0).clamp_sample(bright, &weights),
+            bright
+        );
+        assert_eq!(FireflyClamp::Off.clamp_sample(bright, &weights), bright);
+    }
+
+    #[test]
+    fn seeded_render_is_deterministic() {
+        let (scene, camera) = test_scene();
+        let render = |threads: usize| {
+            let renderer = Renderer::new(&scene, camera)
+                .width(24)
+                .height(16)
+                .num_samples(2)
+                .max_bounces(2)
+                .integrator(PhotonMapper::new(1000, 0.1))
+                .seed(42);
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap()
+                .install(|| renderer.render())
+        };
+        assert_eq!(render(1), render(4));
+    }
+
+    #[test]
+    fn adaptive_sampling_skips_converged_pixels() {
+        let (scene, camera) = test_scene();
+        let buffer = Renderer::new(&scene, camera)
+            .width(24)
+            .height(16)
+            .num_samples(4)
+            .max_bounces(2)
+            .seed(0)
+            .adaptive_sampling(AdaptiveSampling::RelativeError {
+                threshold: 0.01,
+                max_samples: 32,
+            })
+            .render_buffer();
+        // The sky is a constant color, while the lit surfaces are noisy
+        assert_eq!(buffer.sample_count(0, 0), 4);
+        assert_eq!(buffer.sample_count(12, 12), 32);
+    }
+
+    #[test]
+    fn cancelled_render_stops_early() {
+        let (scene, camera) = test_scene();
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let renderer = Renderer::new(&scene, camera)
+            .width(64)
+            .height(64)
+            .num_samples(8)
+            .tiles(16, TileOrder::Scanline)
+            .cancellation_token(token)
+            .progress(move |progress| {
+                if progress.pass == 1 && progress.tiles_finished == 1 {
+                    cancel.cancel();
+                }
+            });
+        let buffer = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| renderer.render_buffer());
+        // The tile finished in the cancelled pass is discarded
+        assert_eq!(buffer.sample_count(0, 0), 1);
+        assert_eq!(buffer.sample_count(63, 63), 1);
+    }
+
+    #[test]
+    fn crop_window_matches_full_render() {
+        let (scene, camera) = test_scene();
+        let renderer = || {
+            Renderer::new(&scene, camera)
+                .width(24)
+                .height(16)
+                .num_samples(2)
+                .max_bounces(2)
+                .seed(7)
+        };
+        let full = renderer().render();
+        let window = CropWindow::Normalized {
+            x0: 0.25,
+            y0: 0.5,
+            x1: 0.75,
+            y1: 1.0,
+        };
+        let cropped = renderer().crop(window, CropOutput::Cropped).render();
+        let frame = renderer().crop(window, CropOutput::FullFrame).render();
+        assert_eq!(cropped.dimensions(), (12, 8));
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            if (6..18).contains(&x) && (8..16).contains(&y) {
+                assert_eq!(pixel, full.get_pixel(x, y));
+                assert_eq!(pixel, cropped.get_pixel(x - 6, y - 8));
+            } else {
+                assert_eq!(pixel.0, [0, 0, 0]);
+            }
+        }
+
+        // Splatting filters do not spread samples outside of the crop window
+        let filter = Filter::Tent { radius: 2.0 };
+        let cropped = renderer()
+            .filter(filter)
+            .crop(window, CropOutput::Cropped)
+            .render();
+        let frame = renderer()
+            .filter(filter)
+            .crop(window, CropOutput::FullFrame)
+            .render();
+        for (x, y, pixel) in frame.enumerate_pixels() {
+            if (6..18).contains(&x) && (8..16).contains(&y) {
+                assert_eq!(pixel, cropped.get_pixel(x - 6, y - 8));
+            } else {
+                assert_eq!(pixel.0, [0, 0, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn merged_sample_ranges_match_full_render() {
+        let (scene, camera) = test_scene();
+        let renderer = || {
+            Renderer::new(&scene, camera)
+                .width(24)
+                .height(16)
+                .num_samples(3)
+                .max_bounces(2)
+                .seed(3)
+        };
+        let full = renderer().render_buffer();
+        let mut merged = renderer().sample_range(0..1).render_buffer();
+        merged.merge(&renderer().sample_range(1..3).render_buffer());
+        assert_eq!(merged.sample_count(5, 5), 3);
+        for (a, b) in merged.image().pixels().zip(full.image().pixels()) {
+            for c in 0..3 {
+                assert!((i32::from(a[c]) - i32::from(b[c])).abs() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn resumed_render_matches_full_render() {
+        let (scene, camera) = test_scene();
+        let renderer = |num_samples| {
+            Renderer::new(&scene, camera)
+                .width(24)
+                .height(16)
+                .num_samples(num_samples)
+                .max_bounces(2)
+                .seed(11)
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("render.ckpt");
+        renderer(2).checkpoint(&path, 1).render_buffer();
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.passes, 2);
+        let resumed = renderer(5).resume(checkpoint).unwrap().render_buffer();
+        let full = renderer(5).render_buffer();
+        assert_eq!(resumed.sample_count(5, 5), 5);
+        for (a, b) in resumed.image().pixels().zip(full.image().pixels()) {
+            for c in 0..3 {
+                assert!((i32::from(a[c]) - i32::from(b[c])).abs() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn cancelled_render_resumes_from_checkpoint() {
+        let (scene, camera) = test_scene();
+        let renderer = || {
+            Renderer::new(&scene, camera)
+                .width(24)
+                .height(16)
+                .num_samples(2)
+                .max_bounces(2)
+                .adaptive_sampling(AdaptiveSampling::RelativeError {
+                    threshold: 0.05,
+                    max_samples: 8,
+                })
+                .tiles(8, TileOrder::Scanline)
+                .seed(13)
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("render.ckpt");
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        let cancelled = renderer()
+            .checkpoint(&path, 3)
+            .cancellation_token(token)
+            .progress(move |progress| {
+                if progress.pass == 4 && progress.tiles_finished == 1 {
+                    cancel.cancel();
+                }
+            });
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| cancelled.render_buffer());
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.passes, 4);
+        assert_eq!(checkpoint.buffer.sample_count(12, 12), 4);
+        assert!(renderer().width(32).resume(checkpoint.clone()).is_err());
+        let resumed = renderer().resume(checkpoint).unwrap().render_buffer();
+        let full = renderer().render_buffer();
+        assert_eq!(resumed.sample_count(0, 0), 2);
+        assert_eq!(resumed.sample_count(12, 12), 8);
+        for (x, y) in renderer().region().pixels() {
+            assert_eq!(resumed.sample_count(x, y), full.sample_count(x, y));
+        }
+        assert_eq!(resumed.image(), full.image());
     }
 }
@@ -0,0 +1,421 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Prime bases of the dimensions of the Halton sequence, after which dimensions are
+/// sampled independently
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+/// Identifies one sample of one pixel in a render
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SampleIndex {
+    /// Seed of the render, which decorrelates the samples of different renders
+    pub seed: u64,
+
+    /// Index of the pixel in the image
+    pub pixel: u64,
+
+    /// Index of the sample within its pixel, counting from zero
+    pub index: u32,
+
+    /// Total number of samples in each pixel
+    pub count: u32,
+}
+
+/// A source of sample values for the random choices made while tracing a camera
+/// sample, such as where it lands in the pixel and which directions it bounces in
+///
+/// Each sample is a point in the unit hypercube, which is consumed one or two
+/// dimensions at a time in a consistent order. Low-discrepancy samplers spread each
+/// dimension evenly over the samples of a pixel, which reduces noise compared to
+/// independent random numbers.
+pub trait Sampler: Send + Sync {
+    /// Begin a new sample, starting from its first dimension
+    fn start_sample(&mut self, index: SampleIndex);
+
+    /// Returns the next dimension of the sample, in [0, 1)
+    fn next_1d(&mut self) -> f64;
+
+    /// Returns the next two dimensions of the sample, in [0, 1)²
+    fn next_2d(&mut self) -> [f64; 2] {
+        [self.next_1d(), self.next_1d()]
+    }
+
+    /// Returns a copy of this sampler, so that each thread can have its own
+    fn clone_sampler(&self) -> Box<dyn Sampler>;
+}
+
+/// Sampler that returns independent uniform random numbers
+#[derive(Clone, Debug)]
+pub struct IndependentSampler {
+    rng: StdRng,
+}
+
+impl Default for IndependentSampler {
+    fn default() -> Self {
+        Self::from(StdRng::from_entropy())
+    }
+}
+
+impl From<StdRng> for IndependentSampler {
+    fn from(rng: StdRng) -> Self {
+        Self { rng }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, index: SampleIndex) {
+        self.rng = sample_rng(&index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        self.rng.gen()
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Sampler that divides each dimension into strata, one for each sample in a pixel,
+/// and jitters samples within a random permutation of the strata
+///
+/// Pairs of dimensions are stratified together on a grid. If a pixel takes more
+/// samples than expected, each additional batch is stratified separately.
+#[derive(Clone, Debug)]
+pub struct StratifiedSampler {
+    index: SampleIndex,
+    dim: u64,
+    rng: StdRng,
+}
+
+impl Default for StratifiedSampler {
+    fn default() -> Self {
+        Self {
+            index: SampleIndex::default(),
+            dim: 0,
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+impl StratifiedSampler {
+    /// Returns the stratum of the current sample, among a number of strata
+    fn stratum(&mut self, strata: u32) -> u32 {
+        let count = self.index.count.clamp(1, strata);
+        let batch = u64::from(self.index.index / count);
+        let SampleIndex { seed, pixel, .. } = self.index;
+        let scramble = hash(&[seed, pixel, self.dim, batch]) as u32;
+        permutation_element(self.index.index % count, strata, scramble)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, index: SampleIndex) {
+        self.index = index;
+        self.dim = 0;
+        self.rng = sample_rng(&index);
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let strata = self.index.count.max(1);
+        let stratum = self.stratum(strata);
+        self.dim += 1;
+        (f64::from(stratum) + self.rng.gen::<f64>()) / f64::from(strata)
+    }
+
+    fn next_2d(&mut self) -> [f64; 2] {
+        let nx = (f64::from(self.index.count.max(1)).sqrt().ceil()) as u32;
+        let ny = self.index.count.max(1).div_ceil(nx);
+        let stratum = self.stratum(nx * ny);
+        self.dim += 2;
+        [
+            (f64::from(stratum % nx) + self.rng.gen::<f64>()) / f64::from(nx),
+            (f64::from(stratum / nx) + self.rng.gen::<f64>()) / f64::from(ny),
+        ]
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Sampler based on the Halton sequence, with the digits of each dimension randomly
+/// permuted in every pixel
+///
+/// Dimensions past the 64th are sampled independently.
+#[derive(Clone, Debug, Default)]
+pub struct HaltonSampler {
+    index: SampleIndex,
+    dim: u64,
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, index: SampleIndex) {
+        self.index = index;
+        self.dim = 0;
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        let SampleIndex {
+            seed, pixel, index, ..
+        } = self.index;
+        let dim = self.dim;
+        self.dim += 1;
+        match PRIMES.get(dim as usize) {
+            Some(&base) => scrambled_radical_inverse(base, index, hash(&[seed, pixel, dim])),
+            None => to_unit(hash(&[seed, pixel, dim, index.into()])),
+        }
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Sampler based on the first two dimensions of the Sobol sequence, which are padded
+/// to higher dimensions by shuffling the order of samples for each pair of dimensions
+///
+/// Samples are randomized with hash-based Owen scrambling, which keeps the Sobol
+/// sequence well-stratified.
+///
+/// Reference: Brent Burley, "Practical Hash-based Owen Scrambling", JCGT 2020.
+#[derive(Clone, Debug, Default)]
+pub struct SobolSampler {
+    index: SampleIndex,
+    dim: u64,
+}
+
+impl SobolSampler {
+    /// Returns the next two dimensions, as scrambled 32-bit integers
+    fn next_pair(&mut self) -> [u32; 2] {
+        let SampleIndex {
+            seed, pixel, index, ..
+        } = self.index;
+        let scramble = hash(&[seed, pixel, self.dim]);
+        self.dim += 2;
+        let index = nested_uniform_scramble(index, scramble as u32);
+        [
+            nested_uniform_scramble(index.reverse_bits(), (scramble >> 32) as u32),
+            nested_uniform_scramble(sobol_1(index), hash(&[scramble]) as u32),
+        ]
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, index: SampleIndex) {
+        self.index = index;
+        self.dim = 0;
+    }
+
+    fn next_1d(&mut self) -> f64 {
+        f64::from(self.next_pair()[0]) / 2f64.powi(32)
+    }
+
+    fn next_2d(&mut self) -> [f64; 2] {
+        let [x, y] = self.next_pair();
+        [f64::from(x) / 2f64.powi(32), f64::from(y) / 2f64.powi(32)]
+    }
+
+    fn clone_sampler(&self) -> Box<dyn Sampler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Hash a sequence of integers to a pseudorandom 64-bit integer, by chaining the
+/// SplitMix64 hash function
+pub(crate) fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(0, |hash, &value| splitmix64(hash ^ splitmix64(value)))
+}
+
+/// The SplitMix64 hash function, which scrambles the bits of its input
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Returns a random number generator that is seeded uniquely for a sample
+fn sample_rng(index: &SampleIndex) -> StdRng {
+    let SampleIndex {
+        seed, pixel, index, ..
+    } = *index;
+    StdRng::seed_from_u64(hash(&[seed, pixel, index.into()]))
+}
+
+/// Convert a 64-bit hash to a float in [0, 1)
+fn to_unit(hash: u64) -> f64 {
+    (hash >> 11) as f64 / 2f64.powi(53)
+}
+
+/// Reflect the digits of an integer in some (prime) base about the radix point,
+/// applying a random affine permutation to the digits in each position
+fn scrambled_radical_inverse(base: u32, mut index: u32, seed: u64) -> f64 {
+    let base64 = u64::from(base);
+    let inv_base = 1.0 / f64::from(base);
+    let mut inv_base_n = 1.0;
+    let mut result = 0.0;
+    let mut state = seed;
+    // Leading zeros of the index are permuted too, up to the precision of the result
+    while inv_base_n > 2f64.powi(-32) {
+        state = splitmix64(state);
+        let scale = 1 + (state >> 32) % (base64 - 1);
+        let shift = (state & 0xffff_ffff) % base64;
+        let digit = (u64::from(index % base) * scale + shift) % base64;
+        inv_base_n *= inv_base;
+        result += digit as f64 * inv_base_n;
+        index /= base;
+    }
+    result.min(1.0 - f64::EPSILON)
+}
+
+/// Second dimension of the Sobol sequence, as a 32-bit binary fraction
+fn sobol_1(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut v = 1 << 31;
+    while index > 0 {
+        if index & 1 == 1 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+/// Owen scrambling of a 32-bit binary fraction, which randomly permutes the digits
+/// after each prefix of digits
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    // Laine-Karras permutation of the reversed bits
+    let mut x = x.reverse_bits().wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+/// Returns element `i` of a pseudorandom permutation of [0, n), chosen by a seed
+///
+/// Reference: Andrew Kensler, "Correlated Multi-Jittered Sampling", 2013.
+fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            return (i.wrapping_add(seed)) % n;
+        }
+    }
+}
+
+/// Map a sample in [0, 1) to an index in [0, n)
+pub(crate) fn sample_index(u: f64, n: usize) -> usize {
+    ((u * n as f64) as usize).min(n - 1)
+}
+
+/// Map a sample in [0, 1)² to a uniformly distributed point on the unit disk, with
+/// Shirley's concentric mapping
+pub(crate) fn sample_disk([u, v]: [f64; 2]) -> [f64; 2] {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return [0.0, 0.0];
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, std::f64::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (
+            b,
+            std::f64::consts::FRAC_PI_2 - std::f64::consts::FRAC_PI_4 * (a / b),
+        )
+    };
+    [r * theta.cos(), r * theta.sin()]
+}
+
+/// Map a sample in [0, 1) to a uniformly distributed point on the unit circle
+pub(crate) fn sample_circle(u: f64) -> [f64; 2] {
+    let (sin, cos) = (2.0 * std::f64::consts::PI * u).sin_cos();
+    [cos, sin]
+}
+
+/// Map a sample in [0, 1)² to a uniformly distributed point on the unit sphere
+pub(crate) fn sample_sphere([u, v]: [f64; 2]) -> [f64; 3] {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let [x, y] = sample_circle(v);
+    [r * x, r * y, z]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samplers_are_stratified() {
+        let samplers: [(Box<dyn Sampler>, bool); 3] = [
+            (Box::new(StratifiedSampler::default()), true),
+            (Box::new(HaltonSampler::default()), false),
+            (Box::new(SobolSampler::default()), true),
+        ];
+        for (mut sampler, stratified_2d) in samplers {
+            for pixel in 0..4 {
+                // One sample in each 1/16 interval, and in each cell of a 4x4 grid
+                let mut intervals = [false; 16];
+                let mut cells = [false; 16];
+                for index in 0..16 {
+                    sampler.start_sample(SampleIndex {
+                        seed: 1,
+                        pixel,
+                        index,
+                        count: 16,
+                    });
+                    let u = sampler.next_1d();
+                    let [x, y] = sampler.next_2d();
+                    assert!([u, x, y].iter().all(|v| (0.0..1.0).contains(v)));
+                    intervals[(u * 16.0) as usize] = true;
+                    cells[(x * 4.0) as usize * 4 + (y * 4.0) as usize] = true;
+                }
+                assert!(intervals.iter().all(|&s| s));
+                assert!(!stratified_2d || cells.iter().all(|&s| s));
+            }
+        }
+    }
+
+    #[test]
+    fn permutation_is_bijective() {
+        for n in [1, 5, 16, 100] {
+            let mut seen = vec![false; n as usize];
+            for i in 0..n {
+                seen[permutation_element(i, n, 12345) as usize] = true;
+            }
+            assert!(seen.iter().all(|&s| s));
+        }
+    }
+}
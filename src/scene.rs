@@ -1,6 +1,9 @@
 use crate::environment::Environment;
+use crate::kdtree::{Bounded, BoundingBox, KdTree};
 use crate::light::Light;
 use crate::object::Object;
+use crate::sampler::Sampler;
+use crate::shape::{HitRecord, Ray, Shape};
 
 /// Object representing a scene that can be rendered
 #[derive(Default)]
@@ -36,6 +39,177 @@ impl SceneAdd<Object> for Scene {
 
 impl SceneAdd<Light> for Scene {
     fn add(&mut self, light: Light) {
+        if let Light::Object(object) = &light {
+            assert!(
+                object.shape.bounds().is_some(),
+                "object lights must have a bounded shape"
+            );
+        }
         self.lights.push(light);
     }
 }
+
+/// Acceleration structure for intersecting rays with all of the objects in a scene
+///
+/// Objects with a finite bounding box are placed into a kd-tree, while objects that
+/// have infinite extent (like planes) are kept in a small list and checked linearly.
+/// This is built once per render, since scenes are mutable between renders.
+pub struct SceneTree<'a> {
+    objects: &'a [Object],
+    tree: KdTree<BoundedObject<'a>>,
+    /// Indices of the objects with infinite extent
+    unbounded: Vec<usize>,
+}
+
+impl<'a> SceneTree<'a> {
+    /// Build an acceleration structure for the objects in a scene
+    pub fn new(scene: &'a Scene) -> Self {
+        let mut bounded = Vec::new();
+        let mut unbounded = Vec::new();
+        for (index, object) in scene.objects.iter().enumerate() {
+            match object.shape.bounds() {
+                Some(bbox) => bounded.push(BoundedObject {
+                    object,
+                    index,
+                    bbox,
+                }),
+                None => unbounded.push(index),
+            }
+        }
+        Self {
+            objects: &scene.objects,
+            tree: KdTree::new(bounded),
+            unbounded,
+        }
+    }
+
+    /// Returns the bounding box of the objects with finite extent, if there are any
+    pub fn bounds(&self) -> Option<BoundingBox> {
+        let bounds = self.tree.bounding_box();
+        if bounds.p_min.x <= bounds.p_max.x {
+            Some(bounds)
+        } else {
+            None
+        }
+    }
+
+    /// Find the closest object hit by a ray, along with its hit record
+    pub fn closest_hit(&self, ray: &Ray, t_min: f64) -> Option<(HitRecord, &'a Object)> {
+        let (h, index) = self.closest_hit_index(ray, t_min)?;
+        Some((h, &self.objects[index]))
+    }
+
+    /// Find the closest object hit by a ray, like `closest_hit`, but returning the index
+    /// of the object in the scene
+    pub fn closest_hit_index(&self, ray: &Ray, t_min: f64) -> Option<(HitRecord, usize)> {
+        let mut h = HitRecord::new();
+        let mut hit = self
+            .tree
+            .intersect_object(ray, t_min, &mut h)
+            .map(|bounded| bounded.index);
+        for &index in &self.unbounded {
+            if self.objects[index].shape.intersect(ray, t_min, &mut h) {
+                hit = Some(index);
+            }
+        }
+        Some((h, hit?))
+    }
+}
+
+/// An object reference paired with its index in the scene and its cached bounding box,
+/// for use in a kd-tree
+struct BoundedObject<'a> {
+    object: &'a Object,
+    index: usize,
+    bbox: BoundingBox,
+}
+
+impl Shape for BoundedObject<'_> {
+    fn intersect(&self, ray: &Ray, t_min: f64, record: &mut HitRecord) -> bool {
+        self.object.shape.intersect(ray, t_min, record)
+    }
+
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        self.object.shape.sample(target, time, sampler)
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        self.object.shape.pdf(target, dir, time)
+    }
+
+    fn sample_area(&self, time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        self.object.shape.sample_area(time, sampler)
+    }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        self.object.shape.pdf_area(ray)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(self.bbox)
+    }
+}
+
+impl Bounded for BoundedObject<'_> {
+    fn bounding_box(&self) -> BoundingBox {
+        self.bbox
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shape::{plane, sphere, Transformable};
+
+    #[test]
+    fn scene_tree_matches_linear_search() {
+        let mut scene = Scene::new();
+        for i in 0..50 {
+            let offset = glm::vec3((i % 7) as f64 - 3.0, (i / 7) as f64 - 3.0, -(i as f64));
+            scene.add(Object::new(
+                sphere().scale(&glm::vec3(0.4, 0.4, 0.4)).translate(&offset),
+            ));
+        }
+        scene.add(Object::new(plane(glm::vec3(0.0, 1.0, 0.0), -2.5)));
+        let tree = SceneTree::new(&scene);
+
+        for i in 0..200 {
+            let angle = i as f64 * 0.05;
+            let ray = Ray {
+                origin: glm::vec3(0.0, 0.0, 10.0),
+                dir: glm::vec3(angle.sin() * 0.3, angle.cos() * 0.3, -1.0).normalize(),
+                time: 0.0,
+            };
+            let mut expected = HitRecord::new();
+            let mut expected_object = None;
+            for object in &scene.objects {
+                if object.shape.intersect(&ray, 1e-12, &mut expected) {
+                    expected_object = Some(object as *const Object);
+                }
+            }
+            let actual = tree.closest_hit(&ray, 1e-12);
+            assert_eq!(
+                actual.as_ref().map(|(_, object)| *object as *const Object),
+                expected_object
+            );
+            if let Some((h, _)) = actual {
+                assert!((h.time - expected.time).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "bounded shape")]
+    fn unbounded_object_lights_are_rejected() {
+        let mut scene = Scene::new();
+        scene.add(Light::Object(Object::new(plane(
+            glm::vec3(0.0, 1.0, 0.0),
+            0.0,
+        ))));
+    }
+}
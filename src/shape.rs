@@ -1,13 +1,15 @@
-use rand::rngs::StdRng;
 use std::sync::Arc;
 
 use crate::kdtree::{Bounded, BoundingBox};
+use crate::sampler::Sampler;
+pub use animated::{Animated, Keyframe};
 pub use cube::Cube;
 pub use mesh::{Mesh, Triangle};
 pub use monomial_surface::MonomialSurface;
 pub use plane::Plane;
 pub use sphere::Sphere;
 
+mod animated;
 mod cube;
 mod mesh;
 mod monomial_surface;
@@ -20,8 +22,37 @@ pub trait Shape: Send + Sync {
     /// `h` if an intersection was found before the current closest one
     fn intersect(&self, ray: &Ray, t_min: f64, record: &mut HitRecord) -> bool;
 
-    /// Sample the shape for a random point on its surface, also returning the normal and PDF
-    fn sample(&self, target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64);
+    /// Sample the shape for a random point on its surface, as seen from a target point,
+    /// also returning the normal and PDF (with respect to solid angle at the target)
+    ///
+    /// The `time` is that of the rays being traced (see `Ray`), for shapes that move.
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64);
+
+    /// Returns the PDF (with respect to solid angle at the target) with which `sample`
+    /// chooses the first point of the shape hit by a ray from the target along `dir`,
+    /// or zero if there is no such point
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64;
+
+    /// Sample the shape for a random point on its surface, independent of any target,
+    /// also returning the normal and PDF (with respect to surface area)
+    fn sample_area(&self, time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64);
+
+    /// Returns the PDF (with respect to surface area) with which `sample_area` chooses
+    /// the first point of the shape hit by a ray, or zero if there is no such point
+    fn pdf_area(&self, ray: &Ray) -> f64;
+
+    /// Returns the shape's bounding box, or `None` if the shape has infinite extent
+    ///
+    /// Shapes that also implement `Bounded` should return `Some` here, which allows them
+    /// to be placed in the scene-level acceleration structure.
+    fn bounds(&self) -> Option<BoundingBox> {
+        None
+    }
 }
 
 impl<T: Shape + ?Sized> Shape for Box<T> {
@@ -29,8 +60,29 @@ impl<T: Shape + ?Sized> Shape for Box<T> {
         self.as_ref().intersect(ray, t_min, record)
     }
 
-    fn sample(&self, target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
-        self.as_ref().sample(target, rng)
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        self.as_ref().sample(target, time, sampler)
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        self.as_ref().pdf(target, dir, time)
+    }
+
+    fn sample_area(&self, time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        self.as_ref().sample_area(time, sampler)
+    }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        self.as_ref().pdf_area(ray)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        self.as_ref().bounds()
     }
 }
 
@@ -39,8 +91,96 @@ impl<T: Shape + ?Sized> Shape for Arc<T> {
         self.as_ref().intersect(ray, t_min, record)
     }
 
-    fn sample(&self, target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
-        self.as_ref().sample(target, rng)
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        self.as_ref().sample(target, time, sampler)
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        self.as_ref().pdf(target, dir, time)
+    }
+
+    fn sample_area(&self, time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        self.as_ref().sample_area(time, sampler)
+    }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        self.as_ref().pdf_area(ray)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        self.as_ref().bounds()
+    }
+}
+
+impl<T: Shape + ?Sized> Shape for &T {
+    fn intersect(&self, ray: &Ray, t_min: f64, record: &mut HitRecord) -> bool {
+        (**self).intersect(ray, t_min, record)
+    }
+
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        (**self).sample(target, time, sampler)
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        (**self).pdf(target, dir, time)
+    }
+
+    fn sample_area(&self, time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        (**self).sample_area(time, sampler)
+    }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        (**self).pdf_area(ray)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        (**self).bounds()
+    }
+}
+
+/// Convert a PDF with respect to surface area at a point (with a given normal) into a
+/// PDF with respect to solid angle, as seen from a target point
+pub fn area_to_solid_angle(
+    pdf: f64,
+    target: &glm::DVec3,
+    point: &glm::DVec3,
+    normal: &glm::DVec3,
+) -> f64 {
+    let disp = point - target;
+    let dist2 = disp.magnitude_squared();
+    let cosine = disp.dot(normal).abs() / dist2.sqrt();
+    if cosine > 0.0 {
+        pdf * dist2 / cosine
+    } else {
+        0.0
+    }
+}
+
+/// Convert a PDF with respect to solid angle at a target point into a PDF with respect
+/// to surface area at a point (with a given normal), the inverse of `area_to_solid_angle`
+pub fn solid_angle_to_area(
+    pdf: f64,
+    target: &glm::DVec3,
+    point: &glm::DVec3,
+    normal: &glm::DVec3,
+) -> f64 {
+    let disp = point - target;
+    let dist2 = disp.magnitude_squared();
+    let cosine = disp.dot(normal).abs() / dist2.sqrt();
+    if dist2 > 0.0 {
+        pdf * cosine / dist2
+    } else {
+        0.0
     }
 }
 
@@ -52,6 +192,10 @@ pub struct Ray {
 
     /// The unit direction of the ray
     pub dir: glm::DVec3,
+
+    /// The moment that the ray is traced at, for motion blur, which is unrelated to the
+    /// parameter of `at` and the time of a `HitRecord` (distances along the ray)
+    pub time: f64,
 }
 
 impl Ray {
@@ -67,6 +211,7 @@ impl Ray {
         Self {
             origin: origin.xyz(),
             dir: dir.xyz(),
+            time: self.time,
         }
     }
 }
@@ -124,6 +269,17 @@ impl<T> Transformed<T> {
     }
 }
 
+impl<T> Transformed<T> {
+    /// Transform a local normal to world space, and divide an area PDF at that point
+    /// by the area scale factor of the transformation
+    fn transform_area_pdf(&self, n: &glm::DVec3, p: f64) -> (glm::DVec3, f64) {
+        let new_normal = (self.normal_transform * n).normalize();
+        let parallelepiped_height = (self.linear * n).dot(&new_normal);
+        let parallelepiped_base = self.scale / parallelepiped_height;
+        (new_normal, p / parallelepiped_base.abs())
+    }
+}
+
 impl<T: Shape> Shape for Transformed<T> {
     fn intersect(&self, ray: &Ray, t_min: f64, record: &mut HitRecord) -> bool {
         let local_ray = ray.apply_transform(&self.inverse_transform);
@@ -136,42 +292,73 @@ impl<T: Shape> Shape for Transformed<T> {
         }
     }
 
-    fn sample(&self, target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
-        let target = (self.inverse_transform * glm::vec4(target.x, target.y, target.z, 1.0)).xyz();
-        let (v, n, p) = self.shape.sample(&target, rng);
-        let new_normal = (self.normal_transform * n).normalize();
-        let parallelepiped_height = (self.linear * n).dot(&new_normal);
-        let parallelepiped_base = self.scale / parallelepiped_height;
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        let local_target =
+            (self.inverse_transform * glm::vec4(target.x, target.y, target.z, 1.0)).xyz();
+        let (v, n, p) = self.shape.sample(&local_target, time, sampler);
+        let p = solid_angle_to_area(p, &local_target, &v, &n);
+        let world_v = (self.transform * glm::vec4(v.x, v.y, v.z, 1.0)).xyz();
+        let (new_normal, p) = self.transform_area_pdf(&n, p);
+        (
+            world_v,
+            new_normal,
+            area_to_solid_angle(p, target, &world_v, &new_normal),
+        )
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        let ray = Ray {
+            origin: *target,
+            dir: *dir,
+            time,
+        };
+        let local_ray = ray.apply_transform(&self.inverse_transform);
+        let mut h = HitRecord::new();
+        if !self.shape.intersect(&local_ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        let local_dir = local_ray.dir.normalize();
+        let p = self.shape.pdf(&local_ray.origin, &local_dir, time);
+        let p = solid_angle_to_area(p, &local_ray.origin, &local_ray.at(h.time), &h.normal);
+        let (new_normal, p) = self.transform_area_pdf(&h.normal, p);
+        area_to_solid_angle(p, target, &ray.at(h.time), &new_normal)
+    }
+
+    fn sample_area(&self, time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        let (v, n, p) = self.shape.sample_area(time, sampler);
+        let (new_normal, p) = self.transform_area_pdf(&n, p);
         (
             (self.transform * glm::vec4(v.x, v.y, v.z, 1.0)).xyz(),
             new_normal,
-            p / parallelepiped_base, // divide PDF by the area scale factor
+            p,
         )
     }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        let local_ray = ray.apply_transform(&self.inverse_transform);
+        let mut h = HitRecord::new();
+        if !self.shape.intersect(&local_ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        let p = self.shape.pdf_area(&local_ray);
+        self.transform_area_pdf(&h.normal, p).1
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        self.shape
+            .bounds()
+            .map(|bbox| bbox.transform(&self.transform))
+    }
 }
 
 impl<T: Bounded> Bounded for Transformed<T> {
     fn bounding_box(&self) -> BoundingBox {
-        // This is not necessarily the best bounding box, but it is correct
-        let BoundingBox { p_min, p_max } = self.shape.bounding_box();
-        let v1 = (self.transform * glm::vec4(p_min.x, p_min.y, p_min.z, 1.0)).xyz();
-        let v2 = (self.transform * glm::vec4(p_min.x, p_min.y, p_max.z, 1.0)).xyz();
-        let v3 = (self.transform * glm::vec4(p_min.x, p_max.y, p_min.z, 1.0)).xyz();
-        let v4 = (self.transform * glm::vec4(p_min.x, p_max.y, p_max.z, 1.0)).xyz();
-        let v5 = (self.transform * glm::vec4(p_max.x, p_min.y, p_min.z, 1.0)).xyz();
-        let v6 = (self.transform * glm::vec4(p_max.x, p_min.y, p_max.z, 1.0)).xyz();
-        let v7 = (self.transform * glm::vec4(p_max.x, p_max.y, p_min.z, 1.0)).xyz();
-        let v8 = (self.transform * glm::vec4(p_max.x, p_max.y, p_max.z, 1.0)).xyz();
-        BoundingBox {
-            p_min: glm::min2(
-                &glm::min4(&v1, &v2, &v3, &v4),
-                &glm::min4(&v5, &v6, &v7, &v8),
-            ),
-            p_max: glm::max2(
-                &glm::max4(&v1, &v2, &v3, &v4),
-                &glm::max4(&v5, &v6, &v7, &v8),
-            ),
-        }
+        self.shape.bounding_box().transform(&self.transform)
     }
 }
 
@@ -303,6 +490,11 @@ pub fn cube() -> Cube {
     Cube
 }
 
+/// Helper function to construct a shape that moves between keyframed transformations
+pub fn animated<T: Shape>(shape: T, keyframes: Vec<Keyframe>) -> Animated<T> {
+    Animated::new(shape, keyframes)
+}
+
 /// Helper function to construct a simple polygon made from triangles
 pub fn polygon(verts: &[glm::DVec3]) -> Mesh {
     let mut tris = Vec::new();
@@ -311,3 +503,95 @@ pub fn polygon(verts: &[glm::DVec3]) -> Mesh {
     }
     Mesh::new(tris)
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::sampler::IndependentSampler;
+
+    #[test]
+    fn sample_pdf_matches() {
+        let shapes: Vec<Box<dyn Shape>> = vec![
+            Box::new(sphere().scale(&glm::vec3(1.0, 2.0, 0.5))),
+            Box::new(cube().rotate_y(0.3).translate(&glm::vec3(0.0, 1.0, 0.0))),
+            Box::new(polygon(&[
+                glm::vec3(-1.0, 0.0, -1.0),
+                glm::vec3(1.0, 0.0, -1.0),
+                glm::vec3(1.0, 0.0, 1.0),
+                glm::vec3(-1.0, 0.0, 1.0),
+            ])),
+        ];
+        let target = glm::vec3(0.5, 4.0, 3.0);
+        let mut sampler = IndependentSampler::from(StdRng::seed_from_u64(0));
+        for shape in &shapes {
+            for _ in 0..100 {
+                let (v, _, pdf) = shape.sample(&target, 0.0, &mut sampler);
+                let dir = (v - target).normalize();
+                let mut h = HitRecord::new();
+                let ray = Ray {
+                    origin: target,
+                    dir,
+                    time: 0.0,
+                };
+                if shape.intersect(&ray, 0.0, &mut h) && glm::distance(&ray.at(h.time), &v) < 1e-6 {
+                    // The sampled point is the first one visible along its direction
+                    assert!((shape.pdf(&target, &dir, 0.0) - pdf).abs() < 1e-6 * pdf);
+                }
+
+                let (v, _, pdf) = shape.sample_area(0.0, &mut sampler);
+                let ray = Ray {
+                    origin: target,
+                    dir: (v - target).normalize(),
+                    time: 0.0,
+                };
+                if shape.intersect(&ray, 0.0, &mut h) && glm::distance(&ray.at(h.time), &v) < 1e-6 {
+                    assert!((shape.pdf_area(&ray) - pdf).abs() < 1e-6 * pdf);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn animated_shapes_move() {
+        let transform = glm::translate(&glm::identity(), &glm::vec3(1.0, -2.0, 3.0))
+            * glm::rotate(&glm::identity(), 2.5, &glm::vec3(1.0, 2.0, -1.0))
+            * glm::scale(&glm::identity(), &glm::vec3(0.5, 2.0, 1.5));
+        let keyframe = Keyframe::new(0.0, transform);
+        assert!((keyframe.matrix() - transform).abs().max() < 1e-9);
+
+        let shape = animated(
+            sphere(),
+            vec![
+                Keyframe::new(0.0, glm::identity()),
+                Keyframe::new(
+                    1.0,
+                    glm::translate(&glm::identity(), &glm::vec3(4.0, 0.0, 0.0)),
+                ),
+            ],
+        );
+        let mut sampler = IndependentSampler::from(StdRng::seed_from_u64(0));
+        for &(time, x) in &[(-1.0, 0.0), (0.25, 1.0), (0.5, 2.0), (2.0, 4.0)] {
+            let ray = Ray {
+                origin: glm::vec3(x, 0.0, 5.0),
+                dir: glm::vec3(0.0, 0.0, -1.0),
+                time,
+            };
+            let mut h = HitRecord::new();
+            assert!(shape.intersect(&ray, 0.0, &mut h));
+            assert!((h.time - 4.0).abs() < 1e-9);
+
+            // Sampled points are on the shape where it is at the same time
+            let center = glm::vec3(x, 0.0, 0.0);
+            let (v, _, pdf) = shape.sample(&ray.origin, time, &mut sampler);
+            assert!((glm::distance(&v, &center) - 1.0).abs() < 1e-9);
+            let dir = (v - ray.origin).normalize();
+            assert!((shape.pdf(&ray.origin, &dir, time) - pdf).abs() < 1e-6 * pdf);
+            let (v, _, _) = shape.sample_area(time, &mut sampler);
+            assert!((glm::distance(&v, &center) - 1.0).abs() < 1e-9);
+        }
+        let bbox = shape.bounds().unwrap();
+        assert!(bbox.p_min.x <= -1.0 && bbox.p_max.x >= 5.0);
+    }
+}
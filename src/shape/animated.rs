@@ -0,0 +1,205 @@
+use super::{HitRecord, Ray, Shape, Transformed};
+use crate::kdtree::{Bounded, BoundingBox};
+use crate::sampler::Sampler;
+
+/// Number of samples taken along each segment of motion to find its bounding box
+const BOUNDS_SAMPLES: usize = 16;
+
+/// A transformation at a moment in time, decomposed so that it can be interpolated
+///
+/// Transformations are split into a translation, a rotation and a scale (which may also
+/// shear), applied in the reverse order. Interpolating these parts separately, rather
+/// than the entries of the matrix, keeps spinning objects from shrinking midway.
+#[derive(Copy, Clone, Debug)]
+pub struct Keyframe {
+    /// Time of the keyframe, in the same units as the camera's shutter interval
+    pub time: f64,
+    translation: glm::DVec3,
+    rotation: glm::DQuat,
+    scale: glm::DMat3,
+}
+
+impl Keyframe {
+    /// Construct a keyframe from an affine transformation, which must be invertible
+    pub fn new(time: f64, transform: glm::DMat4) -> Self {
+        let translation = transform.column(3).xyz();
+        let linear = glm::mat4_to_mat3(&transform);
+
+        // Polar decomposition by averaging with the inverse transpose, which converges
+        // to the closest rotation (or reflection) to the linear part
+        let mut rotation = linear;
+        for _ in 0..100 {
+            let next = (rotation + glm::inverse_transpose(rotation)) * 0.5;
+            let change = (next - rotation).abs().max();
+            rotation = next;
+            if change < 1e-14 {
+                break;
+            }
+        }
+        if rotation.determinant() < 0.0 {
+            // Reflections are left in the scale, so that the rotation is proper
+            rotation = -rotation;
+        }
+        let scale = rotation.transpose() * linear;
+        Self {
+            time,
+            translation,
+            rotation: glm::mat3_to_quat(&rotation),
+            scale,
+        }
+    }
+
+    /// Returns the transformation at this keyframe, as a homogeneous matrix
+    pub fn matrix(&self) -> glm::DMat4 {
+        let linear = glm::quat_to_mat3(&self.rotation) * self.scale;
+        glm::translate(&glm::identity(), &self.translation) * glm::mat3_to_mat4(&linear)
+    }
+
+    /// Interpolate between two keyframes, where `t` is between 0 and 1
+    fn lerp(&self, other: &Keyframe, t: f64) -> Keyframe {
+        Keyframe {
+            time: self.time * (1.0 - t) + other.time * t,
+            translation: glm::lerp(&self.translation, &other.translation, t),
+            rotation: slerp(&self.rotation, &other.rotation, t),
+            scale: self.scale * (1.0 - t) + other.scale * t,
+        }
+    }
+}
+
+/// Spherical linear interpolation between rotations, along the shorter path
+fn slerp(a: &glm::DQuat, b: &glm::DQuat, t: f64) -> glm::DQuat {
+    let mut cos_theta = glm::quat_dot(a, b);
+    let b = if cos_theta < 0.0 {
+        cos_theta = -cos_theta;
+        -b
+    } else {
+        *b
+    };
+    if cos_theta > 0.9995 {
+        // Nearly parallel, so normalized linear interpolation is accurate and stable
+        return glm::quat_normalize(&(a * (1.0 - t) + b * t));
+    }
+    let theta = cos_theta.acos();
+    let sin_theta = theta.sin();
+    a * (((1.0 - t) * theta).sin() / sin_theta) + b * ((t * theta).sin() / sin_theta)
+}
+
+/// A shape that moves over time, following keyframed transformations
+///
+/// Rays hit the shape where it is at their time, between the keyframes before and
+/// after. Before the first and after the last keyframe, the shape stays still. Points
+/// sampled on the shape are also placed where it is at the given time, so animated
+/// shapes can be used as object lights.
+pub struct Animated<T> {
+    shape: T,
+    keyframes: Vec<Keyframe>,
+}
+
+impl<T> Animated<T> {
+    /// Construct a new animated shape, from a nonempty list of keyframes
+    pub fn new(shape: T, mut keyframes: Vec<Keyframe>) -> Self {
+        assert!(!keyframes.is_empty(), "animated shape has no keyframes");
+        keyframes.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        Self { shape, keyframes }
+    }
+
+    /// Returns the transformation of the shape at a given time
+    pub fn transform_at(&self, time: f64) -> glm::DMat4 {
+        self.keyframe_at(time).matrix()
+    }
+
+    fn keyframe_at(&self, time: f64) -> Keyframe {
+        let i = self.keyframes.partition_point(|k| k.time <= time);
+        if i == 0 {
+            self.keyframes[0]
+        } else if i == self.keyframes.len() {
+            self.keyframes[i - 1]
+        } else {
+            let (a, b) = (&self.keyframes[i - 1], &self.keyframes[i]);
+            a.lerp(b, (time - a.time) / (b.time - a.time))
+        }
+    }
+
+    /// The shape, as placed at a given time
+    fn at(&self, time: f64) -> Transformed<&T> {
+        Transformed::new(&self.shape, self.transform_at(time))
+    }
+
+    /// Returns a bounding box over all positions of a box (in local coordinates)
+    fn motion_bounds(&self, bbox: &BoundingBox) -> BoundingBox {
+        let corners = |transform: &glm::DMat4| {
+            let BoundingBox { p_min, p_max } = bbox;
+            let mut corners = Vec::with_capacity(8);
+            for &x in &[p_min.x, p_max.x] {
+                for &y in &[p_min.y, p_max.y] {
+                    for &z in &[p_min.z, p_max.z] {
+                        corners.push((transform * glm::vec4(x, y, z, 1.0)).xyz());
+                    }
+                }
+            }
+            corners
+        };
+
+        let mut result = bbox.transform(&self.keyframes[0].matrix());
+        for pair in self.keyframes.windows(2) {
+            let mut prev = corners(&pair[0].matrix());
+            for i in 1..=BOUNDS_SAMPLES {
+                let transform = pair[0]
+                    .lerp(&pair[1], i as f64 / BOUNDS_SAMPLES as f64)
+                    .matrix();
+                let next = corners(&transform);
+                // Between samples, corners stay within the distance they moved
+                let pad = prev
+                    .iter()
+                    .zip(&next)
+                    .map(|(a, b)| glm::distance(a, b))
+                    .fold(0.0, f64::max);
+                let pad = glm::vec3(pad, pad, pad);
+                let sample = bbox.transform(&transform);
+                result = result.merge(&BoundingBox {
+                    p_min: sample.p_min - pad,
+                    p_max: sample.p_max + pad,
+                });
+                prev = next;
+            }
+        }
+        result
+    }
+}
+
+impl<T: Shape> Shape for Animated<T> {
+    fn intersect(&self, ray: &Ray, t_min: f64, record: &mut HitRecord) -> bool {
+        self.at(ray.time).intersect(ray, t_min, record)
+    }
+
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        self.at(time).sample(target, time, sampler)
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        self.at(time).pdf(target, dir, time)
+    }
+
+    fn sample_area(&self, time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        self.at(time).sample_area(time, sampler)
+    }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        self.at(ray.time).pdf_area(ray)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        self.shape.bounds().map(|bbox| self.motion_bounds(&bbox))
+    }
+}
+
+impl<T: Bounded> Bounded for Animated<T> {
+    fn bounding_box(&self) -> BoundingBox {
+        self.motion_bounds(&self.shape.bounding_box())
+    }
+}
@@ -1,7 +1,6 @@
-use rand::{distributions::Uniform, rngs::StdRng, Rng};
-
-use super::{HitRecord, Ray, Shape};
+use super::{area_to_solid_angle, HitRecord, Ray, Shape};
 use crate::kdtree::{Bounded, BoundingBox};
+use crate::sampler::{sample_index, Sampler};
 
 /// A unit cube centered at the origin
 #[derive(Copy, Clone)]
@@ -71,10 +70,32 @@ impl Shape for Cube {
         }
     }
 
-    fn sample(&self, _target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
-        let a = rng.gen::<f64>() - 0.5;
-        let b = rng.gen::<f64>() - 0.5;
-        let (v, n) = match rng.sample(Uniform::from(0..6)) {
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        let (v, n, pdf) = self.sample_area(time, sampler);
+        (v, n, area_to_solid_angle(pdf, target, &v, &n))
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        let ray = Ray {
+            origin: *target,
+            dir: *dir,
+            time,
+        };
+        let mut h = HitRecord::new();
+        if !self.intersect(&ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        area_to_solid_angle(1.0 / 6.0, target, &ray.at(h.time), &h.normal)
+    }
+
+    fn sample_area(&self, _time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        let [a, b] = sampler.next_2d().map(|x| x - 0.5);
+        let (v, n) = match sample_index(sampler.next_1d(), 6) {
             0 => (glm::vec3(a, b, 0.5), glm::vec3(0.0, 0.0, 1.0)),
             1 => (glm::vec3(a, b, -0.5), glm::vec3(0.0, 0.0, -1.0)),
             2 => (glm::vec3(a, 0.5, b), glm::vec3(0.0, 1.0, 0.0)),
@@ -85,4 +106,16 @@ impl Shape for Cube {
         };
         (v, n, 1.0 / 6.0)
     }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        let mut h = HitRecord::new();
+        if !self.intersect(ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        1.0 / 6.0
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(self.bounding_box())
+    }
 }
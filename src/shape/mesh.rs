@@ -1,7 +1,6 @@
-use rand::{rngs::StdRng, Rng};
-
-use super::{HitRecord, Ray, Shape};
+use super::{area_to_solid_angle, HitRecord, Ray, Shape};
 use crate::kdtree::{Bounded, BoundingBox, KdTree};
+use crate::sampler::Sampler;
 
 /// A triangle with three vertices and three normals
 #[derive(Copy, Clone)]
@@ -36,6 +35,13 @@ impl Triangle {
     }
 }
 
+impl Triangle {
+    /// Returns the surface area of the triangle
+    pub fn area(&self) -> f64 {
+        0.5 * (self.v2 - self.v1).cross(&(self.v3 - self.v1)).magnitude()
+    }
+}
+
 impl Bounded for Triangle {
     fn bounding_box(&self) -> BoundingBox {
         BoundingBox {
@@ -81,21 +87,57 @@ impl Shape for Triangle {
         }
     }
 
-    fn sample(&self, _target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
-        let mut u: f64 = rng.gen();
-        let mut v: f64 = rng.gen();
-        while u + v > 1.0 {
-            u = rng.gen();
-            v = rng.gen();
-        }
-        let w = 1.0 - u - v;
-        let area: f64 = 0.5 * (self.v2 - self.v1).cross(&(self.v3 - self.v1)).magnitude();
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        let (point, normal, pdf) = self.sample_area(time, sampler);
         (
-            u * self.v1 + v * self.v2 + w * self.v3,
-            (u * self.n1 + v * self.n2 + w * self.n3).normalize(),
-            area.recip(),
+            point,
+            normal,
+            area_to_solid_angle(pdf, target, &point, &normal),
         )
     }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        let ray = Ray {
+            origin: *target,
+            dir: *dir,
+            time,
+        };
+        let mut h = HitRecord::new();
+        if !self.intersect(&ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        area_to_solid_angle(self.area().recip(), target, &ray.at(h.time), &h.normal)
+    }
+
+    fn sample_area(&self, _time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        let [mut u, mut v] = sampler.next_2d();
+        if u + v > 1.0 {
+            // Reflect into the lower triangle of the unit square
+            u = 1.0 - u;
+            v = 1.0 - v;
+        }
+        let w = 1.0 - u - v;
+        let point = u * self.v1 + v * self.v2 + w * self.v3;
+        let normal = (u * self.n1 + v * self.n2 + w * self.n3).normalize();
+        (point, normal, self.area().recip())
+    }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        let mut h = HitRecord::new();
+        if !self.intersect(ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        self.area().recip()
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(self.bounding_box())
+    }
 }
 
 /// A triangle mesh, stored using a kd-tree
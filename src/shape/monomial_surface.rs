@@ -1,8 +1,9 @@
-use rand::{rngs::StdRng, Rng};
-use rand_distr::UnitCircle;
-
-use super::{HitRecord, Ray, Shape};
+use super::{area_to_solid_angle, HitRecord, Ray, Shape};
 use crate::kdtree::{Bounded, BoundingBox};
+use crate::sampler::{sample_circle, Sampler};
+
+// Surface area of one side, only valid for exp = 4
+const AREA: f64 = 6.3406654362; // thanks WolframAlpha, hope I have set up the integrals correctly
 
 /// Represents a glass-shaped surface with height and exp parameters
 ///
@@ -105,20 +106,55 @@ impl Shape for MonomialSurface {
         true
     }
 
-    fn sample(&self, _target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
-        let [x, z]: [f64; 2] = rng.sample(UnitCircle);
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        let (pos, normal, pdf) = self.sample_area(time, sampler);
+        (pos, normal, area_to_solid_angle(pdf, target, &pos, &normal))
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        let ray = Ray {
+            origin: *target,
+            dir: *dir,
+            time,
+        };
+        let mut h = HitRecord::new();
+        if !self.intersect(&ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        area_to_solid_angle(1. / (2. * AREA), target, &ray.at(h.time), &h.normal)
+    }
+
+    fn sample_area(&self, _time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        let [u, v] = sampler.next_2d();
+        let [x, z] = sample_circle(u);
         let pos = glm::vec3(x, self.height * (x * x + z * z).powf(self.exp / 2.), z);
         let mut normal = glm::normalize(&glm::vec3(
             self.height * 4. * pos.x * (pos.x * pos.x + pos.z * pos.z),
             -1.,
             self.height * 4. * pos.z * (pos.x * pos.x + pos.z * pos.z),
         ));
-        // Again, only valid for exp = 4
-        const AREA: f64 = 6.3406654362; // thanks WolframAlpha, hope I have set up the integrals correctly
-        if rng.gen::<bool>() {
+        if v < 0.5 {
             normal = -normal;
         }
-        (pos, normal, 1. / (2. * AREA)) // 2 * AREA because there are two sides
+        // 2 * AREA because there are two sides
+        (pos, normal, 1. / (2. * AREA))
+    }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        let mut h = HitRecord::new();
+        if !self.intersect(ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        1. / (2. * AREA)
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(self.bounding_box())
     }
 }
 
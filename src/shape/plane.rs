@@ -1,6 +1,5 @@
-use rand::rngs::StdRng;
-
 use super::{HitRecord, Ray, Shape};
+use crate::sampler::Sampler;
 
 /// A plane represented by the linear equation x • normal = value
 #[derive(Copy, Clone)]
@@ -31,7 +30,31 @@ impl Shape for Plane {
         }
     }
 
-    fn sample(&self, _target: &glm::DVec3, _rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
-        unimplemented!()
+    // An unbounded plane has no finite area to sample uniformly, so it cannot be an
+    // object light, and every point on it is chosen with zero probability
+    fn sample(
+        &self,
+        _target: &glm::DVec3,
+        time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        self.sample_area(time, sampler)
+    }
+
+    fn pdf(&self, _target: &glm::DVec3, _dir: &glm::DVec3, _time: f64) -> f64 {
+        0.0
+    }
+
+    fn sample_area(&self, _time: f64, _sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        let normal = self.normal.normalize();
+        (
+            self.normal * self.value / self.normal.norm_squared(),
+            normal,
+            0.0,
+        )
+    }
+
+    fn pdf_area(&self, _ray: &Ray) -> f64 {
+        0.0
     }
 }
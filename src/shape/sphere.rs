@@ -1,8 +1,6 @@
-use rand::{rngs::StdRng, Rng};
-use rand_distr::UnitDisc;
-
-use super::{HitRecord, Ray, Shape};
+use super::{area_to_solid_angle, HitRecord, Ray, Shape};
 use crate::kdtree::{Bounded, BoundingBox};
+use crate::sampler::{sample_disk, sample_sphere, Sampler};
 
 /// A unit sphere centered at the origin
 #[derive(Copy, Clone)]
@@ -49,8 +47,13 @@ impl Shape for Sphere {
     /// Currently, this implementation just generates a random point in the hemisphere facing
     /// the target point, weighted by the cosine. This isn't the most sophisticated technique,
     /// since you can sample the solid angle exactly, but it's pretty good.
-    fn sample(&self, target: &glm::DVec3, rng: &mut StdRng) -> (glm::DVec3, glm::DVec3, f64) {
-        let [x, y]: [f64; 2] = rng.sample(UnitDisc);
+    fn sample(
+        &self,
+        target: &glm::DVec3,
+        _time: f64,
+        sampler: &mut dyn Sampler,
+    ) -> (glm::DVec3, glm::DVec3, f64) {
+        let [x, y] = sample_disk(sampler.next_2d());
         let z = (1.0 - x * x - y * y).sqrt();
         let n = target.normalize();
         let n1 = if n.x.is_normal() {
@@ -60,7 +63,41 @@ impl Shape for Sphere {
         };
         let n2 = n1.cross(&n);
         let p = x * n1 + y * n2 + z * n;
-        (p, p, z * std::f64::consts::FRAC_1_PI)
+        let pdf = z * std::f64::consts::FRAC_1_PI;
+        (p, p, area_to_solid_angle(pdf, target, &p, &p))
+    }
+
+    fn pdf(&self, target: &glm::DVec3, dir: &glm::DVec3, time: f64) -> f64 {
+        let ray = Ray {
+            origin: *target,
+            dir: *dir,
+            time,
+        };
+        let mut h = HitRecord::new();
+        if !self.intersect(&ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        let p = ray.at(h.time);
+        let pdf = p.dot(&target.normalize()).max(0.0) * std::f64::consts::FRAC_1_PI;
+        area_to_solid_angle(pdf, target, &p, &p)
+    }
+
+    fn sample_area(&self, _time: f64, sampler: &mut dyn Sampler) -> (glm::DVec3, glm::DVec3, f64) {
+        let [x, y, z] = sample_sphere(sampler.next_2d());
+        let p = glm::vec3(x, y, z);
+        (p, p, 0.25 * std::f64::consts::FRAC_1_PI)
+    }
+
+    fn pdf_area(&self, ray: &Ray) -> f64 {
+        let mut h = HitRecord::new();
+        if !self.intersect(ray, 0.0, &mut h) {
+            return 0.0;
+        }
+        0.25 * std::f64::consts::FRAC_1_PI
+    }
+
+    fn bounds(&self) -> Option<BoundingBox> {
+        Some(self.bounding_box())
     }
 }
 
@@ -0,0 +1,474 @@
+use std::sync::OnceLock;
+
+use rayon::prelude::*;
+
+use crate::color::{Color, Primaries};
+use crate::material::Material;
+
+/// Shortest wavelength sampled in spectral rendering, in nanometers
+pub const MIN_WAVELENGTH: f64 = 360.0;
+
+/// Longest wavelength sampled in spectral rendering, in nanometers
+pub const MAX_WAVELENGTH: f64 = 830.0;
+
+/// Wavelength of the helium d line, which refractive indices are usually quoted at
+const D_LINE: f64 = 587.6;
+
+/// Wavelengths of the hydrogen F and C lines, which define the Abbe number
+const F_LINE: f64 = 486.1;
+const C_LINE: f64 = 656.3;
+
+/// Number of wavelengths traced together along each path
+pub(crate) const NUM_WAVELENGTHS: usize = 3;
+
+/// Resolution of the table of sigmoid coefficients in each dimension
+const TABLE_RES: usize = 32;
+
+/// Spacing of the wavelengths that spectra are integrated over, in nanometers
+const STEP: f64 = 5.0;
+
+/// Maximum number of Gauss-Newton iterations when fitting a spectrum to a color
+const MAX_ITERATIONS: usize = 30;
+
+/// Distance from 0 and 1 of the colors that spectra are fitted to
+const FIT_MARGIN: f64 = 1e-3;
+
+/// Wavelength-dependent index of refraction of a transparent material, which causes
+/// dispersion in spectral rendering
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dispersion {
+    /// Cauchy's equation, n(λ) = a + b / λ², with λ in micrometers
+    Cauchy {
+        /// Constant term, which is the index at long wavelengths
+        a: f64,
+        /// Coefficient of the inverse squared wavelength
+        b: f64,
+    },
+
+    /// Sellmeier equation, n(λ)² = 1 + Σ b λ² / (λ² - c), with λ in micrometers
+    Sellmeier {
+        /// Strengths of the absorption resonances
+        b: [f64; 3],
+        /// Squared wavelengths of the absorption resonances, in square micrometers
+        c: [f64; 3],
+    },
+}
+
+impl Dispersion {
+    /// Schott N-BK7, a borosilicate crown glass used for most lenses and prisms
+    pub const BK7: Self = Self::Sellmeier {
+        b: [1.039_612_12, 0.231_792_344, 1.010_469_45],
+        c: [0.006_000_698_67, 0.020_017_914_4, 103.560_653],
+    };
+
+    /// Fused silica, which has low dispersion
+    pub const FUSED_SILICA: Self = Self::Sellmeier {
+        b: [0.696_166_3, 0.407_942_6, 0.897_479_4],
+        c: [0.004_679_148, 0.013_512_063, 97.934_003],
+    };
+
+    /// Schott SF11, a dense flint glass with high dispersion
+    pub const SF11: Self = Self::Sellmeier {
+        b: [1.737_596_95, 0.313_747_346, 1.898_781_01],
+        c: [0.013_188_707, 0.062_306_814_2, 155.236_29],
+    };
+
+    /// Cauchy's equation fitted to an index of refraction at the helium d line
+    /// (587.6 nm) and an Abbe number, which is lower for more dispersive materials
+    ///
+    /// Example of use: `Dispersion::abbe(1.5168, 64.17)` approximates BK7 glass.
+    pub fn abbe(index: f64, abbe_number: f64) -> Self {
+        let inverse_square = |wavelength: f64| (1000.0 / wavelength).powi(2);
+        let b = (index - 1.0) / (abbe_number * (inverse_square(F_LINE) - inverse_square(C_LINE)));
+        Self::Cauchy {
+            a: index - b * inverse_square(D_LINE),
+            b,
+        }
+    }
+
+    /// Index of refraction at a wavelength, in nanometers
+    pub fn index(&self, wavelength: f64) -> f64 {
+        let l2 = (wavelength / 1000.0).powi(2);
+        match self {
+            Self::Cauchy { a, b } => a + b / l2,
+            Self::Sellmeier { b, c } => {
+                let sum: f64 = b.iter().zip(c).map(|(b, c)| b * l2 / (l2 - c)).sum();
+                (1.0 + sum).sqrt()
+            }
+        }
+    }
+
+    /// Index of refraction at the helium d line, used when rendering in RGB
+    pub fn d_line_index(&self) -> f64 {
+        self.index(D_LINE)
+    }
+}
+
+/// Wavelengths traced together along a path, with hero wavelength sampling
+///
+/// The first wavelength (the hero) is sampled uniformly from the visible range, and the
+/// others are spaced equally after it, wrapping around the range. Spectral values at
+/// the wavelengths are stored in the components of a `Color`, so that materials can be
+/// evaluated at all of them at once. See "Hero Wavelength Spectral Sampling" (Wilkie et
+/// al. 2014) for details.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Wavelengths([f64; NUM_WAVELENGTHS]);
+
+impl Wavelengths {
+    /// Sample wavelengths from a uniform random number
+    pub(crate) fn sample(u: f64) -> Self {
+        let range = MAX_WAVELENGTH - MIN_WAVELENGTH;
+        let mut wavelengths = [0.0; NUM_WAVELENGTHS];
+        for (i, wavelength) in wavelengths.iter_mut().enumerate() {
+            let offset = (u + i as f64 / NUM_WAVELENGTHS as f64).fract();
+            *wavelength = MIN_WAVELENGTH + offset * range;
+        }
+        Self(wavelengths)
+    }
+
+    /// The hero wavelength, which decides directions that depend on wavelength
+    pub(crate) fn hero(&self) -> f64 {
+        self.0[0]
+    }
+
+    /// Reflectance at each wavelength of a color in a working space, clamped to [0, 1]
+    pub(crate) fn reflectance(&self, color: &Color, primaries: Primaries) -> Color {
+        let c = SpectrumTable::get(primaries).coefficients(color);
+        glm::vec3(
+            sigmoid_spectrum(&c, self.0[0]),
+            sigmoid_spectrum(&c, self.0[1]),
+            sigmoid_spectrum(&c, self.0[2]),
+        )
+    }
+
+    /// Radiance at each wavelength of an emitted color in a working space, whose white
+    /// is the D65 illuminant
+    pub(crate) fn illuminant(&self, color: &Color, primaries: Primaries) -> Color {
+        // Scaling to half of the maximum leaves room for saturated colors, and the
+        // spectrum is scaled back up afterwards
+        let scale = 2.0 * color.max();
+        if scale <= 0.0 {
+            return glm::vec3(0.0, 0.0, 0.0);
+        }
+        let reflectance = self.reflectance(&(color / scale), primaries);
+        let d65 = glm::vec3(d65(self.0[0]), d65(self.0[1]), d65(self.0[2]));
+        reflectance.component_mul(&d65) * scale
+    }
+
+    /// A material with its colors and index of refraction at these wavelengths, where
+    /// the index of a dispersive material is taken at the hero wavelength
+    pub(crate) fn material(&self, material: &Material, primaries: Primaries) -> Material {
+        Material {
+            color: self.reflectance(&material.color, primaries),
+            index: material
+                .dispersion
+                .map_or(material.index, |dispersion| dispersion.index(self.hero())),
+            ..*material
+        }
+    }
+
+    /// Convert radiance at these wavelengths to a color in a working space, as a Monte
+    /// Carlo estimate over the visible range
+    pub(crate) fn integrate(&self, radiance: &Color, primaries: Primaries) -> Color {
+        let table = SpectrumTable::get(primaries);
+        let xyz: glm::DVec3 = (0..NUM_WAVELENGTHS)
+            .map(|i| cie_xyz(self.0[i]) * radiance[i])
+            .sum();
+        let pdf = NUM_WAVELENGTHS as f64 / (MAX_WAVELENGTH - MIN_WAVELENGTH);
+        table.from_xyz * xyz / (pdf * table.white_luminance)
+    }
+}
+
+/// Table of coefficients of sigmoid-polynomial reflectance spectra for colors in a
+/// working space, from "A Low-Dimensional Function Space for Efficient Spectral
+/// Upsampling" (Jakob and Hanika 2019)
+///
+/// Colors are parameterized by their largest component `z` and the ratios of the other
+/// components to it. Coefficients are fitted to each entry by Gauss-Newton iteration,
+/// starting from the fit of its neighbor, and interpolated between entries.
+struct SpectrumTable {
+    /// Values of `z` at each entry, which are denser near black and white
+    scale: [f64; TABLE_RES],
+
+    /// Coefficients, indexed by largest component, `z`, and the two ratios
+    coefficients: Vec<[f64; 3]>,
+
+    /// Conversion from CIE XYZ to the working space
+    from_xyz: glm::DMat3,
+
+    /// Luminance of the D65 illuminant, which is white in the working space
+    white_luminance: f64,
+}
+
+impl SpectrumTable {
+    /// Get the table for a working space, which is computed on first use
+    fn get(primaries: Primaries) -> &'static Self {
+        static TABLES: [OnceLock<SpectrumTable>; 4] = [
+            OnceLock::new(),
+            OnceLock::new(),
+            OnceLock::new(),
+            OnceLock::new(),
+        ];
+        TABLES[primaries as usize].get_or_init(|| Self::new(primaries))
+    }
+
+    fn new(primaries: Primaries) -> Self {
+        let from_xyz = primaries
+            .to_xyz()
+            .try_inverse()
+            .expect("Color space matrix should be invertible");
+        let wavelengths: Vec<_> = (0..)
+            .map(|i| MIN_WAVELENGTH + (f64::from(i) + 0.5) * STEP)
+            .take_while(|&wavelength| wavelength < MAX_WAVELENGTH)
+            .collect();
+        let white_luminance: f64 = wavelengths
+            .iter()
+            .map(|&wavelength| d65(wavelength) * cie_xyz(wavelength).y * STEP)
+            .sum();
+        // Color of a unit reflectance at each wavelength, lit by the white illuminant
+        let weights: Vec<_> = wavelengths
+            .iter()
+            .map(|&wavelength| {
+                let xyz = cie_xyz(wavelength) * d65(wavelength) * STEP / white_luminance;
+                (normalize_wavelength(wavelength), from_xyz * xyz)
+            })
+            .collect();
+
+        let mut scale = [0.0; TABLE_RES];
+        for (k, z) in scale.iter_mut().enumerate() {
+            *z = smoothstep(smoothstep(k as f64 / (TABLE_RES - 1) as f64));
+        }
+        let columns: Vec<_> = (0..3 * TABLE_RES * TABLE_RES)
+            .into_par_iter()
+            .map(|index| {
+                let (l, j, i) = (
+                    index / (TABLE_RES * TABLE_RES),
+                    index / TABLE_RES % TABLE_RES,
+                    index % TABLE_RES,
+                );
+                let x = i as f64 / (TABLE_RES - 1) as f64;
+                let y = j as f64 / (TABLE_RES - 1) as f64;
+                let mut column = [[0.0; 3]; TABLE_RES];
+                // Fit outward from a medium brightness, where fitting converges easily
+                let start = TABLE_RES / 5;
+                let ranges = [
+                    (start..TABLE_RES).collect::<Vec<_>>(),
+                    (0..start).rev().collect(),
+                ];
+                for range in ranges {
+                    let mut c = [0.0; 3];
+                    for k in range {
+                        let z = scale[k];
+                        let mut rgb = glm::vec3(0.0, 0.0, 0.0);
+                        rgb[l] = z;
+                        rgb[(l + 1) % 3] = x * z;
+                        rgb[(l + 2) % 3] = y * z;
+                        fit(&weights, &rgb, &mut c);
+                        column[k] = c;
+                    }
+                }
+                column
+            })
+            .collect();
+
+        let mut coefficients = vec![[0.0; 3]; 3 * TABLE_RES * TABLE_RES * TABLE_RES];
+        for (index, column) in columns.into_iter().enumerate() {
+            let (l, rest) = (
+                index / (TABLE_RES * TABLE_RES),
+                index % (TABLE_RES * TABLE_RES),
+            );
+            for (k, &c) in column.iter().enumerate() {
+                coefficients[(l * TABLE_RES + k) * TABLE_RES * TABLE_RES + rest] = c;
+            }
+        }
+        Self {
+            scale,
+            coefficients,
+            from_xyz,
+            white_luminance,
+        }
+    }
+
+    /// Interpolate the coefficients of the spectrum of a color, clamped to [0, 1]
+    fn coefficients(&self, color: &Color) -> [f64; 3] {
+        let rgb = color.map(|c| c.clamp(0.0, 1.0));
+        let l = rgb.imax();
+        let z = rgb[l];
+        if z == 0.0 {
+            return [0.0, 0.0, f64::NEG_INFINITY];
+        }
+        let max_index = (TABLE_RES - 1) as f64;
+        let x = rgb[(l + 1) % 3] / z * max_index;
+        let y = rgb[(l + 2) % 3] / z * max_index;
+        let xi = (x as usize).min(TABLE_RES - 2);
+        let yi = (y as usize).min(TABLE_RES - 2);
+        let zi = self
+            .scale
+            .partition_point(|&s| s <= z)
+            .clamp(1, TABLE_RES - 1)
+            - 1;
+        let (dx, dy) = (x - xi as f64, y - yi as f64);
+        let dz = (z - self.scale[zi]) / (self.scale[zi + 1] - self.scale[zi]);
+
+        let mut c = [0.0; 3];
+        for (k, wz) in [(zi, 1.0 - dz), (zi + 1, dz)] {
+            for (j, wy) in [(yi, 1.0 - dy), (yi + 1, dy)] {
+                for (i, wx) in [(xi, 1.0 - dx), (xi + 1, dx)] {
+                    let index = ((l * TABLE_RES + k) * TABLE_RES + j) * TABLE_RES + i;
+                    for (c, entry) in c.iter_mut().zip(self.coefficients[index]) {
+                        *c += wx * wy * wz * entry;
+                    }
+                }
+            }
+        }
+        c
+    }
+}
+
+/// Fit the coefficients of a sigmoid-polynomial spectrum to a color, given the color
+/// of each normalized wavelength, starting from an initial guess
+fn fit(weights: &[(f64, Color)], rgb: &Color, c: &mut [f64; 3]) {
+    // Sigmoids only reach 0 and 1 in the limit, so fit to a color just inside them
+    let rgb = rgb.map(|c| c.clamp(FIT_MARGIN, 1.0 - FIT_MARGIN));
+    let (mut residual, mut jacobian) = fit_residual(weights, &rgb, c);
+    for _ in 0..MAX_ITERATIONS {
+        if residual.abs().max() < 1e-6 {
+            break;
+        }
+        let step = match jacobian.try_inverse() {
+            Some(inverse) => inverse * residual,
+            None => break,
+        };
+        // Backtrack along the Gauss-Newton step until the residual decreases
+        let mut scale = 1.0;
+        loop {
+            let next = [
+                c[0] - scale * step[0],
+                c[1] - scale * step[1],
+                c[2] - scale * step[2],
+            ];
+            let (next_residual, next_jacobian) = fit_residual(weights, &rgb, &next);
+            if next_residual.norm() < residual.norm() {
+                *c = next;
+                residual = next_residual;
+                jacobian = next_jacobian;
+                break;
+            }
+            scale *= 0.5;
+            if scale < 1e-4 {
+                return;
+            }
+        }
+    }
+}
+
+/// Difference between the color of a sigmoid-polynomial spectrum and a target color,
+/// and its Jacobian with respect to the coefficients
+fn fit_residual(weights: &[(f64, Color)], rgb: &Color, c: &[f64; 3]) -> (Color, glm::DMat3) {
+    let mut residual = -rgb;
+    let mut jacobian = glm::DMat3::zeros();
+    for (x, weight) in weights {
+        let p = (c[0] * x + c[1]) * x + c[2];
+        residual += weight * sigmoid(p);
+        let derivative = 0.5 / (1.0 + p * p).powf(1.5);
+        for (j, dp) in [x * x, *x, 1.0].iter().enumerate() {
+            let mut column = jacobian.column_mut(j);
+            column += weight * derivative * *dp;
+        }
+    }
+    (residual, jacobian)
+}
+
+/// Evaluate a sigmoid-polynomial spectrum at a wavelength, in nanometers
+fn sigmoid_spectrum(c: &[f64; 3], wavelength: f64) -> f64 {
+    let x = normalize_wavelength(wavelength);
+    sigmoid((c[0] * x + c[1]) * x + c[2])
+}
+
+/// Map a wavelength in the visible range to [0, 1], to keep coefficients well scaled
+fn normalize_wavelength(wavelength: f64) -> f64 {
+    (wavelength - MIN_WAVELENGTH) / (MAX_WAVELENGTH - MIN_WAVELENGTH)
+}
+
+/// Smooth sigmoid from 0 to 1, which is cheaper than the logistic function
+fn sigmoid(x: f64) -> f64 {
+    if x.is_infinite() {
+        return if x > 0.0 { 1.0 } else { 0.0 };
+    }
+    0.5 + x / (2.0 * (1.0 + x * x).sqrt())
+}
+
+fn smoothstep(x: f64) -> f64 {
+    x * x * (3.0 - 2.0 * x)
+}
+
+/// CIE 1931 color matching functions at a wavelength, in nanometers, using the
+/// multi-lobe Gaussian fit by Wyman, Sloan and Shirley (2013)
+pub fn cie_xyz(wavelength: f64) -> glm::DVec3 {
+    let g = |mu: f64, sigma1: f64, sigma2: f64| {
+        let t = (wavelength - mu) / if wavelength < mu { sigma1 } else { sigma2 };
+        (-0.5 * t * t).exp()
+    };
+    glm::vec3(
+        1.056 * g(599.8, 37.9, 31.0) + 0.362 * g(442.0, 16.0, 26.7) - 0.065 * g(501.1, 20.4, 26.2),
+        0.821 * g(568.8, 46.9, 40.5) + 0.286 * g(530.9, 16.3, 31.1),
+        1.217 * g(437.0, 11.8, 36.0) + 0.681 * g(459.0, 26.0, 13.8),
+    )
+}
+
+/// Relative spectral power of the CIE D65 illuminant at a wavelength, in nanometers,
+/// interpolated linearly from 10 nm steps
+fn d65(wavelength: f64) -> f64 {
+    let x = ((wavelength - MIN_WAVELENGTH) / 10.0).clamp(0.0, (D65.len() - 1) as f64);
+    let i = (x as usize).min(D65.len() - 2);
+    let t = x - i as f64;
+    D65[i] * (1.0 - t) + D65[i + 1] * t
+}
+
+/// CIE D65 illuminant from 360 nm to 830 nm, in steps of 10 nm
+const D65: [f64; 48] = [
+    46.6383, 52.0891, 49.9755, 54.6482, 82.7549, 91.486, 93.4318, 86.6823, 104.865, 117.008,
+    117.812, 114.861, 115.923, 108.811, 109.354, 107.802, 104.79, 107.689, 104.405, 104.046, 100.0,
+    96.3342, 95.788, 88.6856, 90.0062, 89.5991, 87.6987, 83.2886, 83.6992, 80.0268, 80.2146,
+    82.2778, 78.2842, 69.7213, 71.6091, 74.349, 61.604, 69.8856, 75.087, 63.5927, 46.4182, 66.8054,
+    63.3828, 64.304, 59.4519, 51.959, 57.4406, 60.3125,
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upsampled_spectra_match_colors() {
+        for primaries in [Primaries::Rec709, Primaries::Ap1] {
+            let table = SpectrumTable::get(primaries);
+            for color in [
+                glm::vec3(1.0, 1.0, 1.0),
+                glm::vec3(0.18, 0.18, 0.18),
+                glm::vec3(0.8, 0.3, 0.1),
+                glm::vec3(0.05, 0.4, 0.7),
+            ] {
+                // Integrate the reflectance under the white illuminant
+                let c = table.coefficients(&color);
+                let xyz: glm::DVec3 = (0..94)
+                    .map(|i| {
+                        let wavelength = MIN_WAVELENGTH + (f64::from(i) + 0.5) * STEP;
+                        cie_xyz(wavelength) * d65(wavelength) * sigmoid_spectrum(&c, wavelength)
+                    })
+                    .sum();
+                let rgb = table.from_xyz * xyz * STEP / table.white_luminance;
+                assert!((rgb - color).abs().max() < 0.01, "{:?} != {:?}", rgb, color);
+            }
+        }
+    }
+
+    #[test]
+    fn dispersion_works() {
+        assert!((Dispersion::BK7.d_line_index() - 1.5168).abs() < 1e-4);
+        let cauchy = Dispersion::abbe(1.5168, 64.17);
+        assert!((cauchy.d_line_index() - 1.5168).abs() < 1e-9);
+        for dispersion in [Dispersion::BK7, Dispersion::SF11, cauchy] {
+            assert!(dispersion.index(450.0) > dispersion.index(650.0));
+        }
+    }
+}
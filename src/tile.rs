@@ -0,0 +1,172 @@
+use crate::color::Color;
+
+/// A rectangular block of pixels, which is rendered as a unit of work
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Tile {
+    /// Column of the top-left pixel
+    pub x: u32,
+
+    /// Row of the top-left pixel
+    pub y: u32,
+
+    /// Width of the tile in pixels
+    pub width: u32,
+
+    /// Height of the tile in pixels
+    pub height: u32,
+}
+
+impl Tile {
+    /// Iterate over the pixel locations in the tile, row by row
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> {
+        let Tile {
+            x,
+            y,
+            width,
+            height,
+        } = *self;
+        (y..y + height).flat_map(move |j| (x..x + width).map(move |i| (i, j)))
+    }
+
+    /// Returns whether a pixel location is inside the tile
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    /// Split the tile into smaller tiles of a given size, sorted in some order
+    pub fn split(&self, size: u32, order: TileOrder) -> Vec<Tile> {
+        assert!(size > 0, "Tile size must be positive");
+        let cols = self.width.div_ceil(size);
+        let rows = self.height.div_ceil(size);
+        let mut coords: Vec<_> = (0..rows)
+            .flat_map(|j| (0..cols).map(move |i| (i, j)))
+            .collect();
+        match order {
+            TileOrder::Scanline => (),
+            TileOrder::Spiral => {
+                // Sort by the square ring around the center, then by angle in each ring
+                let center = (f64::from(cols) / 2.0, f64::from(rows) / 2.0);
+                let key = |&(i, j): &(u32, u32)| {
+                    let dx = f64::from(i) + 0.5 - center.0;
+                    let dy = f64::from(j) + 0.5 - center.1;
+                    (dx.abs().max(dy.abs()).floor(), dy.atan2(dx))
+                };
+                coords.sort_by(|a, b| {
+                    let (a, b) = (key(a), key(b));
+                    a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+                });
+            }
+            TileOrder::Hilbert => {
+                let n = cols.max(rows).next_power_of_two();
+                coords.sort_by_key(|&(i, j)| hilbert_index(n, i, j));
+            }
+        }
+        coords
+            .into_iter()
+            .map(|(i, j)| Tile {
+                x: self.x + i * size,
+                y: self.y + j * size,
+                width: size.min(self.width - i * size),
+                height: size.min(self.height - j * size),
+            })
+            .collect()
+    }
+}
+
+/// Order in which the tiles of an image are rendered
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TileOrder {
+    /// Row by row, from the top-left corner
+    Scanline,
+
+    /// Outward from the center of the image, which is usually the most interesting part
+    #[default]
+    Spiral,
+
+    /// Along a Hilbert curve, which keeps consecutive tiles close together
+    Hilbert,
+}
+
+/// Progress of a render, reported after each tile is finished
+#[derive(Copy, Clone, Debug)]
+pub struct TileProgress<'a> {
+    /// Index of the pass over the image, counting from zero
+    pub pass: u32,
+
+    /// The tile that was finished
+    pub tile: Tile,
+
+    /// New samples in the tile from this pass, row by row, or `None` for pixels that
+    /// have converged with adaptive sampling
+    pub samples: &'a [Option<Color>],
+
+    /// Number of tiles finished so far in this pass
+    pub tiles_finished: usize,
+
+    /// Total number of tiles in each pass
+    pub num_tiles: usize,
+}
+
+/// Distance along the Hilbert curve filling an n-by-n grid, where n is a power of two
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = u32::from(x & s > 0);
+        let ry = u32::from(y & s > 0);
+        d += u64::from(s) * u64::from(s) * u64::from((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve within it starts in the right place
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_image() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert] {
+            let region = Tile {
+                x: 10,
+                y: 20,
+                width: 100,
+                height: 70,
+            };
+            let mut covered = vec![0; 110 * 90];
+            for tile in region.split(32, order) {
+                for (x, y) in tile.pixels() {
+                    covered[(y * 110 + x) as usize] += 1;
+                }
+            }
+            for (i, &count) in covered.iter().enumerate() {
+                let (x, y) = (i as u32 % 110, i as u32 / 110);
+                assert_eq!(count, u32::from(region.contains(x, y)));
+            }
+        }
+    }
+
+    #[test]
+    fn hilbert_tiles_are_adjacent() {
+        let image = Tile {
+            x: 0,
+            y: 0,
+            width: 256,
+            height: 256,
+        };
+        let tiles = image.split(32, TileOrder::Hilbert);
+        for pair in tiles.windows(2) {
+            let dx = (pair[0].x as i32 - pair[1].x as i32).abs();
+            let dy = (pair[0].y as i32 - pair[1].y as i32).abs();
+            assert_eq!(dx + dy, 32);
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use crate::color::{Color, ColorSpace, Primaries, TransferFunction};
+
+/// A tone mapping operator, which compresses the high dynamic range of a render into
+/// the [0, 1] range of a display
+///
+/// Operators are applied to linear colors, before the transfer function.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ToneMap {
+    /// Clamp each channel to [0, 1], which blows out bright areas to white
+    #[default]
+    Clamp,
+
+    /// Reinhard's operator L / (1 + L), applied to luminance to preserve hue
+    Reinhard,
+
+    /// Reinhard's operator with a white point, the smallest luminance that is mapped to
+    /// white
+    ReinhardExtended {
+        /// Luminance of the white point
+        white: f64,
+    },
+
+    /// Filmic curve fitted to the ACES reference rendering and sRGB output transforms,
+    /// by Stephen Hill
+    Aces,
+
+    /// AgX, a filmic curve that desaturates bright colors smoothly toward white, using
+    /// the polynomial approximation by Benjamin Wrensch
+    Agx,
+
+    /// John Hable's filmic curve from Uncharted 2
+    Hable,
+}
+
+impl ToneMap {
+    /// Apply the operator to a linear color, returning a linear color in [0, 1]
+    ///
+    /// Operators that act on luminance use the weights of the color's primaries, from
+    /// `Primaries::luminance_weights`.
+    pub fn apply(&self, color: &Color, luminance_weights: &Color) -> Color {
+        let color = color.map(|c| c.max(0.0));
+        let mapped = match *self {
+            Self::Clamp => color,
+            Self::Reinhard => scale_luminance(&color, luminance_weights, |l| l / (1.0 + l)),
+            Self::ReinhardExtended { white } => scale_luminance(&color, luminance_weights, |l| {
+                l * (1.0 + l / (white * white)) / (1.0 + l)
+            }),
+            Self::Aces => {
+                let v = transform(&ACES_INPUT, &color);
+                let v = v.map(|x| {
+                    (x * (x + 0.024_578_6) - 0.000_090_537)
+                        / (x * (0.983_729 * x + 0.432_951) + 0.238_081)
+                });
+                transform(&ACES_OUTPUT, &v)
+            }
+            Self::Agx => {
+                let v = transform(&AGX_INPUT, &color);
+                let v = v.map(|x| {
+                    // Encode in log2 space, then apply the sigmoid contrast curve
+                    let x = (x.max(1e-10).log2().clamp(AGX_MIN_EV, AGX_MAX_EV) - AGX_MIN_EV)
+                        / (AGX_MAX_EV - AGX_MIN_EV);
+                    let x2 = x * x;
+                    let x4 = x2 * x2;
+                    15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x
+                        + 0.4298 * x2
+                        + 0.1191 * x
+                        - 0.00232
+                });
+                // The curve outputs display-encoded values with a 2.2 gamma
+                transform(&AGX_OUTPUT, &v).map(|x| x.max(0.0).powf(2.2))
+            }
+            Self::Hable => {
+                const EXPOSURE_BIAS: f64 = 2.0;
+                const WHITE: f64 = 11.2;
+                color.map(|x| hable(x * EXPOSURE_BIAS) / hable(WHITE))
+            }
+        };
+        mapped.map(|c| c.clamp(0.0, 1.0))
+    }
+}
+
+/// A display transform, which converts linear colors in a working space to bytes in an
+/// output color space, with a tone mapping operator
+#[derive(Copy, Clone, Debug)]
+pub struct DisplayTransform {
+    tone_map: ToneMap,
+    transfer_function: TransferFunction,
+
+    /// Conversion from the working space to the primaries of the tone mapping operator
+    to_tone_map: glm::DMat3,
+
+    /// Conversion from the primaries of the tone mapping operator to the output space
+    from_tone_map: glm::DMat3,
+
+    /// Luminance weights of the primaries of the tone mapping operator
+    luminance_weights: Color,
+}
+
+impl DisplayTransform {
+    /// Construct a new display transform
+    pub fn new(working_space: Primaries, tone_map: ToneMap, output_space: ColorSpace) -> Self {
+        let output = output_space.primaries;
+        let (tone_map_space, to_tone_map, from_tone_map) = match tone_map {
+            // These curves are fitted for Rec. 709 primaries
+            ToneMap::Aces | ToneMap::Agx => (
+                Primaries::Rec709,
+                working_space.conversion_matrix(Primaries::Rec709),
+                Primaries::Rec709.conversion_matrix(output),
+            ),
+            _ => (
+                output,
+                working_space.conversion_matrix(output),
+                glm::DMat3::identity(),
+            ),
+        };
+        Self {
+            tone_map,
+            transfer_function: output_space.transfer_function,
+            to_tone_map,
+            from_tone_map,
+            luminance_weights: tone_map_space.luminance_weights(),
+        }
+    }
+
+    /// Convert a linear color to a triple of display bytes
+    pub fn bytes(&self, color: &Color) -> [u8; 3] {
+        let mapped = self
+            .tone_map
+            .apply(&(self.to_tone_map * color), &self.luminance_weights);
+        let color = self.from_tone_map * mapped;
+        [color.x, color.y, color.z]
+            .map(|c| (self.transfer_function.encode(c.clamp(0.0, 1.0)) * 255.0) as u8)
+    }
+}
+
+/// Convert a linear color to a triple of display bytes, with a tone mapping operator
+/// and transfer function
+///
+/// With the default operator and transfer function, this is the same as `color_bytes`.
+pub fn display_bytes(color: &Color, tone_map: ToneMap, transfer: TransferFunction) -> [u8; 3] {
+    let output_space = ColorSpace::new(Primaries::Rec709, transfer);
+    DisplayTransform::new(Primaries::Rec709, tone_map, output_space).bytes(color)
+}
+
+/// Scale a color so that its luminance, with the given weights, is mapped by a curve
+fn scale_luminance(color: &Color, weights: &Color, curve: impl Fn(f64) -> f64) -> Color {
+    let l = weights.dot(color);
+    if l > 0.0 {
+        color * (curve(l) / l)
+    } else {
+        *color
+    }
+}
+
+/// Multiply a color by a matrix, given as an array of rows
+fn transform(matrix: &[[f64; 3]; 3], color: &Color) -> Color {
+    let [r0, r1, r2] = matrix.map(|row| glm::vec3(row[0], row[1], row[2]).dot(color));
+    glm::vec3(r0, r1, r2)
+}
+
+/// John Hable's filmic curve, before normalizing by the white point
+fn hable(x: f64) -> f64 {
+    const A: f64 = 0.15; // shoulder strength
+    const B: f64 = 0.50; // linear strength
+    const C: f64 = 0.10; // linear angle
+    const D: f64 = 0.20; // toe strength
+    const E: f64 = 0.02; // toe numerator
+    const F: f64 = 0.30; // toe denominator
+    (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+}
+
+/// Transform from linear sRGB into the ACES AP1 space, including the saturation
+/// adjustment of the reference rendering transform
+const ACES_INPUT: [[f64; 3]; 3] = [
+    [0.59719, 0.35458, 0.04823],
+    [0.07600, 0.90834, 0.01566],
+    [0.02840, 0.13383, 0.83777],
+];
+
+/// Transform from the ACES AP1 space back to linear sRGB, including the saturation
+/// adjustment of the output transform
+const ACES_OUTPUT: [[f64; 3]; 3] = [
+    [1.60475, -0.53108, -0.07367],
+    [-0.10208, 1.10813, -0.00605],
+    [-0.00327, -0.07276, 1.07602],
+];
+
+/// Transform from linear sRGB into the AgX working space, which is slightly inset so
+/// that very saturated colors desaturate toward white
+const AGX_INPUT: [[f64; 3]; 3] = [
+    [
+        0.842_479_062_253_094,
+        0.078_433_599_999_999_2,
+        0.079_223_745_147_764_3,
+    ],
+    [
+        0.042_328_242_261_012_3,
+        0.878_468_636_469_772,
+        0.079_166_127_460_543_4,
+    ],
+    [0.042_375_654_905_705_1, 0.078_433_6, 0.879_142_973_793_104],
+];
+
+/// Inverse of `AGX_INPUT`, applied after the sigmoid curve
+const AGX_OUTPUT: [[f64; 3]; 3] = [
+    [
+        1.196_879_005_120_17,
+        -0.098_020_881_140_136_8,
+        -0.099_029_744_079_720_5,
+    ],
+    [
+        -0.052_896_851_757_456_2,
+        1.151_903_129_904_17,
+        -0.098_961_176_844_843_3,
+    ],
+    [
+        -0.052_971_635_514_443_8,
+        -0.098_043_450_117_124_1,
+        1.151_073_672_641_16,
+    ],
+];
+
+/// Range of exposures, in stops relative to middle gray, that AgX maps to [0, 1]
+const AGX_MIN_EV: f64 = -12.473_931_188;
+const AGX_MAX_EV: f64 = 4.026_068_812;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::color_bytes;
+
+    #[test]
+    fn tone_maps_are_monotonic() {
+        let operators = [
+            ToneMap::Clamp,
+            ToneMap::Reinhard,
+            ToneMap::ReinhardExtended { white: 4.0 },
+            ToneMap::Aces,
+            ToneMap::Agx,
+            ToneMap::Hable,
+        ];
+        let weights = Primaries::Rec709.luminance_weights();
+        for tone_map in operators {
+            let mut prev = -1.0;
+            for i in 0..=1000 {
+                let x = f64::from(i) / 50.0;
+                let y = tone_map.apply(&glm::vec3(x, x, x), &weights);
+                assert!(y.x >= prev - 1e-9 && y.x <= 1.0, "{:?} at {}", tone_map, x);
+                prev = y.x;
+            }
+            assert!(tone_map.apply(&glm::vec3(0.0, 0.0, 0.0), &weights).x < 0.01);
+            assert!(tone_map.apply(&glm::vec3(100.0, 100.0, 100.0), &weights).x > 0.9);
+        }
+        let white =
+            ToneMap::ReinhardExtended { white: 4.0 }.apply(&glm::vec3(4.0, 4.0, 4.0), &weights);
+        assert!((white.x - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn default_display_matches_color_bytes() {
+        let color = glm::vec3(0.2, 0.5, 3.0);
+        let bytes = display_bytes(&color, ToneMap::default(), TransferFunction::default());
+        assert_eq!(bytes, color_bytes(&color));
+    }
+}